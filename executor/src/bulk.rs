@@ -0,0 +1,460 @@
+//! Serves partitions over `neuromancer::socket::bulk`. Partitions are held in memory, the first
+//! fetch of one over this channel writes a copy of it to an unnamed temporary file, and every
+//! fetch after that is sent straight from the page cache until the copy goes unused for
+//! `SPILL_TTL`. The copy doesn't take the partition out of memory
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use bytes::BytesMut;
+use prost::Message;
+use tonic::{Code, Status};
+
+use crate::errors::*;
+use crate::executor::{Executor, Partition};
+use crate::services::checksum_of;
+use neuromancer::base::Map;
+use neuromancer::checksum::{algorithm, verify_checksum, Digest};
+use neuromancer::executor::{BulkHeader, FetchRequest};
+use neuromancer::socket::bulk::{self, IO_TIMEOUT};
+use neuromancer::socket::{Address, Options};
+use neuromancer::{read_lock, write_lock};
+
+/// how many fetches are served at once, the ones over it are turned away with RESOURCE_EXHAUSTED
+const MAX_CONNECTIONS: usize = 64;
+/// how long accepting pauses after it failed, doubling while it keeps failing. Running out of
+/// descriptors is the usual cause, and retrying right away only spins
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+/// spilled partitions that weren't fetched for this long are deleted, the partition itself stays
+/// and is spilled again if it's fetched after that
+const SPILL_TTL: Duration = Duration::from_secs(300);
+const SPILL_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+/// how often a stopping server checks whether the fetches it is still sending are done
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A partition written out as length delimited records, deleted once the last handle to it is
+/// dropped
+pub(crate) struct Spilled {
+    file: File,
+    /// where every record starts, followed by the length of the file
+    offsets: Vec<u64>,
+    /// the digests of the records from an index to the end, the one of the whole partition is
+    /// taken while it is written out and the ones of resumed fetches the first time they're asked
+    /// for, so that sending stays clear of reading the file
+    digests: Mutex<BTreeMap<usize, Vec<u8>>>,
+    last_fetched: Mutex<Instant>,
+}
+
+impl Spilled {
+    fn new(records: &[Map]) -> io::Result<Self> {
+        let file = tempfile::tempfile()?;
+        let mut offsets = Vec::with_capacity(records.len() + 1);
+        let mut writer = BufWriter::new(&file);
+        let mut digest = Digest::new(algorithm());
+        let mut encoded = BytesMut::new();
+        let mut offset = 0;
+        for record in records {
+            offsets.push(offset);
+            encoded.clear();
+            // encoding into a buffer that can grow is infallible
+            let _ = record.encode_length_delimited(&mut encoded);
+            writer.write_all(&encoded)?;
+            digest.update(&encoded);
+            offset += encoded.len() as u64;
+        }
+        offsets.push(offset);
+        writer.flush()?;
+        drop(writer);
+        let mut digests = BTreeMap::new();
+        digests.insert(0, digest.finish());
+        Ok(Self {
+            file,
+            offsets,
+            digests: Mutex::new(digests),
+            last_fetched: Mutex::new(Instant::now()),
+        })
+    }
+
+    /// The digest of the records from `start` to the end
+    fn digest_from(&self, start: usize) -> io::Result<Vec<u8>> {
+        let mut digests = self.digests.lock();
+        if let Some(digest) = digests.get(&start) {
+            return Ok(digest.clone());
+        }
+        let offset = self.offsets[start];
+        let length = self.offsets[self.offsets.len() - 1] - offset;
+        let digest = bulk::digest_file(&self.file, offset, length, algorithm())?;
+        digests.insert(start, digest.clone());
+        Ok(digest)
+    }
+}
+
+/// Stops a `serve` listening on `address`
+#[derive(Clone)]
+pub(crate) struct Stop {
+    stopped: Arc<AtomicBool>,
+    address: Address,
+}
+
+impl Stop {
+    pub(crate) fn new(address: Address) -> Self {
+        Self {
+            stopped: Arc::new(AtomicBool::new(false)),
+            address,
+        }
+    }
+
+    /// Makes `serve` return once the fetches it is sending are done. Accepting blocks, so the
+    /// server is woken up with a connection of our own
+    pub(crate) fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        let _ = bulk::connect(&self.address, &Options::default());
+    }
+}
+
+/// Accepts bulk fetches until `stop` is used, each one is sent from its own thread. Spilled
+/// partitions are swept from yet another
+pub(crate) fn serve(executor: Executor, listener: bulk::Listener, stop: Stop) {
+    let sweeper = executor.clone();
+    // the sweeper stops once the sender is dropped
+    let (serving, stopped) = mpsc::channel::<()>();
+    let sweeper = thread::spawn(move || {
+        while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(SPILL_SWEEP_INTERVAL) {
+            sweeper.evict_spilled(Instant::now(), SPILL_TTL);
+        }
+    });
+    serve_at_most(executor, listener, MAX_CONNECTIONS, &stop.stopped);
+    drop(serving);
+    let _ = sweeper.join();
+}
+
+fn serve_at_most(
+    executor: Executor,
+    listener: bulk::Listener,
+    connections: usize,
+    stopped: &AtomicBool,
+) {
+    let active = Arc::new(AtomicUsize::new(0));
+    let mut backoff = ACCEPT_BACKOFF;
+    loop {
+        let accepted = listener.accept();
+        if stopped.load(Ordering::SeqCst) {
+            break;
+        }
+        let mut stream = match accepted {
+            Ok(stream) => {
+                backoff = ACCEPT_BACKOFF;
+                stream
+            }
+            // a connection that failed before it was accepted is the client's problem
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::ConnectionAborted | io::ErrorKind::Interrupted
+                ) =>
+            {
+                continue
+            }
+            Err(e) => {
+                eprintln!("could not accept a bulk connection: {}", e);
+                thread::sleep(backoff);
+                backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+                continue;
+            }
+        };
+        let slot = match Slot::take(&active, connections) {
+            Some(slot) => slot,
+            None => {
+                // the header is small enough for the socket buffer, this never blocks for long
+                let status = Status::resource_exhausted("too many bulk fetches in flight");
+                let _ = stream.set_write_timeout(Some(IO_TIMEOUT));
+                let _ = bulk::write_frame(&mut stream, &header_for(&status));
+                continue;
+            }
+        };
+        let executor = executor.clone();
+        thread::spawn(move || {
+            let _slot = slot;
+            // as is one that goes away halfway through, it will fetch again from its offset
+            let _ = executor.send_partition(stream);
+        });
+    }
+    // nothing new is accepted once the listener is gone
+    drop(listener);
+    while active.load(Ordering::SeqCst) > 0 {
+        thread::sleep(STOP_POLL_INTERVAL);
+    }
+}
+
+/// One of the connections that are served at once, given back when dropped
+struct Slot(Arc<AtomicUsize>);
+
+impl Slot {
+    fn take(active: &Arc<AtomicUsize>, limit: usize) -> Option<Self> {
+        if active.fetch_add(1, Ordering::SeqCst) >= limit {
+            active.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(Self(active.clone()))
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Executor {
+    fn send_partition(&self, mut stream: bulk::Stream) -> io::Result<()> {
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+        let request: FetchRequest = bulk::read_frame(&mut stream)?;
+        let (spilled, start) = match self.spilled_from(&request) {
+            Ok(found) => found,
+            Err(status) => return bulk::write_frame(&mut stream, &header_for(&status)),
+        };
+        let end = spilled.offsets[spilled.offsets.len() - 1];
+        let length = end - spilled.offsets[start];
+        let digest = match spilled.digest_from(start) {
+            Ok(digest) => digest,
+            Err(e) => {
+                let status = Status::internal(e.to_string());
+                return bulk::write_frame(&mut stream, &header_for(&status));
+            }
+        };
+        let mut header = BulkHeader {
+            code: Code::Ok as i32,
+            records: (spilled.offsets.len() - 1 - start) as u64,
+            length,
+            digest,
+            ..Default::default()
+        };
+        header.checksum = match checksum_of(&header) {
+            Ok(checksum) => checksum,
+            Err(status) => return bulk::write_frame(&mut stream, &header_for(&status)),
+        };
+        bulk::write_frame(&mut stream, &header)?;
+        bulk::send_file(
+            &mut stream,
+            &spilled.file,
+            spilled.offsets[start],
+            header.length,
+        )
+    }
+
+    /// The spilled partition a fetch asks for along with the index of its first record
+    fn spilled_from(&self, request: &FetchRequest) -> Result<(Arc<Spilled>, usize), Status> {
+        verify_checksum(request, &request.checksum)?;
+        let (partition, records) = self.partition_records(request)?;
+        let spilled = self.spill(partition, &records).map_err(|source| {
+            Status::internal(
+                Error::Spill {
+                    run: partition.run,
+                    index: partition.index,
+                    source,
+                }
+                .to_string(),
+            )
+        })?;
+        Ok((spilled, request.offset as usize))
+    }
+
+    fn spill(&self, partition: Partition, records: &[Map]) -> io::Result<Arc<Spilled>> {
+        if let Some(spilled) = read_lock!(self.spilled).get(&partition) {
+            *spilled.last_fetched.lock() = Instant::now();
+            return Ok(spilled.clone());
+        }
+        // concurrent first fetches may each spill, only one of them is kept
+        let spilled = Arc::new(Spilled::new(records)?);
+        Ok(write_lock!(self.spilled)
+            .entry(partition)
+            .or_insert(spilled)
+            .clone())
+    }
+
+    /// Forgets the spilled partitions that weren't fetched within `ttl` of `now`. Transfers
+    /// still sending one hold on to its file until they are done
+    pub(crate) fn evict_spilled(&self, now: Instant, ttl: Duration) {
+        write_lock!(self.spilled)
+            .retain(|_, spilled| now.saturating_duration_since(*spilled.last_fetched.lock()) < ttl);
+    }
+}
+
+fn header_for(status: &Status) -> BulkHeader {
+    let mut header = BulkHeader {
+        code: status.code() as i32,
+        message: status.message().to_string(),
+        ..Default::default()
+    };
+    // a header without a checksum is rejected by the client, which is the best that can be done
+    header.checksum = checksum_of(&header).unwrap_or_default();
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Seek, SeekFrom};
+
+    use uuid::Uuid;
+
+    use super::*;
+    use neuromancer::base::Identifier;
+    use neuromancer::socket::{Address, Options};
+
+    fn fetch_request(run: u128, offset: u64) -> FetchRequest {
+        FetchRequest {
+            run_id: Some(Identifier {
+                uuid: Uuid::from_u128(run).to_string(),
+            }),
+            offset,
+            ..Default::default()
+        }
+    }
+
+    fn records() -> Vec<Map> {
+        (0..1200)
+            .map(|i| Map {
+                key: format!("key-{}", i).into_bytes(),
+                // not valid UTF-8
+                value: vec![0xff, i as u8],
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    /// an executor holding `records()` as the first partition of run 1, served on `addr` to at
+    /// most `connections` at once
+    fn serve_records(addr: &Address, connections: usize) -> (Executor, Address) {
+        let executor = Executor::new();
+        write_lock!(executor.partitions).insert(
+            Partition {
+                run: Uuid::from_u128(1),
+                index: 0,
+            },
+            Arc::new(records()),
+        );
+        let listener = bulk::listen(addr, Options::default()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = executor.clone();
+        thread::spawn(move || {
+            serve_at_most(server, listener, connections, &AtomicBool::new(false))
+        });
+        (executor, addr)
+    }
+
+    fn read_records(file: &mut File) -> Vec<Map> {
+        let mut contents = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut contents).unwrap();
+        let mut contents = &contents[..];
+        let mut received = Vec::new();
+        while !contents.is_empty() {
+            received.push(Map::decode_length_delimited(&mut contents).unwrap());
+        }
+        received
+    }
+
+    #[test]
+    fn fetches_spilled_partitions_from_offset() {
+        let records = records();
+        let (executor, addr) = serve_records(&"[::1]:0".parse().unwrap(), MAX_CONNECTIONS);
+
+        for &offset in &[0u64, 700, 1200] {
+            let mut file = tempfile::tempfile().unwrap();
+            let header =
+                bulk::fetch(&addr, &Options::default(), fetch_request(1, offset), &file).unwrap();
+            assert_eq!(header.records, 1200 - offset);
+            assert_eq!(file.metadata().unwrap().len(), header.length);
+            assert_eq!(read_records(&mut file), &records[offset as usize..]);
+        }
+        assert_eq!(read_lock!(executor.spilled).len(), 1);
+        // the digest of the whole partition was taken while spilling, the others once
+        let spilled = read_lock!(executor.spilled)
+            .values()
+            .next()
+            .unwrap()
+            .clone();
+        let starts: Vec<usize> = spilled.digests.lock().keys().copied().collect();
+        assert_eq!(starts, vec![0, 700, 1200]);
+        drop(spilled);
+
+        // a spill that went unused is deleted, and made again by the next fetch
+        executor.evict_spilled(Instant::now(), SPILL_TTL);
+        assert_eq!(read_lock!(executor.spilled).len(), 1);
+        executor.evict_spilled(Instant::now() + SPILL_TTL, SPILL_TTL);
+        assert!(read_lock!(executor.spilled).is_empty());
+        let mut file = tempfile::tempfile().unwrap();
+        bulk::fetch(&addr, &Options::default(), fetch_request(1, 0), &file).unwrap();
+        assert_eq!(read_lock!(executor.spilled).len(), 1);
+        assert_eq!(read_records(&mut file), records);
+
+        let file = tempfile::tempfile().unwrap();
+        let err = bulk::fetch(&addr, &Options::default(), fetch_request(2, 0), &file).unwrap_err();
+        assert_eq!(err.code(), Code::FailedPrecondition);
+        let err =
+            bulk::fetch(&addr, &Options::default(), fetch_request(1, 1201), &file).unwrap_err();
+        assert_eq!(err.code(), Code::OutOfRange);
+    }
+
+    #[test]
+    fn fetches_over_unix_sockets() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("bulk.sock");
+        let (_, addr) = serve_records(&Address::Unix(path), MAX_CONNECTIONS);
+
+        let mut file = tempfile::tempfile().unwrap();
+        let header =
+            bulk::fetch(&addr, &Options::default(), fetch_request(1, 1000), &file).unwrap();
+        assert_eq!(header.records, 200);
+        assert_eq!(read_records(&mut file), &records()[1000..]);
+    }
+
+    #[test]
+    fn stops_once_the_fetches_in_flight_are_done() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("bulk.sock");
+        let listener = bulk::listen(&Address::Unix(path.clone()), Options::default()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stop = Stop::new(addr.clone());
+        let (executor, _) = serve_records(&"[::1]:0".parse().unwrap(), MAX_CONNECTIONS);
+        let stopping = stop.clone();
+        let server = thread::spawn(move || serve(executor, listener, stopping));
+
+        let file = tempfile::tempfile().unwrap();
+        bulk::fetch(&addr, &Options::default(), fetch_request(1, 0), &file).unwrap();
+        stop.stop();
+        server.join().unwrap();
+        assert!(!path.exists());
+        assert!(bulk::fetch(&addr, &Options::default(), fetch_request(1, 0), &file).is_err());
+    }
+
+    #[test]
+    fn turns_away_fetches_over_the_limit() {
+        let (_, addr) = serve_records(&"[::1]:0".parse().unwrap(), 1);
+        // holds the only slot without ever sending its request
+        let idle = bulk::connect(&addr, &Options::default()).unwrap();
+        thread::sleep(Duration::from_millis(50));
+
+        let file = tempfile::tempfile().unwrap();
+        let err = bulk::fetch(&addr, &Options::default(), fetch_request(1, 0), &file).unwrap_err();
+        assert_eq!(err.code(), Code::ResourceExhausted);
+
+        // the slot is given back once the idle connection goes away
+        drop(idle);
+        let fetched = (0..100).any(|_| {
+            thread::sleep(Duration::from_millis(10));
+            bulk::fetch(&addr, &Options::default(), fetch_request(1, 0), &file).is_ok()
+        });
+        assert!(fetched);
+    }
+}
@@ -13,12 +13,34 @@ pub(crate) enum Error {
     InvalidAddressForServer { source: std::net::AddrParseError },
     #[snafu(display("grpc transport error: {}", source))]
     GRPCTransport { source: tonic::transport::Error },
+    #[snafu(display("grpc request failed: {}", source))]
+    GRPCStatus { source: tonic::Status },
     #[snafu(display(
-        "checksum mismatch for payload, computed: {} got: {}",
-        computed,
-        request
+        "stale membership epoch {}, the executor is at epoch {}",
+        request,
+        current
     ))]
-    ChecksumMismatch { computed: u64, request: u64 },
+    StaleMembershipEpoch { current: u64, request: u64 },
+    #[snafu(display("the executor is draining and no longer accepts runs"))]
+    Draining,
+    #[snafu(display("uuid encoding error: {}", source))]
+    UuidEncoding { source: uuid::Error },
+    #[snafu(display("no identifier provided"))]
+    NoIdentifierProvided,
+    #[snafu(display("partition {} of run {} is not present on this executor", index, run))]
+    PartitionNotFound { run: uuid::Uuid, index: u32 },
+    #[snafu(display(
+        "offset {} is past the end of the partition, which has {} records",
+        offset,
+        len
+    ))]
+    OffsetOutOfRange { offset: u64, len: u64 },
+    #[snafu(display("could not spill partition {} of run {}: {}", index, run, source))]
+    Spill {
+        run: uuid::Uuid,
+        index: u32,
+        source: std::io::Error,
+    },
 }
 
 #[derive(Debug, Snafu)]
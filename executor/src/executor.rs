@@ -1,16 +1,38 @@
 use std::collections::BTreeMap;
 use std::collections::HashSet;
+use std::sync::Arc;
 
-use conhash::{ConsistentHash, Node};
 use crossbeam_utils::sync::ShardedLock;
 use smol_str::SmolStr;
 use uuid::Uuid;
 
-use neuromancer::{read_lock, write_lock, DefaultHasher};
+use crate::bulk::Spilled;
+use crate::runs::Runs;
+use neuromancer::base::Map;
+use neuromancer::membership::Epoch;
+use neuromancer::ring::{Node, Ring};
+use neuromancer::{write_lock, DefaultHasher};
 
+/// A handle to the executor's state, clones are cheap and share the same state so that every
+/// service can be handed its own copy
+#[derive(Clone)]
 pub(crate) struct Executor {
-    pub(crate) identifier_mappings: ShardedLock<BTreeMap<Librarian, Vec<Uuid>>>,
-    pub(crate) librarians: ShardedLock<KnownLibrarians>,
+    pub(crate) identifier_mappings: Arc<ShardedLock<BTreeMap<Librarian, Vec<Uuid>>>>,
+    pub(crate) librarians: Arc<ShardedLock<KnownLibrarians>>,
+    pub(crate) runs: Arc<Runs>,
+    /// map output that is waiting to be fetched by reducers, held in memory
+    pub(crate) partitions: Arc<ShardedLock<BTreeMap<Partition, Arc<Vec<Map>>>>>,
+    /// copies of partitions written out the first time they were fetched in bulk, the partitions
+    /// stay in `partitions` as well
+    pub(crate) spilled: Arc<ShardedLock<BTreeMap<Partition, Arc<Spilled>>>>,
+    /// where the bulk channel listens, empty until it does
+    pub(crate) bulk_address: String,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub(crate) struct Partition {
+    pub(crate) run: Uuid,
+    pub(crate) index: u32,
 }
 
 pub(crate) trait ToLibrarian {
@@ -24,74 +46,97 @@ pub(crate) struct Librarian {
 
 pub(crate) struct KnownLibrarians {
     set: HashSet<Librarian, DefaultHasher>,
-    ring: ConsistentHash<Librarian>,
+    ring: Ring<Librarian>,
+    epoch: Epoch,
 }
 
 impl Executor {
     pub(crate) fn new() -> Self {
         Self {
-            identifier_mappings: ShardedLock::new(BTreeMap::default()),
-            librarians: ShardedLock::new(KnownLibrarians::new()),
+            identifier_mappings: Arc::new(ShardedLock::new(BTreeMap::default())),
+            librarians: Arc::new(ShardedLock::new(KnownLibrarians::new())),
+            runs: Arc::new(Runs::new()),
+            partitions: Arc::new(ShardedLock::new(BTreeMap::default())),
+            spilled: Arc::new(ShardedLock::new(BTreeMap::default())),
+            bulk_address: String::new(),
         }
     }
 
-    pub(crate) fn rebalance(&self, deleted: Vec<Librarian>) {
-        let unbalanced = self.unbalanced_identifiers(deleted);
-        if unbalanced.is_empty() {
-            return;
-        }
+    /// Moves every identifier onto the librarian that owns it on the ring of `librarians`, which
+    /// changes for some of them whenever a librarian joins or leaves. Callers are expected to hold
+    /// the `librarians` lock across the membership change and the rebalance
+    pub(crate) fn rebalance(&self, librarians: &KnownLibrarians) {
         let mut identifier_mappings = write_lock!(self.identifier_mappings);
-        let librarians = read_lock!(self.librarians);
+        let mut unbalanced = Vec::new();
+        for (librarian, uuids) in identifier_mappings.iter_mut() {
+            uuids.retain(|uuid| {
+                let owned = librarians.mapping_for(*uuid) == Some(librarian);
+                if !owned {
+                    unbalanced.push(*uuid);
+                }
+                owned
+            });
+        }
+        identifier_mappings.retain(|_, uuids| !uuids.is_empty());
         for uuid in unbalanced {
-            // should be infallible but I don't want to insert a needless
-            // panic
+            // only fails once every librarian has left, at which point there is nowhere to put
+            // the identifier
             if let Some(librarian) = librarians.mapping_for(uuid) {
                 let entry = identifier_mappings
                     .entry(librarian.clone())
-                    .or_insert_with(|| Vec::new());
+                    .or_insert_with(Vec::new);
                 entry.push(uuid);
                 // FIXME: at this point we need to call Librarian.remap(uuid)
             }
         }
     }
-
-    /// Returns the list of uuids that are currently mapped on this executor to deleted
-    /// librarians
-    fn unbalanced_identifiers(&self, deleted: Vec<Librarian>) -> Vec<Uuid> {
-        let mut result = Vec::new();
-        let mut deleted = deleted;
-        let identifier_mappings = read_lock!(self.identifier_mappings);
-        for librarian in deleted.drain(..) {
-            let uuids = identifier_mappings.get(&librarian);
-            if uuids.is_none() {
-                continue;
-            }
-            let uuids = uuids.unwrap();
-            result.extend_from_slice(uuids);
-        }
-        result
-    }
 }
 
 impl KnownLibrarians {
     pub(crate) fn new() -> Self {
-        let ring = ConsistentHash::new();
+        let ring = Ring::new();
         Self {
             set: HashSet::default(),
             ring,
+            epoch: Epoch::default(),
         }
     }
 
+    /// The epoch of the membership list that was last applied, a fresh executor starts at 0
+    pub(crate) fn epoch(&self) -> u64 {
+        self.epoch.current()
+    }
+
+    /// Whether a change sent at `epoch` is newer than the membership that is currently known,
+    /// by the same rule the librarians apply
+    pub(crate) fn is_newer(&self, epoch: u64) -> bool {
+        self.epoch.is_newer(epoch)
+    }
+
+    pub(crate) fn set_epoch(&mut self, epoch: u64) {
+        self.epoch.apply(epoch);
+    }
+
     fn mapping_for(&self, id: Uuid) -> Option<&Librarian> {
         self.ring.get(id.as_bytes())
     }
 
+    /// The known librarians in address order
+    pub(crate) fn members(&self) -> Vec<&Librarian> {
+        let mut members: Vec<&Librarian> = self.set.iter().collect();
+        members.sort();
+        members
+    }
+
+    pub(crate) fn ring(&self) -> &Ring<Librarian> {
+        &self.ring
+    }
+
     /// Accepts the new list of librarians and returns the librarians that were removed.
-    #[must_use = "You must remap the values that have been assigned to the deleted servers"]
     pub(crate) fn modify_membership(&mut self, librarians: &[Librarian]) -> Vec<Librarian> {
         let mut removed = Vec::new();
         let mut added = Vec::new();
-        let new_membership_list: HashSet<Librarian, _> = librarians.into_iter().cloned().collect();
+        let new_membership_list: HashSet<Librarian, _> = librarians.iter().cloned().collect();
         if new_membership_list.is_superset(&self.set) {
             for member in new_membership_list.difference(&self.set) {
                 added.push(member.clone());
@@ -107,15 +152,44 @@ impl KnownLibrarians {
             }
         }
         for deleted in &removed {
-            self.ring.remove(deleted);
-            self.set.remove(deleted);
+            self.remove(deleted);
         }
         for new in added {
-            self.ring.add(&new, 1);
-            self.set.insert(new);
+            self.insert(new);
         }
         removed
     }
+
+    /// Adds the librarians to the membership list, librarians that are already known are ignored.
+    pub(crate) fn add_members(&mut self, librarians: &[Librarian]) {
+        for librarian in librarians {
+            if !self.set.contains(librarian) {
+                self.insert(librarian.clone());
+            }
+        }
+    }
+
+    /// Removes the librarians from the membership list and returns the ones that were known.
+    pub(crate) fn remove_members(&mut self, librarians: &[Librarian]) -> Vec<Librarian> {
+        let mut removed = Vec::new();
+        for librarian in librarians {
+            if self.set.contains(librarian) {
+                self.remove(librarian);
+                removed.push(librarian.clone());
+            }
+        }
+        removed
+    }
+
+    fn insert(&mut self, librarian: Librarian) {
+        self.ring.add(&librarian, 1);
+        self.set.insert(librarian);
+    }
+
+    fn remove(&mut self, librarian: &Librarian) {
+        self.ring.remove(librarian);
+        self.set.remove(librarian);
+    }
 }
 
 impl Librarian {
@@ -123,6 +197,10 @@ impl Librarian {
         let address = SmolStr::new(s);
         Self { address }
     }
+
+    pub(crate) fn address(&self) -> &str {
+        &self.address
+    }
 }
 
 impl Ord for Librarian {
@@ -155,6 +233,7 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
+    use neuromancer::read_lock;
 
     #[test]
     fn superset_modify_membership() {
@@ -192,4 +271,68 @@ mod tests {
         assert_eq!(known.set, request.into_iter().collect());
         assert_eq!(known.ring.len(), 2);
     }
+
+    #[test]
+    fn add_and_remove_members() {
+        let baseline: Vec<Librarian> = ["foo", "bar"]
+            .iter()
+            .map(<_ as ToLibrarian>::to_librarian)
+            .collect();
+        let mut known = KnownLibrarians::new();
+
+        known.add_members(&baseline);
+        known.add_members(&[Librarian::new("foo"), Librarian::new("baz")]);
+        let removed = known.remove_members(&[Librarian::new("bar"), Librarian::new("qux")]);
+
+        let expected: Vec<Librarian> = ["foo", "baz"]
+            .iter()
+            .map(<_ as ToLibrarian>::to_librarian)
+            .collect();
+        assert_eq!(removed, vec![Librarian::new("bar")]);
+        assert_eq!(known.set, expected.into_iter().collect());
+        assert_eq!(known.ring.len(), 2);
+    }
+
+    #[test]
+    fn rebalance_moves_identifiers_off_deleted_librarians() {
+        let executor = Executor::new();
+        let uuids: Vec<Uuid> = (0..16).map(Uuid::from_u128).collect();
+        let mut librarians = write_lock!(executor.librarians);
+        librarians.add_members(&[Librarian::new("foo"), Librarian::new("bar")]);
+        write_lock!(executor.identifier_mappings).insert(Librarian::new("bar"), uuids.clone());
+
+        librarians.remove_members(&[Librarian::new("bar")]);
+        executor.rebalance(&librarians);
+
+        let identifier_mappings = read_lock!(executor.identifier_mappings);
+        assert_eq!(identifier_mappings.get(&Librarian::new("bar")), None);
+        assert_eq!(
+            identifier_mappings.get(&Librarian::new("foo")),
+            Some(&uuids)
+        );
+    }
+
+    #[test]
+    fn rebalance_moves_identifiers_onto_added_librarians() {
+        let executor = Executor::new();
+        let uuids: Vec<Uuid> = (0..64).map(Uuid::from_u128).collect();
+        let mut librarians = write_lock!(executor.librarians);
+        librarians.add_members(&[Librarian::new("foo")]);
+        write_lock!(executor.identifier_mappings).insert(Librarian::new("foo"), uuids.clone());
+
+        librarians.add_members(&[Librarian::new("bar")]);
+        executor.rebalance(&librarians);
+
+        let identifier_mappings = read_lock!(executor.identifier_mappings);
+        assert!(identifier_mappings.contains_key(&Librarian::new("bar")));
+        let mut rebalanced: Vec<Uuid> = Vec::new();
+        for (librarian, mapped) in identifier_mappings.iter() {
+            assert!(mapped
+                .iter()
+                .all(|uuid| librarians.mapping_for(*uuid) == Some(librarian)));
+            rebalanced.extend(mapped);
+        }
+        rebalanced.sort();
+        assert_eq!(rebalanced, uuids);
+    }
 }
@@ -1,36 +1,143 @@
+mod bulk;
 mod errors;
 mod executor;
+mod runs;
 mod services;
+mod shutdown;
 
-use tonic::transport::server::{Router, Unimplemented};
+use std::thread;
+use std::time::Duration;
 
-use neuromancer::executor::administrative_server::*;
+use futures::future::{self, Either, FutureExt};
+use tokio::sync::oneshot;
+use tokio::time::timeout;
+
+use neuromancer::executor::{administrative_server::*, shuffle_server::*};
+use neuromancer::socket;
 
 use crate::errors::*;
 use crate::executor::Executor;
 use errors::Result;
 
-pub struct Server {
-    router: Router<AdministrativeServer<Executor>, Unimplemented>,
+pub(crate) struct Server {
+    executor: Executor,
     addr: String,
+    advertised_addr: String,
+    bulk_addr: String,
 }
 
 impl Server {
     const EXECUTOR_SERVER_ADDRESS: &'static str = "[::1]:9001";
+    /// overrides `EXECUTOR_SERVER_ADDRESS`, a `unix://` address serves over a Unix domain socket
+    const ADDRESS_VARIABLE: &'static str = "EXECUTOR_ADDRESS";
+    /// how the supervisor addresses this executor, which the listening address may not be when
+    /// it is a wildcard such as `[::]:9001`
+    const EXECUTOR_ADVERTISED_ADDRESS: &'static str = "http://[::1]:9001";
+    /// overrides `EXECUTOR_ADVERTISED_ADDRESS`, which is otherwise the listening address when
+    /// that is a Unix domain socket
+    const ADVERTISED_ADDRESS_VARIABLE: &'static str = "EXECUTOR_ADVERTISED_ADDRESS";
+    /// where partitions are fetched from over the bulk channel
+    const EXECUTOR_BULK_ADDRESS: &'static str = "[::1]:9002";
+    /// overrides `EXECUTOR_BULK_ADDRESS`, a `unix://` address serves over a Unix domain socket
+    const BULK_ADDRESS_VARIABLE: &'static str = "EXECUTOR_BULK_ADDRESS";
+    const SUPERVISOR_ADDRESS: &'static str = "http://[::1]:9000";
+    /// overrides `SUPERVISOR_ADDRESS`, for a supervisor on the same host behind a `unix://` address
+    const SUPERVISOR_ADDRESS_VARIABLE: &'static str = "EXECUTOR_SUPERVISOR_ADDRESS";
+    /// how long the runs in flight may hold up a shutdown
+    const RUN_DEADLINE: Duration = Duration::from_secs(30);
+    /// how long the supervisor has to collect the results of the finished runs after that
+    const COLLECTION_DEADLINE: Duration = Duration::from_secs(30);
+    /// how long open connections and bulk fetches get to wind down once the executor stops
+    /// serving, a client holding a stream open can't keep it around for longer than that
+    const CLOSE_DEADLINE: Duration = Duration::from_secs(10);
 
-    pub fn new() -> Self {
+    pub(crate) fn new() -> Self {
         let executor = Executor::new();
-        let router =
-            tonic::transport::Server::builder().add_service(AdministrativeServer::new(executor));
-        let addr = Self::EXECUTOR_SERVER_ADDRESS.to_string();
-        Self { router, addr }
+        let addr = std::env::var(Self::ADDRESS_VARIABLE)
+            .unwrap_or_else(|_| Self::EXECUTOR_SERVER_ADDRESS.to_string());
+        let advertised_addr =
+            std::env::var(Self::ADVERTISED_ADDRESS_VARIABLE).unwrap_or_else(|_| {
+                if addr.starts_with(socket::UNIX_SCHEME) {
+                    addr.clone()
+                } else {
+                    Self::EXECUTOR_ADVERTISED_ADDRESS.to_string()
+                }
+            });
+        let bulk_addr = std::env::var(Self::BULK_ADDRESS_VARIABLE)
+            .unwrap_or_else(|_| Self::EXECUTOR_BULK_ADDRESS.to_string());
+        Self {
+            executor,
+            addr,
+            advertised_addr,
+            bulk_addr,
+        }
     }
 
-    pub async fn build(self) -> Result<()> {
-        self.router
-            .serve(self.addr.parse().context(InvalidAddressForServer)?)
-            .await
-            .context(GRPCTransport)?;
+    /// Serves until SIGTERM. The executor then stops taking runs and keeps serving while the
+    /// runs in flight finish and the supervisor collects their results, tells the supervisor
+    /// that it is leaving and gives open connections `CLOSE_DEADLINE` to wind down
+    pub(crate) async fn build(self) -> Result<()> {
+        neuromancer::checksum::configure_from_env().context(Neuromancer)?;
+        let addr = self
+            .addr
+            .parse::<socket::Address>()
+            .context(InvalidAddressForServer)?;
+        let bulk_addr = self
+            .bulk_addr
+            .parse::<socket::Address>()
+            .context(InvalidAddressForServer)?;
+        let listener =
+            socket::bulk::listen(&bulk_addr, socket::Options::default()).context(Neuromancer)?;
+        let mut executor = self.executor;
+        // the port is only known once listening when it was left to the kernel
+        let bulk_addr = listener.local_addr().unwrap_or(bulk_addr);
+        executor.bulk_address = bulk_addr.to_string();
+        let stop = bulk::Stop::new(bulk_addr);
+        let bulk_server = {
+            let executor = executor.clone();
+            let stop = stop.clone();
+            thread::spawn(move || bulk::serve(executor, listener, stop))
+        };
+        let runs = executor.runs.clone();
+
+        let (close, closing) = oneshot::channel::<()>();
+        let server = tonic::transport::Server::builder()
+            .add_service(AdministrativeServer::new(executor.clone()))
+            .add_service(ShuffleServer::new(executor))
+            .serve_with_incoming_shutdown(
+                socket::incoming(addr, socket::Options::default()).context(Neuromancer)?,
+                closing.map(drop),
+            );
+        futures::pin_mut!(server);
+        if let Either::Left((served, _)) =
+            future::select(&mut server, shutdown::terminated().boxed()).await
+        {
+            served.context(GRPCTransport)?;
+            return Ok(());
+        }
+
+        if !shutdown::drain(&runs, Self::RUN_DEADLINE, Self::COLLECTION_DEADLINE).await {
+            eprintln!("leaving with runs that didn't finish or weren't collected in time");
+        }
+        let supervisor = std::env::var(Self::SUPERVISOR_ADDRESS_VARIABLE)
+            .unwrap_or_else(|_| Self::SUPERVISOR_ADDRESS.to_string());
+        // we're leaving regardless of whether the supervisor could be told about it
+        if let Err(e) = shutdown::depart(supervisor, self.advertised_addr).await {
+            eprintln!(
+                "could not tell the supervisor that the executor is leaving: {}",
+                e
+            );
+        }
+        let _ = close.send(());
+        match timeout(Self::CLOSE_DEADLINE, &mut server).await {
+            Ok(served) => served.context(GRPCTransport)?,
+            Err(_) => eprintln!("leaving with connections that were still open"),
+        }
+        stop.stop();
+        let stopped = tokio::task::spawn_blocking(move || bulk_server.join());
+        if timeout(Self::CLOSE_DEADLINE, stopped).await.is_err() {
+            eprintln!("leaving with bulk fetches that were still being sent");
+        }
         Ok(())
     }
 }
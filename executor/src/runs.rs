@@ -0,0 +1,97 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crossbeam_utils::sync::ShardedLock;
+use snafu::ensure;
+use uuid::Uuid;
+
+use crate::errors::*;
+use neuromancer::{read_lock, write_lock};
+
+pub(crate) use neuromancer::executor::run_progression::Status as RunStatus;
+
+/// The runs this executor knows about, shared between the services and the shutdown path so
+/// that a draining executor can tell when it is safe to leave the cluster
+pub(crate) struct Runs {
+    table: ShardedLock<BTreeMap<Uuid, Run>>,
+    draining: AtomicBool,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct Run {
+    pub(crate) status: RunStatus,
+    /// whether the supervisor has obtained the results of the run
+    pub(crate) collected: bool,
+}
+
+// FIXME: start, finish and collect are driven by the Mapper, Combiner and Reducer services once
+// they are implemented
+#[allow(dead_code)]
+impl Runs {
+    pub(crate) fn new() -> Self {
+        Self {
+            table: ShardedLock::new(BTreeMap::default()),
+            draining: AtomicBool::new(false),
+        }
+    }
+
+    /// Registers a new run, refused once the executor has started draining
+    pub(crate) fn start(&self, uuid: Uuid) -> Result<()> {
+        let mut table = write_lock!(self.table);
+        // checked under the lock so that a run can't sneak in after the drain observed the table
+        ensure!(!self.is_draining(), Draining);
+        table.insert(
+            uuid,
+            Run {
+                status: RunStatus::Incomplete,
+                collected: false,
+            },
+        );
+        Ok(())
+    }
+
+    pub(crate) fn finish(&self, uuid: Uuid, status: RunStatus) {
+        if let Some(run) = write_lock!(self.table).get_mut(&uuid) {
+            run.status = status;
+        }
+    }
+
+    /// Marks the results of a run as handed off to the supervisor
+    pub(crate) fn collect(&self, uuid: Uuid) {
+        if let Some(run) = write_lock!(self.table).get_mut(&uuid) {
+            run.collected = true;
+        }
+    }
+
+    pub(crate) fn get(&self, uuid: Uuid) -> Option<Run> {
+        read_lock!(self.table).get(&uuid).copied()
+    }
+
+    /// The runs that are still executing
+    pub(crate) fn active(&self) -> Vec<Uuid> {
+        self.filter(|run| run.status == RunStatus::Incomplete)
+    }
+
+    /// The runs that finished but whose results haven't been collected by the supervisor
+    pub(crate) fn uncollected(&self) -> Vec<Uuid> {
+        self.filter(|run| run.status == RunStatus::Finished && !run.collected)
+    }
+
+    /// Stops accepting new runs, this cannot be undone
+    pub(crate) fn drain(&self) {
+        let _table = write_lock!(self.table);
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
+    pub(crate) fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    fn filter(&self, predicate: impl Fn(&Run) -> bool) -> Vec<Uuid> {
+        read_lock!(self.table)
+            .iter()
+            .filter(|(_, run)| predicate(run))
+            .map(|(uuid, _)| *uuid)
+            .collect()
+    }
+}
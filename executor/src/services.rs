@@ -1 +1,4 @@
 mod administrative;
+mod shuffle;
+
+pub(crate) use neuromancer::checksum::checksum_of;
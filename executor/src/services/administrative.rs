@@ -1,10 +1,14 @@
-use std::convert::TryInto;
-
-use tonic::{Request, Response, Status};
+use tonic::{Code, Request, Response, Status};
 
 use crate::errors::*;
-use crate::executor::{Executor, Librarian, ToLibrarian};
-use neuromancer::{executor::administrative_server::*, executor::*, write_lock, *};
+use crate::executor::{Executor, KnownLibrarians, Librarian, ToLibrarian};
+use crate::services::checksum_of;
+use neuromancer::{
+    base::{Identifier, MembershipEpoch},
+    executor::administrative_server::*,
+    executor::*,
+    read_lock, write_lock, *,
+};
 
 #[tonic::async_trait]
 impl Administrative for Executor {
@@ -14,39 +18,136 @@ impl Administrative for Executor {
     ) -> Result<Response<()>, Status> {
         let request = request.into_inner();
 
-        // elide bounds checks
-        if request.checksum.len() != 8 {
-            return Err(Status::out_of_range(
-                "length mismatch for checksum".to_string(),
-            ));
+        let mut librarians = write_lock!(self.librarians);
+
+        if !librarians.is_newer(request.epoch) {
+            return Err(stale_epoch(librarians.epoch(), request.epoch));
         }
 
-        let checksum = u64::from_ne_bytes(request.checksum[..].try_into().unwrap());
-        match request.librarians.checksum() {
-            Ok(computed) if computed != checksum => {
-                return Err(Status::invalid_argument(
-                    Error::ChecksumMismatch {
-                        computed,
-                        request: checksum,
-                    }
-                    .to_string(),
-                ))
-            }
-            Err(e) => return Err(Status::aborted(e.to_string())),
-            Ok(_) => (), // all is well
+        let new_librarians: Vec<Librarian> = request
+            .librarians
+            .into_iter()
+            .map(|s| s.to_librarian())
+            .collect();
+        librarians.modify_membership(&new_librarians);
+        librarians.set_epoch(request.epoch);
+        self.rebalance(&librarians);
+        Ok(Response::new(()))
+    }
+
+    async fn add_librarians(
+        &self,
+        request: Request<LibrarianMembershipDelta>,
+    ) -> Result<Response<()>, Status> {
+        let request = request.into_inner();
+
+        let mut librarians = write_lock!(self.librarians);
+
+        if !librarians.is_newer(request.epoch) {
+            return Err(stale_epoch(librarians.epoch(), request.epoch));
         }
 
+        let added: Vec<Librarian> = request
+            .librarians
+            .into_iter()
+            .map(|s| s.to_librarian())
+            .collect();
+        librarians.add_members(&added);
+        librarians.set_epoch(request.epoch);
+        // the new librarians take over part of the ring from the ones that were there before
+        self.rebalance(&librarians);
+        Ok(Response::new(()))
+    }
+
+    async fn remove_librarians(
+        &self,
+        request: Request<LibrarianMembershipDelta>,
+    ) -> Result<Response<()>, Status> {
+        let request = request.into_inner();
+
         let mut librarians = write_lock!(self.librarians);
 
-        let new_librarians: Vec<Librarian> = request
+        if !librarians.is_newer(request.epoch) {
+            return Err(stale_epoch(librarians.epoch(), request.epoch));
+        }
+
+        let deleted: Vec<Librarian> = request
             .librarians
             .into_iter()
             .map(|s| s.to_librarian())
             .collect();
-        let removed = librarians.modify_membership(&new_librarians);
-        self.rebalance(removed);
+        librarians.remove_members(&deleted);
+        librarians.set_epoch(request.epoch);
+        self.rebalance(&librarians);
         Ok(Response::new(()))
     }
+
+    async fn get_membership(&self, _request: Request<()>) -> Result<Response<Membership>, Status> {
+        let librarians = read_lock!(self.librarians);
+        Ok(Response::new(membership(&librarians)))
+    }
+
+    async fn get_state(&self, _request: Request<()>) -> Result<Response<ExecutorState>, Status> {
+        let librarians = read_lock!(self.librarians);
+        let identifier_mappings = read_lock!(self.identifier_mappings);
+
+        let mut membership = membership(&librarians);
+        membership.checksum = checksum_of(&membership)?;
+        let ring = librarians
+            .ring()
+            .layout()
+            .map(|(token, librarian)| RingPosition {
+                token,
+                librarian: librarian.address().to_string(),
+            })
+            .collect();
+        let assignments = identifier_mappings
+            .iter()
+            .map(|(librarian, uuids)| LibrarianAssignment {
+                librarian: librarian.address().to_string(),
+                identifiers: uuids.len() as u64,
+            })
+            .collect();
+        let active_runs = self
+            .runs
+            .active()
+            .into_iter()
+            .map(|uuid| Identifier {
+                uuid: uuid.to_string(),
+            })
+            .collect();
+
+        let state = ExecutorState {
+            membership: Some(membership),
+            ring,
+            assignments,
+            active_runs,
+            bulk_address: self.bulk_address.clone(),
+            ..Default::default()
+        };
+        Ok(Response::new(state))
+    }
+}
+
+fn membership(librarians: &KnownLibrarians) -> Membership {
+    Membership {
+        librarians: librarians
+            .members()
+            .into_iter()
+            .map(|librarian| librarian.address().to_string())
+            .collect(),
+        epoch: librarians.epoch(),
+        ..Default::default()
+    }
+}
+
+/// FAILED_PRECONDITION carrying the executor's epoch so that the sender can resynchronise
+fn stale_epoch(current: u64, request: u64) -> Status {
+    status_with_details(
+        Code::FailedPrecondition,
+        Error::StaleMembershipEpoch { current, request }.to_string(),
+        &MembershipEpoch { epoch: current },
+    )
 }
 
 #[cfg(test)]
@@ -56,16 +157,29 @@ mod tests {
     use futures::future::FutureExt;
     use tokio::sync::oneshot;
     use tokio::sync::oneshot::Receiver;
+    use uuid::Uuid;
 
     use super::*;
+    use crate::runs::RunStatus;
     use neuromancer::executor::administrative_client::AdministrativeClient;
+    use neuromancer::Algorithm;
 
     const CHECKSUM_MISMATCH_ADDRESS: &str = "[::1]:1337";
     const DEFAULT_STARTUP_TIMEOUT: u64 = 1;
     const LENGTH_MISMATCH_ADDRESS: &str = "[::1]:1336";
+    const STALE_EPOCH_ADDRESS: &str = "[::1]:1335";
+    const MEMBERSHIP_DELTA_ADDRESS: &str = "[::1]:1334";
+    const INTROSPECTION_ADDRESS: &str = "[::1]:1333";
 
     async fn gen_server(addr: &'static str, rx: Receiver<()>) -> tokio::task::JoinHandle<()> {
-        let executor = Executor::new();
+        gen_server_with(addr, rx, Executor::new()).await
+    }
+
+    async fn gen_server_with(
+        addr: &'static str,
+        rx: Receiver<()>,
+        executor: Executor,
+    ) -> tokio::task::JoinHandle<()> {
         let server = tokio::spawn(async move {
             tonic::transport::Server::builder()
                 .add_service(AdministrativeServer::new(executor))
@@ -87,7 +201,8 @@ mod tests {
 
         let payload = LibrarianMembershipChangeRequest {
             librarians: vec!["foo".into()],
-            checksum: "12345678".as_bytes().into(),
+            checksum: [&[Algorithm::Xxh3 as u8][..], b"12345678"].concat(),
+            epoch: 1,
         };
 
         let err = client
@@ -112,7 +227,8 @@ mod tests {
 
         let payload = LibrarianMembershipChangeRequest {
             librarians: vec!["foo".into()],
-            checksum: "1234".as_bytes().into(),
+            checksum: vec![Algorithm::Xxh3 as u8, 1, 2, 3, 4],
+            epoch: 1,
         };
 
         let err = client
@@ -126,4 +242,172 @@ mod tests {
         tx.send(()).unwrap();
         server.await.unwrap();
     }
+
+    fn membership_change(librarians: &[&str], epoch: u64) -> LibrarianMembershipChangeRequest {
+        let mut request = LibrarianMembershipChangeRequest {
+            librarians: librarians.iter().map(|s| s.to_string()).collect(),
+            epoch,
+            ..Default::default()
+        };
+        request.checksum = request.checksum().unwrap();
+        request
+    }
+
+    #[tokio::test]
+    async fn returns_failed_precondition_for_stale_epoch() {
+        let (tx, rx) = oneshot::channel::<()>();
+        let server = gen_server(STALE_EPOCH_ADDRESS, rx).await;
+        let mut client_address = String::from("http://");
+        client_address += STALE_EPOCH_ADDRESS;
+        let mut client = AdministrativeClient::connect(client_address).await.unwrap();
+
+        // a fresh executor takes a change that carries no epoch, but only the one since a
+        // second could be a replay of it
+        client
+            .librarian_membership_change(Request::new(membership_change(&["foo"], 0)))
+            .await
+            .unwrap();
+        let err = client
+            .librarian_membership_change(Request::new(membership_change(&["foo"], 0)))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::FailedPrecondition);
+        client
+            .librarian_membership_change(Request::new(membership_change(&["foo", "bar"], 2)))
+            .await
+            .unwrap();
+        let err = client
+            .librarian_membership_change(Request::new(membership_change(&["foo"], 1)))
+            .await
+            .unwrap_err();
+        let details: MembershipEpoch = details_from_status(&err).unwrap();
+
+        assert_eq!(err.code(), tonic::Code::FailedPrecondition);
+        assert_eq!(details.epoch, 2);
+
+        // replaying the current epoch is just as stale, as is leaving the epoch out once one
+        // has been applied
+        for epoch in &[2, 0] {
+            let err = client
+                .librarian_membership_change(Request::new(membership_change(&["foo"], *epoch)))
+                .await
+                .unwrap_err();
+            assert_eq!(err.code(), tonic::Code::FailedPrecondition);
+        }
+
+        client
+            .librarian_membership_change(Request::new(membership_change(&["foo"], 3)))
+            .await
+            .unwrap();
+
+        tx.send(()).unwrap();
+        server.await.unwrap();
+    }
+
+    fn membership_delta(librarians: &[&str], epoch: u64) -> LibrarianMembershipDelta {
+        let mut request = LibrarianMembershipDelta {
+            librarians: librarians.iter().map(|s| s.to_string()).collect(),
+            epoch,
+            ..Default::default()
+        };
+        request.checksum = request.checksum().unwrap();
+        request
+    }
+
+    #[tokio::test]
+    async fn applies_membership_deltas() {
+        let (tx, rx) = oneshot::channel::<()>();
+        let server = gen_server(MEMBERSHIP_DELTA_ADDRESS, rx).await;
+        let mut client_address = String::from("http://");
+        client_address += MEMBERSHIP_DELTA_ADDRESS;
+        let mut client = AdministrativeClient::connect(client_address).await.unwrap();
+
+        client
+            .add_librarians(Request::new(membership_delta(&["foo", "bar"], 1)))
+            .await
+            .unwrap();
+        client
+            .remove_librarians(Request::new(membership_delta(&["foo"], 2)))
+            .await
+            .unwrap();
+        // deltas share the epoch sequence with full membership changes
+        let err = client
+            .librarian_membership_change(Request::new(membership_change(&["baz"], 2)))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::FailedPrecondition);
+
+        let mut payload = membership_delta(&["baz"], 3);
+        payload.checksum = vec![Algorithm::Xxh3 as u8, 1, 2, 3, 4];
+        let err = client
+            .add_librarians(Request::new(payload))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::OutOfRange);
+
+        tx.send(()).unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn reports_membership_and_state() {
+        let (tx, rx) = oneshot::channel::<()>();
+        let mut executor = Executor::new();
+        executor.bulk_address = "unix:///run/executor-bulk.sock".to_string();
+        let active = Uuid::from_u128(1);
+        executor.runs.start(active).unwrap();
+        executor.runs.start(Uuid::from_u128(2)).unwrap();
+        executor
+            .runs
+            .finish(Uuid::from_u128(2), RunStatus::Finished);
+        write_lock!(executor.identifier_mappings)
+            .insert(Librarian::new("foo"), vec![Uuid::from_u128(3)]);
+        let server = gen_server_with(INTROSPECTION_ADDRESS, rx, executor).await;
+        let mut client_address = String::from("http://");
+        client_address += INTROSPECTION_ADDRESS;
+        let mut client = AdministrativeClient::connect(client_address).await.unwrap();
+
+        client
+            .librarian_membership_change(Request::new(membership_change(&["foo", "bar"], 4)))
+            .await
+            .unwrap();
+        let membership = client
+            .get_membership(Request::new(()))
+            .await
+            .unwrap()
+            .into_inner();
+        let state = client
+            .get_state(Request::new(()))
+            .await
+            .unwrap()
+            .into_inner();
+
+        assert_eq!(membership.librarians, vec!["bar", "foo"]);
+        assert_eq!(membership.epoch, 4);
+        assert_eq!(membership.checksum, membership.checksum().unwrap());
+        assert_eq!(state.membership, Some(membership));
+        assert_eq!(state.bulk_address, "unix:///run/executor-bulk.sock");
+        assert_eq!(state.ring.len(), 2);
+        assert!(state
+            .ring
+            .windows(2)
+            .all(|pair| pair[0].token < pair[1].token));
+        // the identifier was rebalanced onto whichever librarian owns it on the new ring
+        assert_eq!(state.assignments.len(), 1);
+        assert_eq!(state.assignments[0].identifiers, 1);
+        let owner = state
+            .ring
+            .iter()
+            .any(|position| position.librarian == state.assignments[0].librarian);
+        assert!(owner);
+        assert_eq!(
+            state.active_runs,
+            vec![Identifier {
+                uuid: active.to_string()
+            }]
+        );
+
+        tx.send(()).unwrap();
+        server.await.unwrap();
+    }
 }
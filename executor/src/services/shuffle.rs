@@ -0,0 +1,213 @@
+use std::pin::Pin;
+use std::sync::Arc;
+
+use futures::{stream, Stream, StreamExt};
+use tonic::{Request, Response, Status};
+use uuid::Uuid;
+
+use crate::errors::*;
+use crate::executor::{Executor, Partition};
+use neuromancer::{base::Map, executor::shuffle_server::*, executor::*, read_lock};
+
+/// the number of records sent per chunk, small enough that a chunk never comes close to the grpc
+/// message limit for reasonably sized records
+const CHUNK_RECORDS: usize = 512;
+
+#[tonic::async_trait]
+impl Shuffle for Executor {
+    type FetchStream =
+        Pin<Box<dyn Stream<Item = Result<ShuffleChunk, Status>> + Send + Sync + 'static>>;
+
+    async fn fetch(
+        &self,
+        request: Request<FetchRequest>,
+    ) -> Result<Response<Self::FetchStream>, Status> {
+        let request = request.into_inner();
+        let (_, records) = self.partition_records(&request)?;
+
+        let offsets = (request.offset as usize..records.len()).step_by(CHUNK_RECORDS);
+        let chunks = stream::iter(offsets).map(move |offset| Ok(chunk(&records, offset)));
+        Ok(Response::new(Box::pin(chunks) as Self::FetchStream))
+    }
+}
+
+impl Executor {
+    /// The partition a fetch asks for, whichever channel it came over
+    pub(crate) fn partition_records(
+        &self,
+        request: &FetchRequest,
+    ) -> Result<(Partition, Arc<Vec<Map>>), Status> {
+        let run = match &request.run_id {
+            Some(identifier) if !identifier.uuid.is_empty() => {
+                match Uuid::parse_str(&identifier.uuid) {
+                    Ok(uuid) => uuid,
+                    Err(source) => {
+                        return Err(Status::invalid_argument(
+                            Error::UuidEncoding { source }.to_string(),
+                        ));
+                    }
+                }
+            }
+            _ => {
+                return Err(Status::invalid_argument(
+                    Error::NoIdentifierProvided.to_string(),
+                ))
+            }
+        };
+
+        let partition = Partition {
+            run,
+            index: request.partition,
+        };
+        let records = match read_lock!(self.partitions).get(&partition) {
+            Some(records) => records.clone(),
+            None => {
+                return Err(Status::failed_precondition(
+                    Error::PartitionNotFound {
+                        run,
+                        index: partition.index,
+                    }
+                    .to_string(),
+                ))
+            }
+        };
+
+        let len = records.len() as u64;
+        if request.offset > len {
+            return Err(Status::out_of_range(
+                Error::OffsetOutOfRange {
+                    offset: request.offset,
+                    len,
+                }
+                .to_string(),
+            ));
+        }
+        Ok((partition, records))
+    }
+}
+
+fn chunk(records: &Arc<Vec<Map>>, offset: usize) -> ShuffleChunk {
+    let end = records.len().min(offset + CHUNK_RECORDS);
+    ShuffleChunk {
+        offset: offset as u64,
+        records: records[offset..end].to_vec(),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::future::FutureExt;
+    use tokio::sync::oneshot;
+    use tokio::sync::oneshot::Receiver;
+
+    use super::*;
+    use neuromancer::base::Identifier;
+    use neuromancer::executor::shuffle_client::ShuffleClient;
+    use neuromancer::{write_lock, Checksummable};
+
+    const DEFAULT_STARTUP_TIMEOUT: u64 = 1;
+    const FETCH_ADDRESS: &str = "[::1]:1331";
+    const FETCH_ERRORS_ADDRESS: &str = "[::1]:1330";
+
+    async fn gen_server(addr: &'static str, rx: Receiver<()>) -> tokio::task::JoinHandle<()> {
+        let executor = Executor::new();
+        let records: Vec<Map> = (0..1200)
+            .map(|i| Map {
+                key: format!("key-{}", i).into_bytes(),
+                value: i.to_string().into_bytes(),
+                ..Default::default()
+            })
+            .collect();
+        write_lock!(executor.partitions).insert(
+            Partition {
+                run: Uuid::from_u128(1),
+                index: 0,
+            },
+            Arc::new(records),
+        );
+        let server = tokio::spawn(async move {
+            tonic::transport::Server::builder()
+                .add_service(ShuffleServer::new(executor))
+                .serve_with_shutdown(addr.parse().unwrap(), rx.map(drop))
+                .await
+                .unwrap();
+        });
+        tokio::time::delay_for(Duration::from_millis(DEFAULT_STARTUP_TIMEOUT)).await;
+        server
+    }
+
+    fn fetch_request(run: u128, partition: u32, offset: u64) -> FetchRequest {
+        let mut request = FetchRequest {
+            run_id: Some(Identifier {
+                uuid: Uuid::from_u128(run).to_string(),
+            }),
+            partition,
+            offset,
+            ..Default::default()
+        };
+        request.checksum = request.checksum().unwrap();
+        request
+    }
+
+    #[tokio::test]
+    async fn streams_checksummed_chunks_from_offset() {
+        let (tx, rx) = oneshot::channel::<()>();
+        let server = gen_server(FETCH_ADDRESS, rx).await;
+        let mut client_address = String::from("http://");
+        client_address += FETCH_ADDRESS;
+        let mut client = ShuffleClient::connect(client_address).await.unwrap();
+
+        for &offset in &[0u64, 700, 1200] {
+            let mut stream = client
+                .fetch(Request::new(fetch_request(1, 0, offset)))
+                .await
+                .unwrap()
+                .into_inner();
+            let mut expected = offset;
+            while let Some(chunk) = stream.next().await {
+                let chunk = chunk.unwrap();
+                assert_eq!(chunk.checksum().unwrap(), chunk.checksum);
+                assert_eq!(chunk.offset, expected);
+                assert_eq!(chunk.records[0].key, format!("key-{}", expected).as_bytes());
+                expected += chunk.records.len() as u64;
+            }
+            assert_eq!(expected, 1200);
+        }
+
+        tx.send(()).unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_missing_partitions_and_bad_offsets() {
+        let (tx, rx) = oneshot::channel::<()>();
+        let server = gen_server(FETCH_ERRORS_ADDRESS, rx).await;
+        let mut client_address = String::from("http://");
+        client_address += FETCH_ERRORS_ADDRESS;
+        let mut client = ShuffleClient::connect(client_address).await.unwrap();
+
+        let err = client
+            .fetch(Request::new(fetch_request(1, 1, 0)))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::FailedPrecondition);
+
+        let err = client
+            .fetch(Request::new(fetch_request(1, 0, 1201)))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::OutOfRange);
+
+        let mut payload = fetch_request(1, 0, 0);
+        payload.run_id = None;
+        payload.checksum = payload.checksum().unwrap();
+        let err = client.fetch(Request::new(payload)).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+
+        tx.send(()).unwrap();
+        server.await.unwrap();
+    }
+}
@@ -0,0 +1,155 @@
+use std::time::Duration;
+
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::{delay_for, timeout};
+use tonic::Request;
+
+use crate::errors::*;
+use crate::runs::Runs;
+use neuromancer::socket;
+use neuromancer::supervisor::{membership_client::MembershipClient, ExecutorDeparture};
+
+/// Resolves once the process receives SIGTERM
+pub(crate) async fn terminated() {
+    match signal(SignalKind::terminate()) {
+        Ok(mut terminate) => {
+            terminate.recv().await;
+        }
+        // without a handler the executor can only be killed
+        Err(_) => futures::future::pending().await,
+    }
+}
+
+/// how often the drain checks whether the runs have settled
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Stops accepting runs, then waits up to `run_deadline` for the runs in flight to finish and up
+/// to `collection_deadline` after that for the supervisor to collect the results of the finished
+/// ones. Returns whether everything settled before the deadlines
+pub(crate) async fn drain(
+    runs: &Runs,
+    run_deadline: Duration,
+    collection_deadline: Duration,
+) -> bool {
+    runs.drain();
+    let finished = settle(|| runs.active().is_empty(), run_deadline).await;
+    // the runs that did finish can still be collected
+    let collected = settle(|| runs.uncollected().is_empty(), collection_deadline).await;
+    finished && collected
+}
+
+async fn settle(settled: impl Fn() -> bool, deadline: Duration) -> bool {
+    let waiting = async {
+        while !settled() {
+            delay_for(DRAIN_POLL_INTERVAL).await;
+        }
+    };
+    timeout(deadline, waiting).await.is_ok()
+}
+
+/// Tells the supervisor that the executor it reaches at `address` is leaving the cluster
+pub(crate) async fn depart(supervisor: String, address: String) -> Result<()> {
+    let mut client =
+        MembershipClient::new(socket::connect(supervisor).await.context(GRPCTransport)?);
+    let departure = ExecutorDeparture {
+        address,
+        ..Default::default()
+    };
+    client
+        .executor_leaving(Request::new(departure))
+        .await
+        .context(GRPCStatus)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use futures::future::FutureExt;
+    use parking_lot::Mutex;
+    use tokio::sync::oneshot;
+    use tonic::{Response, Status};
+
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::runs::RunStatus;
+    use neuromancer::supervisor::membership_server::{Membership, MembershipServer};
+
+    const SUPERVISOR_ADDRESS: &str = "[::1]:1332";
+
+    #[derive(Default)]
+    struct Supervisor {
+        departed: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[tonic::async_trait]
+    impl Membership for Supervisor {
+        async fn executor_leaving(
+            &self,
+            request: Request<ExecutorDeparture>,
+        ) -> Result<Response<()>, Status> {
+            self.departed.lock().push(request.into_inner().address);
+            Ok(Response::new(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn drain_waits_for_runs_and_collection() {
+        let runs = Arc::new(Runs::new());
+        let uuid = Uuid::from_u128(1);
+        runs.start(uuid).unwrap();
+
+        let worker = runs.clone();
+        tokio::spawn(async move {
+            delay_for(Duration::from_millis(20)).await;
+            worker.finish(uuid, RunStatus::Finished);
+            delay_for(Duration::from_millis(20)).await;
+            worker.collect(uuid);
+        });
+
+        assert!(drain(&runs, Duration::from_secs(5), Duration::from_secs(5)).await);
+        assert!(runs.start(Uuid::from_u128(2)).is_err());
+        assert_eq!(runs.get(Uuid::from_u128(2)), None);
+    }
+
+    #[tokio::test]
+    async fn drain_gives_up_after_deadlines() {
+        let runs = Runs::new();
+        runs.start(Uuid::from_u128(1)).unwrap();
+        runs.start(Uuid::from_u128(2)).unwrap();
+        runs.finish(Uuid::from_u128(2), RunStatus::Finished);
+
+        let drained = drain(&runs, Duration::from_millis(30), Duration::from_millis(30));
+        assert!(!drained.await);
+        assert!(runs.is_draining());
+        assert_eq!(runs.uncollected(), vec![Uuid::from_u128(2)]);
+    }
+
+    #[tokio::test]
+    async fn depart_notifies_supervisor() {
+        let (tx, rx) = oneshot::channel::<()>();
+        let supervisor = Supervisor::default();
+        let departed = supervisor.departed.clone();
+        let server = tokio::spawn(async move {
+            tonic::transport::Server::builder()
+                .add_service(MembershipServer::new(supervisor))
+                .serve_with_shutdown(SUPERVISOR_ADDRESS.parse().unwrap(), rx.map(drop))
+                .await
+                .unwrap();
+        });
+        delay_for(Duration::from_millis(1)).await;
+
+        let mut supervisor_address = String::from("http://");
+        supervisor_address += SUPERVISOR_ADDRESS;
+        depart(supervisor_address, "[::1]:9001".to_string())
+            .await
+            .unwrap();
+
+        assert_eq!(*departed.lock(), vec!["[::1]:9001".to_string()]);
+
+        tx.send(()).unwrap();
+        server.await.unwrap();
+    }
+}
@@ -0,0 +1,208 @@
+//! Dumps a librarian's graph, or seeds an empty librarian with a JSON dump
+//!
+//! ```text
+//! librarian-graph export [json|dot] [--root <uuid>] [--librarian <address>]
+//! librarian-graph import <file> [--librarian <address>]
+//! ```
+//!
+//! Exports are written to stdout. Exits with 2 for bad usage and 1 when the librarian can't be
+//! reached or refuses the request.
+
+use std::error::Error;
+use std::io::{self, Write};
+use std::{env, fs, process};
+
+use futures::stream;
+
+use neuromancer::base::Identifier;
+use neuromancer::librarian::{dump_client::DumpClient, export_request::Format, *};
+use neuromancer::socket;
+
+/// `unix://<path>` reaches a librarian on the same host over its socket file
+const DEFAULT_LIBRARIAN_ADDRESS: &str = "http://[::1]:1337";
+/// dumps are sent in chunks of this many bytes, a chunk stays far below the grpc message limit
+const CHUNK_LEN: usize = 1 << 20;
+const EXIT_FAILURE: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const USAGE: &str = "usage:
+    librarian-graph export [json|dot] [--root <uuid>] [--librarian <address>]
+    librarian-graph import <file> [--librarian <address>]";
+
+enum Command {
+    Export {
+        format: Format,
+        root: Option<String>,
+    },
+    Import {
+        path: String,
+    },
+}
+
+struct Arguments {
+    command: Command,
+    librarian: String,
+}
+
+fn parse(mut args: impl Iterator<Item = String>) -> Option<Arguments> {
+    let command = args.next()?;
+    let mut librarian = DEFAULT_LIBRARIAN_ADDRESS.to_string();
+    let mut root = None;
+    let mut positional = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--librarian" => librarian = args.next()?,
+            "--root" => root = Some(args.next()?),
+            _ => positional.push(arg),
+        }
+    }
+
+    let command = match (command.as_str(), positional.as_slice()) {
+        ("export", []) => Command::Export {
+            format: Format::Json,
+            root,
+        },
+        ("export", [format]) => Command::Export {
+            format: match format.as_str() {
+                "json" => Format::Json,
+                "dot" => Format::Dot,
+                _ => return None,
+            },
+            root,
+        },
+        ("import", [path]) if root.is_none() => Command::Import { path: path.clone() },
+        _ => return None,
+    };
+    Some(Arguments { command, librarian })
+}
+
+#[tokio::main]
+async fn main() {
+    process::exit(run(env::args().skip(1)).await);
+}
+
+/// The exit code: 2 for bad usage, 1 when the librarian can't be reached or refuses the request
+async fn run(args: impl Iterator<Item = String>) -> i32 {
+    let arguments = match parse(args) {
+        Some(arguments) => arguments,
+        None => {
+            eprintln!("{}", USAGE);
+            return EXIT_USAGE;
+        }
+    };
+    match execute(arguments).await {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("librarian-graph: {}", e);
+            EXIT_FAILURE
+        }
+    }
+}
+
+async fn execute(arguments: Arguments) -> Result<(), Box<dyn Error>> {
+    let mut client = DumpClient::new(socket::connect(arguments.librarian).await?);
+
+    match arguments.command {
+        Command::Export { format, root } => {
+            let request = ExportRequest {
+                format: format as i32,
+                root: root.map(|uuid| Identifier { uuid }),
+                ..Default::default()
+            };
+            let mut chunks = client.export(request).await?.into_inner();
+            let stdout = io::stdout();
+            let mut stdout = stdout.lock();
+            while let Some(chunk) = chunks.message().await? {
+                stdout.write_all(&chunk.contents)?;
+            }
+            stdout.flush()?;
+        }
+        Command::Import { path } => {
+            let contents = fs::read(path)?;
+            let chunks: Vec<GraphDump> = contents
+                .chunks(CHUNK_LEN)
+                .map(|chunk| GraphDump {
+                    format: Format::Json as i32,
+                    contents: chunk.to_vec(),
+                    ..Default::default()
+                })
+                .collect();
+            client.import(stream::iter(chunks)).await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // nothing listens here
+    const UNREACHABLE_LIBRARIAN: &str = "http://[::1]:1300";
+
+    fn args(args: &[&str]) -> impl Iterator<Item = String> {
+        args.iter()
+            .map(|arg| arg.to_string())
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn parses_exports() {
+        let arguments = parse(args(&["export"])).unwrap();
+        assert!(matches!(
+            arguments.command,
+            Command::Export {
+                format: Format::Json,
+                root: None
+            }
+        ));
+        assert_eq!(arguments.librarian, DEFAULT_LIBRARIAN_ADDRESS);
+
+        let arguments = parse(args(&[
+            "export",
+            "--root",
+            "foo",
+            "dot",
+            "--librarian",
+            "unix:///tmp/librarian.sock",
+        ]))
+        .unwrap();
+        match arguments.command {
+            Command::Export { format, root } => {
+                assert_eq!(format, Format::Dot);
+                assert_eq!(root.as_deref(), Some("foo"));
+            }
+            Command::Import { .. } => panic!("expected an export"),
+        }
+        assert_eq!(arguments.librarian, "unix:///tmp/librarian.sock");
+    }
+
+    #[test]
+    fn parses_imports() {
+        let arguments = parse(args(&["import", "graph.json"])).unwrap();
+        assert!(matches!(arguments.command, Command::Import { path } if path == "graph.json"));
+    }
+
+    #[test]
+    fn refuses_bad_usage() {
+        for bad in &[
+            &[][..],
+            &["export", "yaml"],
+            &["export", "json", "dot"],
+            &["export", "--root"],
+            &["import"],
+            &["import", "graph.json", "--root", "foo"],
+            &["import", "graph.json", "--librarian"],
+            &["remap"],
+        ] {
+            assert!(parse(args(bad)).is_none(), "{:?}", bad);
+        }
+    }
+
+    #[tokio::test]
+    async fn exits_with_the_kind_of_failure() {
+        assert_eq!(run(args(&["export", "yaml"])).await, EXIT_USAGE);
+        let unreachable = ["export", "--librarian", UNREACHABLE_LIBRARIAN];
+        assert_eq!(run(args(&unreachable)).await, EXIT_FAILURE);
+    }
+}
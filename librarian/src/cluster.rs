@@ -0,0 +1,197 @@
+use std::collections::BTreeSet;
+
+use uuid::Uuid;
+
+use neuromancer::membership::Epoch;
+use neuromancer::ring::Ring;
+
+/// What a librarian knows about its peers, used to decide which librarians hold a copy of an
+/// identifier. The ring is laid out the same way as the executors' so that the librarian an
+/// executor routes an identifier to is the first replica of that identifier
+pub(crate) struct Cluster {
+    /// the address this librarian is known by on the ring
+    address: String,
+    /// how many librarians hold a copy of every identifier, including its owner
+    replication: usize,
+    ring: Ring<String>,
+    epoch: Epoch,
+}
+
+impl Cluster {
+    pub(crate) fn new(address: impl Into<String>, replication: usize) -> Self {
+        Self {
+            address: address.into(),
+            replication,
+            ring: Ring::new(),
+            epoch: Epoch::default(),
+        }
+    }
+
+    /// The epoch of the membership list that was last applied, a fresh librarian starts at 0
+    pub(crate) fn epoch(&self) -> u64 {
+        self.epoch.current()
+    }
+
+    /// Whether a change sent at `epoch` is newer than the membership that is currently known,
+    /// by the same rule the executors apply
+    pub(crate) fn is_newer(&self, epoch: u64) -> bool {
+        self.epoch.is_newer(epoch)
+    }
+
+    /// Replaces the membership list, callers are expected to have checked the epoch
+    pub(crate) fn modify_membership(&mut self, librarians: &[String], epoch: u64) {
+        let mut ring = Ring::new();
+        for librarian in librarians {
+            ring.add(librarian, 1);
+        }
+        self.ring = ring;
+        self.epoch.apply(epoch);
+    }
+
+    /// The librarians that hold a copy of `uuid`, the owner first. Empty until a membership
+    /// list has been received, which is how a standalone librarian keeps every write local
+    pub(crate) fn replicas(&self, uuid: Uuid) -> Vec<String> {
+        self.ring
+            .successors(uuid.as_bytes(), self.replication)
+            .into_iter()
+            .cloned()
+            .collect()
+    }
+
+    /// The owner of `uuid` when this librarian holds no copy of it, every identifier is held
+    /// locally until a membership list has been received
+    pub(crate) fn redirect(&self, uuid: Uuid) -> Option<String> {
+        let replicas = self.replicas(uuid);
+        if replicas.iter().any(|replica| self.is_local(replica)) {
+            return None;
+        }
+        replicas.into_iter().next()
+    }
+
+    /// The owner of `uuid` when it isn't this librarian, unlike `redirect` this also points the
+    /// other replicas to the owner
+    pub(crate) fn redirect_to_owner(&self, uuid: Uuid) -> Option<String> {
+        let owner = self.replicas(uuid).into_iter().next()?;
+        if self.is_local(&owner) {
+            None
+        } else {
+            Some(owner)
+        }
+    }
+
+    /// The other librarians, whose graphs hold edges that this librarian's doesn't. Empty while
+    /// it holds a copy of every identifier, which it does on its own or when there are no more
+    /// librarians than copies of an identifier
+    pub(crate) fn other_partitions(&self) -> Vec<String> {
+        let members: BTreeSet<&String> = self.ring.layout().map(|(_, member)| member).collect();
+        let holds_everything = members.iter().any(|member| self.is_local(member));
+        if members.len() <= self.replication && holds_everything {
+            return Vec::new();
+        }
+        members
+            .into_iter()
+            .filter(|member| !self.is_local(member))
+            .cloned()
+            .collect()
+    }
+
+    pub(crate) fn is_local(&self, librarian: &str) -> bool {
+        self.address == librarian
+    }
+}
+
+impl Default for Cluster {
+    fn default() -> Self {
+        Self::new(String::new(), 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replicas_are_capped_by_the_membership() {
+        let mut cluster = Cluster::new("foo", 3);
+        assert!(cluster.replicas(Uuid::from_u128(1)).is_empty());
+
+        cluster.modify_membership(&["foo".to_string(), "bar".to_string()], 1);
+        let replicas = cluster.replicas(Uuid::from_u128(1));
+        assert_eq!(replicas.len(), 2);
+        assert!(replicas.iter().any(|replica| cluster.is_local(replica)));
+
+        let librarians: Vec<String> = (0..5).map(|i| format!("librarian-{}", i)).collect();
+        cluster.modify_membership(&librarians, 2);
+        for i in 0..32 {
+            let replicas = cluster.replicas(Uuid::from_u128(i));
+            assert_eq!(replicas.len(), 3);
+            assert!(!replicas.iter().any(|replica| cluster.is_local(replica)));
+        }
+        assert_eq!(cluster.epoch(), 2);
+    }
+
+    #[test]
+    fn only_identifiers_without_a_local_copy_are_redirected() {
+        let mut cluster = Cluster::new("librarian-0", 1);
+        assert_eq!(cluster.redirect(Uuid::from_u128(1)), None);
+
+        let librarians: Vec<String> = (0..3).map(|i| format!("librarian-{}", i)).collect();
+        cluster.modify_membership(&librarians, 1);
+        for i in 0..32 {
+            let uuid = Uuid::from_u128(i);
+            let owner = cluster.replicas(uuid).remove(0);
+            match cluster.redirect(uuid) {
+                Some(redirect) => assert_eq!(redirect, owner),
+                None => assert!(cluster.is_local(&owner)),
+            }
+        }
+    }
+
+    #[test]
+    fn replicas_are_redirected_to_the_owner() {
+        let mut cluster = Cluster::new("librarian-0", 2);
+        assert_eq!(cluster.redirect_to_owner(Uuid::from_u128(1)), None);
+
+        let librarians: Vec<String> = (0..2).map(|i| format!("librarian-{}", i)).collect();
+        cluster.modify_membership(&librarians, 1);
+        for i in 0..32 {
+            let uuid = Uuid::from_u128(i);
+            let owner = cluster.replicas(uuid).remove(0);
+            assert_eq!(cluster.redirect(uuid), None);
+            match cluster.redirect_to_owner(uuid) {
+                Some(redirect) => assert_eq!(redirect, owner),
+                None => assert!(cluster.is_local(&owner)),
+            }
+        }
+    }
+
+    #[test]
+    fn only_partitioned_librarians_have_other_partitions() {
+        let mut cluster = Cluster::new("librarian-0", 2);
+        assert!(cluster.other_partitions().is_empty());
+
+        let librarians: Vec<String> = (0..2).map(|i| format!("librarian-{}", i)).collect();
+        cluster.modify_membership(&librarians, 1);
+        assert!(cluster.other_partitions().is_empty());
+
+        let librarians: Vec<String> = (0..3).map(|i| format!("librarian-{}", i)).collect();
+        cluster.modify_membership(&librarians, 2);
+        assert_eq!(cluster.other_partitions(), &librarians[1..]);
+    }
+
+    #[test]
+    fn refuses_changes_that_are_not_newer() {
+        let mut cluster = Cluster::new("foo", 3);
+        let librarians = ["foo".to_string(), "bar".to_string()];
+        // a fresh librarian takes a change without an epoch, the same one again is a replay
+        assert!(cluster.is_newer(0));
+        cluster.modify_membership(&librarians, 0);
+        assert!(!cluster.is_newer(0));
+
+        cluster.modify_membership(&librarians, 2);
+        for stale in &[0, 1, 2] {
+            assert!(!cluster.is_newer(*stale));
+        }
+        assert!(cluster.is_newer(3));
+    }
+}
@@ -4,6 +4,7 @@ pub use snafu::{OptionExt, ResultExt};
 
 #[derive(Debug, Snafu)]
 #[snafu(visibility = "pub(crate)")]
+#[allow(clippy::enum_variant_names)]
 pub(crate) enum Error {
     #[snafu(display("{}", source))]
     NeuromancerError {
@@ -17,8 +18,106 @@ pub(crate) enum Error {
     UuidEncodingError { source: uuid::Error },
     #[snafu(display("no identifiers were found for {}", uuid))]
     IdentifierNotFound { uuid: uuid::Uuid },
+    #[snafu(display("making {} a parent of {} would create a cycle", parent, child))]
+    CycleDetected {
+        parent: uuid::Uuid,
+        child: uuid::Uuid,
+    },
+    #[snafu(display("the run graph of {} contains a cycle", uuid))]
+    CyclicRunGraph { uuid: uuid::Uuid },
+    #[snafu(display(
+        "events after {} are no longer retained, the oldest is {}",
+        after,
+        oldest
+    ))]
+    EventsExpired { after: u64, oldest: u64 },
+    #[snafu(display("event {} is unknown, the last event is {}", after, last))]
+    UnknownEvent { after: u64, last: u64 },
+    #[snafu(display(
+        "events of epoch {} are gone, the librarian restarted into epoch {}",
+        epoch,
+        current
+    ))]
+    EventsRestarted { epoch: u64, current: u64 },
+    #[snafu(display("the watch fell behind by {} events", missed))]
+    WatchLagged { missed: u64 },
+    #[snafu(display("invalid graph json: {}", source))]
+    InvalidGraphJson { source: serde_json::Error },
+    #[snafu(display("only json dumps can be imported"))]
+    UnsupportedImportFormat,
+    #[snafu(display("graphs can only be imported into an empty librarian"))]
+    GraphNotEmpty,
+    #[snafu(display("{} is already known to the cluster", uuid))]
+    ImportedIdentifierKnown { uuid: uuid::Uuid },
+    #[snafu(display("the imported graph contains a cycle"))]
+    CyclicImport,
     #[snafu(display("no identifier provided"))]
     NoIdentifierProvided,
+    #[snafu(display("no executor provided for the output of {}", uuid))]
+    NoExecutorProvided { uuid: uuid::Uuid },
+    #[snafu(display("no children provided"))]
+    NoChildrenProvided,
+    #[snafu(display("storage error for {}: {}", path.display(), source))]
+    StorageIo {
+        source: std::io::Error,
+        path: std::path::PathBuf,
+    },
+    #[snafu(display("the snapshot at {} is corrupt", path.display()))]
+    CorruptSnapshot { path: std::path::PathBuf },
+    #[snafu(display(
+        "the edge log at {} is corrupt at byte {}, nothing after it was replayed",
+        path.display(),
+        offset
+    ))]
+    CorruptLog {
+        path: std::path::PathBuf,
+        offset: u64,
+    },
+    #[snafu(display("invalid durability level: {}", durability))]
+    InvalidDurability { durability: String },
+    #[snafu(display(
+        "stale membership epoch {}, the librarian is at epoch {}",
+        request,
+        current
+    ))]
+    StaleMembershipEpoch { current: u64, request: u64 },
+    #[snafu(display(
+        "{} of the {} replicas of {} acknowledged the write, {} are needed",
+        acknowledged,
+        replicas,
+        uuid,
+        quorum
+    ))]
+    NoQuorum {
+        uuid: uuid::Uuid,
+        acknowledged: usize,
+        replicas: usize,
+        quorum: usize,
+    },
+    #[snafu(display("{} is not held by this librarian, its owner is {}", uuid, owner))]
+    MisroutedIdentifier { uuid: uuid::Uuid, owner: String },
+    #[snafu(display("{} is only tracked by its owner {}", uuid, owner))]
+    NotTheOwner { uuid: uuid::Uuid, owner: String },
+    #[snafu(display(
+        "the parents {} and {} are held by different librarians, a batch has to be sent to \
+         the owner of its parents",
+        first,
+        other
+    ))]
+    BatchSpansPartitions {
+        first: uuid::Uuid,
+        other: uuid::Uuid,
+    },
+    #[snafu(display("unknown graph change kind: {}", kind))]
+    UnknownChangeKind { kind: i32 },
+    #[snafu(display("unknown traversal direction: {}", direction))]
+    UnknownDirection { direction: i32 },
+    #[snafu(display(
+        "{} could not be asked for the part of the graph it holds: {}",
+        peer,
+        message
+    ))]
+    PartitionUnreachable { peer: String, message: String },
 }
 
 #[derive(Debug, Snafu)]
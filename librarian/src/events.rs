@@ -0,0 +1,192 @@
+use std::collections::VecDeque;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tokio::sync::broadcast;
+
+use crate::librarian::Change;
+
+/// how many events are kept around for watchers that resume, and how far a live watcher may
+/// fall behind before it's cut off
+const RETAINED_EVENTS: usize = 4096;
+
+/// a change along with its sequence number
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct Event {
+    pub(crate) sequence: u64,
+    pub(crate) change: Change,
+    /// whether this is the last event of the write the change was part of
+    pub(crate) ends_write: bool,
+}
+
+/// The changes the graph went through, numbered in the order they were applied. Only the
+/// changes a watcher cares about are published, adding a node on its own isn't one of them.
+/// Sequence numbers are only kept in memory, so every librarian process numbers its events in an
+/// epoch of its own and a watcher can only resume within the epoch it started in
+pub(crate) struct Events {
+    /// when this process started publishing, in nanoseconds since the unix epoch
+    epoch: u64,
+    /// the sequence number of the last published event, sequence numbers start at 1
+    last: u64,
+    history: VecDeque<Event>,
+    sender: broadcast::Sender<Event>,
+}
+
+/// Why a watch can't resume from the sequence number it asked for
+#[derive(Debug, Eq, PartialEq)]
+pub(crate) enum Gap {
+    /// the events after it are no longer retained
+    Expired { oldest: u64 },
+    /// it was never published
+    Unknown { last: u64 },
+    /// it was published in another epoch, the librarian restarted since
+    Restarted { epoch: u64 },
+}
+
+impl Events {
+    pub(crate) fn new() -> Self {
+        let (sender, _) = broadcast::channel(RETAINED_EVENTS);
+        let epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(1, |since| since.as_nanos() as u64);
+        Self {
+            epoch,
+            last: 0,
+            history: VecDeque::with_capacity(RETAINED_EVENTS),
+            sender,
+        }
+    }
+
+    pub(crate) fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Publishes the changes of a single write, callers only pass the changes that actually
+    /// changed the graph
+    pub(crate) fn publish(&mut self, changes: &[Change]) {
+        let published: Vec<Change> = changes
+            .iter()
+            .copied()
+            .filter(|change| !matches!(change, Change::AddNode(_)))
+            .collect();
+        for (i, &change) in published.iter().enumerate() {
+            self.last += 1;
+            let event = Event {
+                sequence: self.last,
+                change,
+                ends_write: i + 1 == published.len(),
+            };
+            if self.history.len() == RETAINED_EVENTS {
+                self.history.pop_front();
+            }
+            self.history.push_back(event);
+            // nobody is watching
+            let _ = self.sender.send(event);
+        }
+    }
+
+    /// The retained events after `after` of `epoch` along with a receiver for the ones published
+    /// from now on, an `after` of 0 only subscribes to new events of any epoch
+    pub(crate) fn subscribe(
+        &self,
+        epoch: u64,
+        after: u64,
+    ) -> Result<(Vec<Event>, broadcast::Receiver<Event>), Gap> {
+        if after != 0 && epoch != self.epoch {
+            return Err(Gap::Restarted { epoch: self.epoch });
+        }
+        if after > self.last {
+            return Err(Gap::Unknown { last: self.last });
+        }
+        let oldest = self
+            .history
+            .front()
+            .map_or(self.last + 1, |event| event.sequence);
+        if after != 0 && after + 1 < oldest {
+            return Err(Gap::Expired { oldest });
+        }
+
+        let backlog = if after == 0 {
+            Vec::new()
+        } else {
+            self.history
+                .iter()
+                .filter(|event| event.sequence > after)
+                .cloned()
+                .collect()
+        };
+        Ok((backlog, self.sender.subscribe()))
+    }
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn edge(parent: u128, child: u128) -> Change {
+        Change::AddEdge(Uuid::from_u128(parent), Uuid::from_u128(child))
+    }
+
+    fn event(sequence: u64, change: Change, ends_write: bool) -> Event {
+        Event {
+            sequence,
+            change,
+            ends_write,
+        }
+    }
+
+    #[test]
+    fn resumes_from_retained_events() {
+        let mut events = Events::new();
+        let epoch = events.epoch();
+        events.publish(&[Change::AddNode(Uuid::from_u128(1)), edge(1, 2), edge(1, 3)]);
+
+        let (backlog, _) = events.subscribe(epoch, 1).unwrap();
+        assert_eq!(backlog, vec![event(2, edge(1, 3), true)]);
+        let (backlog, mut receiver) = events.subscribe(0, 0).unwrap();
+        assert!(backlog.is_empty());
+        assert_eq!(
+            events.subscribe(epoch, 3).unwrap_err(),
+            Gap::Unknown { last: 2 }
+        );
+
+        events.publish(&[edge(1, 4)]);
+        assert_eq!(receiver.try_recv().unwrap(), event(3, edge(1, 4), true));
+
+        for child in 0..RETAINED_EVENTS as u128 {
+            events.publish(&[edge(2, child)]);
+        }
+        assert_eq!(
+            events.subscribe(epoch, 2).unwrap_err(),
+            Gap::Expired { oldest: 4 }
+        );
+        assert!(events.subscribe(epoch, 3).is_ok());
+    }
+
+    #[test]
+    fn rejects_resuming_from_another_epoch() {
+        let mut before = Events::new();
+        before.publish(&[edge(1, 2), edge(1, 3)]);
+        let mut restarted = Events {
+            epoch: before.epoch() + 1,
+            ..Events::new()
+        };
+        restarted.publish(&[edge(4, 5), edge(4, 6), edge(4, 7)]);
+
+        // the sequence number exists in the new epoch but means something else there
+        assert_eq!(
+            restarted.subscribe(before.epoch(), 1).unwrap_err(),
+            Gap::Restarted {
+                epoch: restarted.epoch()
+            }
+        );
+        assert!(restarted.subscribe(before.epoch(), 0).is_ok());
+    }
+}
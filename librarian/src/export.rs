@@ -0,0 +1,111 @@
+use petgraph::dot::{Config, Dot};
+use petgraph::graphmap::DiGraphMap as Graph;
+use petgraph::Direction;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::errors::*;
+use crate::lineage;
+
+/// The JSON representation of a graph, nodes without edges are kept so that a dump round trips
+#[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
+pub(crate) struct Dump {
+    nodes: Vec<Uuid>,
+    edges: Vec<(Uuid, Uuid)>,
+}
+
+impl Dump {
+    /// Dumps the whole graph, or `root` and its descendants along with the edges between them
+    pub(crate) fn new(graph: &Graph<Uuid, ()>, root: Option<Uuid>) -> Self {
+        let mut nodes: Vec<Uuid> = match root {
+            Some(root) => {
+                let mut nodes = lineage::reachable(graph, root, Direction::Outgoing, 0);
+                nodes.push(root);
+                nodes
+            }
+            None => graph.nodes().collect(),
+        };
+        nodes.sort();
+        let mut edges: Vec<(Uuid, Uuid)> = nodes
+            .iter()
+            .flat_map(|&node| graph.neighbors(node).map(move |child| (node, child)))
+            .collect();
+        edges.sort();
+        Self { nodes, edges }
+    }
+
+    pub(crate) fn from_json(json: &[u8]) -> Result<Self> {
+        Ok(serde_json::from_slice(json).context(InvalidGraphJson)?)
+    }
+
+    pub(crate) fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self).context(InvalidGraphJson)?)
+    }
+
+    /// Graphviz labels every node with its uuid
+    pub(crate) fn to_dot(&self) -> String {
+        let mut graph: Graph<Uuid, &str> = Graph::new();
+        for &node in &self.nodes {
+            graph.add_node(node);
+        }
+        for &(parent, child) in &self.edges {
+            graph.add_edge(parent, child, "");
+        }
+        format!("{}", Dot::with_config(&graph, &[Config::EdgeNoLabel]))
+    }
+
+    pub(crate) fn into_graph(self) -> Graph<Uuid, ()> {
+        let mut graph = Graph::new();
+        for node in self.nodes {
+            graph.add_node(node);
+        }
+        for (parent, child) in self.edges {
+            graph.add_edge(parent, child, ());
+        }
+        graph
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn uuid(n: u128) -> Uuid {
+        Uuid::from_u128(n)
+    }
+
+    fn graph() -> Graph<Uuid, ()> {
+        let mut graph = Graph::new();
+        graph.add_node(uuid(9));
+        for &(parent, child) in &[(1, 2), (2, 3), (4, 3)] {
+            graph.add_edge(uuid(parent), uuid(child), ());
+        }
+        graph
+    }
+
+    #[test]
+    fn json_round_trips() {
+        let dump = Dump::new(&graph(), None);
+        assert_eq!(dump.nodes.len(), 5);
+
+        let json = dump.to_json().unwrap();
+        let imported = Dump::from_json(json.as_bytes()).unwrap();
+        assert_eq!(imported, dump);
+        let imported = imported.into_graph();
+        assert_eq!(Dump::new(&imported, None), dump);
+
+        assert!(Dump::from_json(b"{\"nodes\": [\"not a uuid\"]}").is_err());
+    }
+
+    #[test]
+    fn subgraphs_only_keep_descendants() {
+        let dump = Dump::new(&graph(), Some(uuid(2)));
+        assert_eq!(dump.nodes, vec![uuid(2), uuid(3)]);
+        assert_eq!(dump.edges, vec![(uuid(2), uuid(3))]);
+
+        let dot = dump.to_dot();
+        assert!(dot.starts_with("digraph {"));
+        assert!(dot.contains(&format!("label = \"{}\"", uuid(3))));
+        assert!(dot.contains("0 -> 1"));
+    }
+}
@@ -1,8 +1,211 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+
 use crossbeam_utils::sync::ShardedLock;
+use parking_lot::Mutex;
 use petgraph::graphmap::DiGraphMap as Graph;
+use petgraph::Direction;
+use tonic::transport::Channel;
 use uuid::Uuid;
 
-#[derive(Default)]
+use crate::cluster::Cluster;
+use crate::errors::*;
+use crate::events::Events;
+use crate::locations::Locations;
+use crate::retention::Retention;
+use crate::storage::{Options, Storage};
+use neuromancer::librarian::replication_client::ReplicationClient;
+use neuromancer::write_lock;
+
+/// A handle to the librarian's state, clones are cheap and share the same state so that every
+/// service can be handed its own copy
+#[derive(Clone, Default)]
 pub(crate) struct Librarian {
-    pub(crate) graph: ShardedLock<Graph<Uuid, ()>>,
+    pub(crate) graph: Arc<ShardedLock<Graph<Uuid, ()>>>,
+    /// `None` keeps the graph in memory only
+    storage: Option<Arc<Mutex<Storage>>>,
+    pub(crate) cluster: Arc<ShardedLock<Cluster>>,
+    pub(crate) locations: Arc<ShardedLock<Locations>>,
+    pub(crate) retention: Arc<Mutex<Retention>>,
+    pub(crate) events: Arc<Mutex<Events>>,
+    /// serialises the writes this librarian coordinates, the changes of a write are computed
+    /// against the graph they are applied to even though the graph lock is released while they
+    /// replicate
+    pub(crate) writes: Arc<tokio::sync::Mutex<()>>,
+    /// a client for every peer that writes were replicated to, a channel multiplexes requests so
+    /// one per peer is enough
+    pub(crate) peers: Arc<Mutex<BTreeMap<String, ReplicationClient<Channel>>>>,
+}
+
+/// A single mutation of the graph, every write to the graph goes through `Librarian::apply`
+/// as a list of these so that it can be persisted before it's acknowledged
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Change {
+    AddNode(Uuid),
+    /// also removes every edge of the node
+    RemoveNode(Uuid),
+    AddEdge(Uuid, Uuid),
+    RemoveEdge(Uuid, Uuid),
+}
+
+impl Librarian {
+    /// Creates a librarian whose graph is persisted in `directory`, replaying whatever was
+    /// persisted there before
+    pub(crate) fn open(
+        directory: impl AsRef<Path>,
+        options: Options,
+        cluster: Cluster,
+    ) -> Result<Self> {
+        let (storage, graph) = Storage::open(directory, options)?;
+        Ok(Self {
+            graph: Arc::new(ShardedLock::new(graph)),
+            storage: Some(Arc::new(Mutex::new(storage))),
+            cluster: Arc::new(ShardedLock::new(cluster)),
+            locations: Arc::default(),
+            retention: Arc::default(),
+            events: Arc::default(),
+            writes: Arc::default(),
+            peers: Arc::default(),
+        })
+    }
+
+    /// An in-memory librarian that replicates its writes to the rest of `cluster`
+    #[cfg(test)]
+    pub(crate) fn in_cluster(cluster: Cluster) -> Self {
+        Self {
+            cluster: Arc::new(ShardedLock::new(cluster)),
+            ..Default::default()
+        }
+    }
+
+    /// Persists `changes`, applies them to `graph` and publishes them to watchers, callers have
+    /// to hold the write lock of `self.graph` so that the order of the log and of the events
+    /// matches the order the graph saw. The output locations of deleted nodes go with them
+    pub(crate) fn apply(&self, graph: &mut Graph<Uuid, ()>, changes: &[Change]) -> Result<()> {
+        self.persist_and_apply(graph, changes)?;
+        let mut locations = write_lock!(self.locations);
+        for change in changes {
+            if let Change::RemoveNode(node) = change {
+                locations.forget(*node);
+            }
+        }
+        Ok(())
+    }
+
+    fn persist_and_apply(&self, graph: &mut Graph<Uuid, ()>, changes: &[Change]) -> Result<()> {
+        if let Some(handle) = &self.storage {
+            let mut storage = handle.lock();
+            storage.append(changes)?;
+            let applied = apply_all(graph, changes);
+            self.events.lock().publish(&applied);
+            if storage.needs_compaction() {
+                storage.start_compaction()?;
+                // the snapshot is written from a copy so that the graph isn't held up by it
+                let snapshot = graph.clone();
+                let directory = storage.directory().to_path_buf();
+                let handle = handle.clone();
+                thread::spawn(move || {
+                    // the log that was set aside stays until a snapshot replaces it, the next
+                    // write that needs a compaction tries again
+                    if let Err(e) = Storage::write_snapshot(&directory, &snapshot) {
+                        eprintln!("could not compact the edge log: {}", e);
+                    }
+                    handle.lock().finish_compaction();
+                });
+            }
+        } else {
+            let applied = apply_all(graph, changes);
+            self.events.lock().publish(&applied);
+        }
+        Ok(())
+    }
+}
+
+/// Applies `changes` in order and returns the ones that changed `graph`, a write may repeat an
+/// edge that is already there or remove one that isn't
+fn apply_all(graph: &mut Graph<Uuid, ()>, changes: &[Change]) -> Vec<Change> {
+    changes
+        .iter()
+        .copied()
+        .filter(|change| change.apply(graph))
+        .collect()
+}
+
+/// The changes that undo each of `changes`, computed against the `graph` they will be applied to.
+/// Undoing the whole write takes the undo of every change in reverse order
+pub(crate) fn undo(graph: &Graph<Uuid, ()>, changes: &[Change]) -> Vec<Vec<Change>> {
+    changes.iter().map(|change| change.undo(graph)).collect()
+}
+
+impl Change {
+    /// The changes that take `graph` back to how it was after `self` was applied to it
+    pub(crate) fn undo(self, graph: &Graph<Uuid, ()>) -> Vec<Change> {
+        let mut undo = Vec::new();
+        match self {
+            Change::AddNode(node) if !graph.contains_node(node) => {
+                undo.push(Change::RemoveNode(node));
+            }
+            Change::AddEdge(parent, child) if !graph.contains_edge(parent, child) => {
+                undo.push(Change::RemoveEdge(parent, child));
+                // adding an edge adds the nodes it connects as well
+                for &node in &[child, parent] {
+                    if !graph.contains_node(node) {
+                        undo.push(Change::RemoveNode(node));
+                    }
+                }
+            }
+            Change::RemoveEdge(parent, child) if graph.contains_edge(parent, child) => {
+                undo.push(Change::AddEdge(parent, child));
+            }
+            Change::RemoveNode(node) if graph.contains_node(node) => {
+                undo.push(Change::AddNode(node));
+                let parents = graph
+                    .neighbors_directed(node, Direction::Incoming)
+                    .map(|parent| Change::AddEdge(parent, node));
+                let children = graph
+                    .neighbors_directed(node, Direction::Outgoing)
+                    .map(|child| Change::AddEdge(node, child));
+                undo.extend(parents.chain(children));
+            }
+            _ => {}
+        }
+        undo
+    }
+
+    /// The identifier whose replicas have to see the change, an edge belongs to its parent
+    pub(crate) fn key(self) -> Uuid {
+        match self {
+            Change::AddNode(node) | Change::RemoveNode(node) => node,
+            Change::AddEdge(parent, _) | Change::RemoveEdge(parent, _) => parent,
+        }
+    }
+
+    /// Applies the change to `graph` and returns whether it changed anything
+    pub(crate) fn apply(self, graph: &mut Graph<Uuid, ()>) -> bool {
+        match self {
+            Change::AddNode(node) => {
+                let added = !graph.contains_node(node);
+                graph.add_node(node);
+                added
+            }
+            Change::RemoveNode(node) => {
+                if !graph.contains_node(node) {
+                    return false;
+                }
+                // petgraph 0.5 leaves the incoming edges of a removed node in the edge list of a
+                // directed graphmap, so they have to go first
+                let parents: Vec<Uuid> = graph
+                    .neighbors_directed(node, Direction::Incoming)
+                    .collect();
+                for parent in parents {
+                    graph.remove_edge(parent, node);
+                }
+                graph.remove_node(node)
+            }
+            Change::AddEdge(parent, child) => graph.add_edge(parent, child, ()).is_none(),
+            Change::RemoveEdge(parent, child) => graph.remove_edge(parent, child).is_some(),
+        }
+    }
 }
@@ -0,0 +1,358 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+use petgraph::graphmap::DiGraphMap as Graph;
+use petgraph::Direction;
+use uuid::Uuid;
+
+/// Every node reachable from `root` by following edges in `direction`, nearest first. A
+/// `max_depth` of 0 doesn't limit the traversal, `root` itself isn't part of the result
+pub(crate) fn reachable(
+    graph: &Graph<Uuid, ()>,
+    root: Uuid,
+    direction: Direction,
+    max_depth: u32,
+) -> Vec<Uuid> {
+    let mut seen = BTreeSet::new();
+    seen.insert(root);
+    let mut result = Vec::new();
+    let mut queue = VecDeque::new();
+    queue.push_back((root, 0));
+    while let Some((node, depth)) = queue.pop_front() {
+        if max_depth != 0 && depth == max_depth {
+            continue;
+        }
+        // sorted so that nodes at the same depth always come back in the same order
+        let mut next: Vec<Uuid> = graph.neighbors_directed(node, direction).collect();
+        next.sort();
+        for neighbor in next {
+            if seen.insert(neighbor) {
+                result.push(neighbor);
+                queue.push_back((neighbor, depth + 1));
+            }
+        }
+    }
+    result
+}
+
+/// `root` and its descendants ordered so that every node comes after all of its parents that
+/// descend from `root`, ties are broken by uuid. `None` when the descendants contain a cycle
+pub(crate) fn topological_order(graph: &Graph<Uuid, ()>, root: Uuid) -> Option<Vec<Uuid>> {
+    let mut nodes = reachable(graph, root, Direction::Outgoing, 0);
+    nodes.push(root);
+    let members: BTreeSet<Uuid> = nodes.into_iter().collect();
+
+    // the number of parents within the run graph that haven't been emitted yet
+    let mut pending: BTreeMap<Uuid, usize> = members
+        .iter()
+        .map(|&node| {
+            let parents = graph
+                .neighbors_directed(node, Direction::Incoming)
+                .filter(|parent| members.contains(parent))
+                .count();
+            (node, parents)
+        })
+        .collect();
+    let mut ready: BTreeSet<Uuid> = pending
+        .iter()
+        .filter(|(_, &parents)| parents == 0)
+        .map(|(&node, _)| node)
+        .collect();
+
+    let mut result = Vec::with_capacity(members.len());
+    while let Some(&node) = ready.iter().next() {
+        ready.remove(&node);
+        result.push(node);
+        for child in graph.neighbors_directed(node, Direction::Outgoing) {
+            if let Some(parents) = pending.get_mut(&child) {
+                *parents -= 1;
+                if *parents == 0 {
+                    ready.insert(child);
+                }
+            }
+        }
+    }
+
+    if result.len() == members.len() {
+        Some(result)
+    } else {
+        None
+    }
+}
+
+/// The shortest path from `from` to `to`, both included, that doesn't use the edges `skip`
+/// returns true for
+pub(crate) fn path(
+    graph: &Graph<Uuid, ()>,
+    from: Uuid,
+    to: Uuid,
+    skip: impl Fn(Uuid, Uuid) -> bool,
+) -> Option<Vec<Uuid>> {
+    shortest_path(from, to, |node| {
+        graph
+            .neighbors_directed(node, Direction::Outgoing)
+            .filter(|&next| !skip(node, next))
+            .collect()
+    })
+}
+
+/// A cycle in the union of `graph` and the edges of `pending`, found with a single depth first
+/// search from the nodes of `pending`. `graph` has to be acyclic, so the cycle runs through an
+/// edge that only `pending` has: it's returned as that edge's parent and child along with the
+/// path from the child back to the parent
+pub(crate) fn cycle_with(
+    graph: &Graph<Uuid, ()>,
+    pending: &Graph<Uuid, ()>,
+) -> Option<(Uuid, Uuid, Vec<Uuid>)> {
+    let neighbors = |node: Uuid| -> Vec<Uuid> {
+        graph
+            .neighbors_directed(node, Direction::Outgoing)
+            .chain(pending.neighbors_directed(node, Direction::Outgoing))
+            .collect()
+    };
+    let mut finished = BTreeSet::new();
+    for start in pending.nodes() {
+        if finished.contains(&start) {
+            continue;
+        }
+        let mut path = vec![start];
+        let mut on_path: BTreeSet<Uuid> = path.iter().cloned().collect();
+        let mut unvisited = vec![neighbors(start).into_iter()];
+        while let Some(children) = unvisited.last_mut() {
+            match children.next() {
+                Some(child) if on_path.contains(&child) => {
+                    let from = path.iter().position(|&node| node == child).unwrap_or(0);
+                    return Some(through_pending(graph, pending, &path[from..]));
+                }
+                Some(child) if !finished.contains(&child) => {
+                    path.push(child);
+                    on_path.insert(child);
+                    unvisited.push(neighbors(child).into_iter());
+                }
+                Some(_) => {}
+                None => {
+                    unvisited.pop();
+                    if let Some(node) = path.pop() {
+                        on_path.remove(&node);
+                        finished.insert(node);
+                    }
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Turns the cycle through `ring`, whose last node leads back to its first, into the edge of it
+/// that only `pending` has and the path from that edge's child back to its parent
+fn through_pending(
+    graph: &Graph<Uuid, ()>,
+    pending: &Graph<Uuid, ()>,
+    ring: &[Uuid],
+) -> (Uuid, Uuid, Vec<Uuid>) {
+    let len = ring.len();
+    let edge = |i: usize| (ring[i], ring[(i + 1) % len]);
+    let start = (0..len)
+        .find(|&i| {
+            let (parent, child) = edge(i);
+            pending.contains_edge(parent, child) && !graph.contains_edge(parent, child)
+        })
+        .unwrap_or(0);
+    let (parent, child) = edge(start);
+    let path = (1..=len).map(|j| ring[(start + j) % len]).collect();
+    (parent, child, path)
+}
+
+fn shortest_path(from: Uuid, to: Uuid, neighbors: impl Fn(Uuid) -> Vec<Uuid>) -> Option<Vec<Uuid>> {
+    let mut predecessors = BTreeMap::new();
+    let mut queue = VecDeque::new();
+    queue.push_back(from);
+    while let Some(node) = queue.pop_front() {
+        if node == to {
+            let mut result = vec![to];
+            let mut node = to;
+            while node != from {
+                node = predecessors[&node];
+                result.push(node);
+            }
+            result.reverse();
+            return Some(result);
+        }
+        for next in neighbors(node) {
+            if next != from && !predecessors.contains_key(&next) {
+                predecessors.insert(next, node);
+                queue.push_back(next);
+            }
+        }
+    }
+    None
+}
+
+/// The nodes of `nodes` that `graph` contains along with their edges in `direction`, as parent
+/// and child pairs
+pub(crate) fn adjacent(
+    graph: &Graph<Uuid, ()>,
+    nodes: &[Uuid],
+    direction: Direction,
+) -> (Vec<Uuid>, Vec<(Uuid, Uuid)>) {
+    let known: Vec<Uuid> = nodes
+        .iter()
+        .copied()
+        .filter(|&node| graph.contains_node(node))
+        .collect();
+    let edges = known
+        .iter()
+        .flat_map(|&node| {
+            graph
+                .neighbors_directed(node, direction)
+                .map(move |neighbor| match direction {
+                    Direction::Outgoing => (node, neighbor),
+                    Direction::Incoming => (neighbor, node),
+                })
+        })
+        .collect();
+    (known, edges)
+}
+
+/// Adds the nodes and edges of `other` to `graph`
+pub(crate) fn merge(graph: &mut Graph<Uuid, ()>, other: &Graph<Uuid, ()>) {
+    for node in other.nodes() {
+        graph.add_node(node);
+    }
+    for (parent, child, _) in other.all_edges() {
+        graph.add_edge(parent, child, ());
+    }
+}
+
+/// The nodes that only `job` references: the job and its descendants, minus whatever a node
+/// outside of the job points at along with everything below it
+pub(crate) fn collectable(graph: &Graph<Uuid, ()>, job: Uuid) -> Vec<Uuid> {
+    let mut scope: BTreeSet<Uuid> = reachable(graph, job, Direction::Outgoing, 0)
+        .into_iter()
+        .collect();
+    scope.insert(job);
+
+    let mut shared: Vec<Uuid> = scope
+        .iter()
+        .filter(|&&node| {
+            graph
+                .neighbors_directed(node, Direction::Incoming)
+                .any(|parent| !scope.contains(&parent))
+        })
+        .cloned()
+        .collect();
+    let mut kept = BTreeSet::new();
+    while let Some(node) = shared.pop() {
+        if kept.insert(node) {
+            shared.extend(graph.neighbors_directed(node, Direction::Outgoing));
+        }
+    }
+    scope.difference(&kept).cloned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn uuid(n: u128) -> Uuid {
+        Uuid::from_u128(n)
+    }
+
+    /// 1 -> 2 -> 4 -> 5, 1 -> 3 -> 4, 6 -> 3
+    fn diamond() -> Graph<Uuid, ()> {
+        let mut graph = Graph::new();
+        for &(parent, child) in &[(1, 2), (1, 3), (2, 4), (3, 4), (4, 5), (6, 3)] {
+            graph.add_edge(uuid(parent), uuid(child), ());
+        }
+        graph
+    }
+
+    #[test]
+    fn reachable_respects_direction_and_depth() {
+        let graph = diamond();
+
+        let descendants = reachable(&graph, uuid(1), Direction::Outgoing, 0);
+        assert_eq!(descendants, vec![uuid(2), uuid(3), uuid(4), uuid(5)]);
+        let descendants = reachable(&graph, uuid(1), Direction::Outgoing, 2);
+        assert_eq!(descendants, vec![uuid(2), uuid(3), uuid(4)]);
+
+        let ancestors = reachable(&graph, uuid(4), Direction::Incoming, 0);
+        assert_eq!(ancestors, vec![uuid(2), uuid(3), uuid(1), uuid(6)]);
+        let ancestors = reachable(&graph, uuid(4), Direction::Incoming, 1);
+        assert_eq!(ancestors, vec![uuid(2), uuid(3)]);
+
+        assert!(reachable(&graph, uuid(7), Direction::Outgoing, 0).is_empty());
+    }
+
+    #[test]
+    fn topological_order_puts_parents_first() {
+        let mut graph = diamond();
+
+        let order = topological_order(&graph, uuid(1)).unwrap();
+        assert_eq!(order, vec![uuid(1), uuid(2), uuid(3), uuid(4), uuid(5)]);
+
+        graph.add_edge(uuid(5), uuid(2), ());
+        assert_eq!(topological_order(&graph, uuid(1)), None);
+    }
+
+    #[test]
+    fn collectable_keeps_what_other_jobs_reference() {
+        let graph = diamond();
+
+        // 6 -> 3 keeps 3 and everything below it
+        assert_eq!(collectable(&graph, uuid(1)), vec![uuid(1), uuid(2)]);
+        assert_eq!(collectable(&graph, uuid(6)), vec![uuid(6)]);
+        // 4 is referenced by 2 and 3 so nothing of it can go
+        assert!(collectable(&graph, uuid(4)).is_empty());
+    }
+
+    #[test]
+    fn path_is_the_shortest_one_left_after_skipping() {
+        let graph = diamond();
+
+        assert_eq!(
+            path(&graph, uuid(1), uuid(5), |_, _| false),
+            Some(vec![uuid(1), uuid(2), uuid(4), uuid(5)])
+        );
+        assert_eq!(
+            path(&graph, uuid(1), uuid(4), |_, child| child == uuid(2)),
+            Some(vec![uuid(1), uuid(3), uuid(4)])
+        );
+        assert_eq!(
+            path(&graph, uuid(1), uuid(1), |_, _| false),
+            Some(vec![uuid(1)])
+        );
+        assert_eq!(path(&graph, uuid(5), uuid(1), |_, _| false), None);
+    }
+
+    #[test]
+    fn adjacent_only_covers_known_nodes() {
+        let graph = diamond();
+
+        let (known, edges) = adjacent(&graph, &[uuid(3), uuid(7)], Direction::Incoming);
+        assert_eq!(known, vec![uuid(3)]);
+        assert_eq!(edges, vec![(uuid(1), uuid(3)), (uuid(6), uuid(3))]);
+        let (_, edges) = adjacent(&graph, &[uuid(3)], Direction::Outgoing);
+        assert_eq!(edges, vec![(uuid(3), uuid(4))]);
+    }
+
+    #[test]
+    fn cycle_with_runs_through_a_pending_edge() {
+        let graph = diamond();
+        let mut pending = Graph::new();
+        pending.add_edge(uuid(5), uuid(7), ());
+        assert_eq!(cycle_with(&graph, &pending), None);
+
+        // 5 -> 6 closes 6 -> 3 -> 4 -> 5 -> 6, 5 -> 7 has nothing to do with it
+        pending.add_edge(uuid(5), uuid(6), ());
+        let (parent, child, path) = cycle_with(&graph, &pending).unwrap();
+        assert_eq!((parent, child), (uuid(5), uuid(6)));
+        assert_eq!(path, vec![uuid(6), uuid(3), uuid(4), uuid(5)]);
+
+        let mut looped = Graph::new();
+        looped.add_edge(uuid(8), uuid(8), ());
+        assert_eq!(
+            cycle_with(&graph, &looped),
+            Some((uuid(8), uuid(8), vec![uuid(8)]))
+        );
+    }
+}
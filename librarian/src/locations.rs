@@ -0,0 +1,68 @@
+use std::collections::BTreeMap;
+
+use uuid::Uuid;
+
+use neuromancer::librarian::OutputLocation;
+
+/// Which executors hold the output of every run. Unlike the graph the index is only kept in
+/// memory and only by the owner of a run, which the other replicas redirect to. Executors
+/// register their outputs again when a librarian comes back
+#[derive(Default)]
+pub(crate) struct Locations {
+    outputs: BTreeMap<Uuid, Vec<OutputLocation>>,
+}
+
+impl Locations {
+    /// Records `location` for `run`, replacing what the same executor registered before
+    pub(crate) fn register(&mut self, run: Uuid, location: OutputLocation) {
+        let locations = self.outputs.entry(run).or_default();
+        match locations
+            .iter_mut()
+            .find(|known| known.executor == location.executor)
+        {
+            Some(known) => *known = location,
+            None => locations.push(location),
+        }
+    }
+
+    /// The executors holding the output of `run` in the order they registered it
+    pub(crate) fn locate(&self, run: Uuid) -> Option<&[OutputLocation]> {
+        self.outputs.get(&run).map(Vec::as_slice)
+    }
+
+    /// Drops every location of `run` and returns how many there were
+    pub(crate) fn forget(&mut self, run: Uuid) -> usize {
+        self.outputs
+            .remove(&run)
+            .map_or(0, |locations| locations.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn location(executor: &str, size: u64) -> OutputLocation {
+        OutputLocation {
+            executor: executor.to_string(),
+            size,
+            output_checksum: vec![1, 2, 3],
+        }
+    }
+
+    #[test]
+    fn registering_again_replaces_the_executors_entry() {
+        let mut locations = Locations::default();
+        let run = Uuid::from_u128(1);
+        assert!(locations.locate(run).is_none());
+
+        locations.register(run, location("foo", 10));
+        locations.register(run, location("bar", 20));
+        locations.register(run, location("foo", 30));
+
+        let expected = [location("foo", 30), location("bar", 20)];
+        assert_eq!(locations.locate(run), Some(&expected[..]));
+        assert_eq!(locations.forget(run), 2);
+        assert!(locations.locate(run).is_none());
+    }
+}
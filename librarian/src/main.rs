@@ -1,34 +1,91 @@
+mod cluster;
 mod errors;
+mod events;
+mod export;
 mod librarian;
+mod lineage;
+mod locations;
+mod retention;
 mod services;
+mod storage;
 
+use std::env;
+use std::time::Duration;
+
+use cluster::Cluster;
 use errors::*;
 use librarian::Librarian;
-use neuromancer::librarian::job_server::*;
+use neuromancer::librarian::{dump_server::*, job_server::*, replication_server::*};
+use neuromancer::socket;
+use storage::Options;
 
-pub struct Server {
-    router: tonic::transport::server::Router<
-        JobServer<Librarian>,
-        tonic::transport::server::Unimplemented,
-    >,
+pub(crate) struct Server {
     addr: String,
+    advertised_addr: String,
+    storage_directory: String,
 }
 
 impl Server {
     const LIBRARIAN_SERVER_ADDRESS: &'static str = "[::1]:1337";
-    pub fn new() -> Self {
-        let librarian = Librarian::default();
-        let router = tonic::transport::Server::builder().add_service(JobServer::new(librarian));
-        let addr = Self::LIBRARIAN_SERVER_ADDRESS.to_string();
-        Self { addr, router }
+    /// overrides `LIBRARIAN_SERVER_ADDRESS`, a `unix://` address serves over a Unix domain socket
+    const ADDRESS_VARIABLE: &'static str = "LIBRARIAN_ADDRESS";
+    const LIBRARIAN_STORAGE_DIRECTORY: &'static str = "./librarian-data";
+    /// how this librarian's peers and the executors address it, has to match the address the
+    /// supervisor hands out in membership lists
+    const LIBRARIAN_ADVERTISED_ADDRESS: &'static str = "http://[::1]:1337";
+    /// overrides `LIBRARIAN_ADVERTISED_ADDRESS`, which is otherwise the listening address when
+    /// that is a Unix domain socket
+    const ADVERTISED_ADDRESS_VARIABLE: &'static str = "LIBRARIAN_ADVERTISED_ADDRESS";
+    /// the number of librarians that hold a copy of every identifier
+    const REPLICATION_FACTOR: usize = 3;
+    /// how often the lineage of expired jobs is collected
+    const GARBAGE_COLLECTION_INTERVAL: Duration = Duration::from_secs(60);
+    pub(crate) fn new() -> Self {
+        let addr = env::var(Self::ADDRESS_VARIABLE)
+            .unwrap_or_else(|_| Self::LIBRARIAN_SERVER_ADDRESS.to_string());
+        let advertised_addr = env::var(Self::ADVERTISED_ADDRESS_VARIABLE).unwrap_or_else(|_| {
+            if addr.starts_with(socket::UNIX_SCHEME) {
+                addr.clone()
+            } else {
+                Self::LIBRARIAN_ADVERTISED_ADDRESS.to_string()
+            }
+        });
+        let storage_directory = Self::LIBRARIAN_STORAGE_DIRECTORY.to_string();
+        Self {
+            addr,
+            advertised_addr,
+            storage_directory,
+        }
     }
 
-    pub async fn build(self) -> Result<()> {
-        self.router
-            .serve(
-                self.addr
-                    .parse()
-                    .context(InvalidLibrarianAddressSpecified)?,
+    /// Replays the persisted graph and serves until the process is killed
+    pub(crate) async fn build(self) -> Result<()> {
+        neuromancer::checksum::configure_from_env().context(NeuromancerError)?;
+        let cluster = Cluster::new(self.advertised_addr.as_str(), Self::REPLICATION_FACTOR);
+        let librarian = Librarian::open(&self.storage_directory, Options::from_env()?, cluster)?;
+        let collector = librarian.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::delay_for(Self::GARBAGE_COLLECTION_INTERVAL).await;
+                // expired jobs stay retained until they are collected, so a failed
+                // collection is retried on the next tick
+                if let Err(e) = collector.collect_expired().await {
+                    eprintln!("could not collect expired jobs: {}", e.message());
+                }
+            }
+        });
+        tonic::transport::Server::builder()
+            .add_service(JobServer::new(librarian.clone()))
+            .add_service(ReplicationServer::new(librarian.clone()))
+            .add_service(DumpServer::new(librarian))
+            .serve_with_incoming(
+                socket::incoming(
+                    self.addr
+                        .parse::<socket::Address>()
+                        .context(InvalidLibrarianAddressSpecified)?,
+                    socket::Options::default(),
+                )
+                .context(NeuromancerError)?,
             )
             .await
             .context(GRPCTransportError)?;
@@ -0,0 +1,72 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use uuid::Uuid;
+
+/// The jobs that are complete and when their lineage may be collected. Like the location index
+/// this is only kept in memory by the owner of a job, the supervisor marks its jobs as complete
+/// again after a restart
+#[derive(Default)]
+pub(crate) struct Retention {
+    expiries: BTreeMap<Uuid, Instant>,
+}
+
+impl Retention {
+    /// Marks `job` as complete, completing a job again restarts its ttl
+    pub(crate) fn complete(&mut self, job: Uuid, ttl: Duration, now: Instant) {
+        self.expiries.insert(job, now + ttl);
+    }
+
+    /// Returns the jobs whose ttl has expired at `now`. They stay retained until `collected`
+    /// confirms that their lineage is gone, so a failed collection is retried
+    pub(crate) fn expired(&self, now: Instant) -> Vec<Uuid> {
+        self.expiries
+            .iter()
+            .filter(|(_, &expiry)| expiry <= now)
+            .map(|(&job, _)| job)
+            .collect()
+    }
+
+    /// Forgets the `jobs` whose lineage was collected as expired at `now`. A job completed
+    /// again since then has a later expiry and is kept
+    pub(crate) fn collected(&mut self, jobs: &[Uuid], now: Instant) {
+        for job in jobs {
+            if matches!(self.expiries.get(job), Some(&expiry) if expiry <= now) {
+                self.expiries.remove(job);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expired_jobs_are_returned_until_collected() {
+        let mut retention = Retention::default();
+        let now = Instant::now();
+        retention.complete(Uuid::from_u128(1), Duration::from_secs(0), now);
+        retention.complete(Uuid::from_u128(2), Duration::from_secs(60), now);
+
+        assert_eq!(retention.expired(now), vec![Uuid::from_u128(1)]);
+        assert_eq!(retention.expired(now), vec![Uuid::from_u128(1)]);
+        retention.collected(&[Uuid::from_u128(1)], now);
+        assert!(retention.expired(now).is_empty());
+        let later = now + Duration::from_secs(60);
+        assert_eq!(retention.expired(later), vec![Uuid::from_u128(2)]);
+    }
+
+    #[test]
+    fn jobs_completed_again_are_not_forgotten() {
+        let mut retention = Retention::default();
+        let now = Instant::now();
+        let job = Uuid::from_u128(1);
+        retention.complete(job, Duration::from_secs(0), now);
+        let expired = retention.expired(now);
+        retention.complete(job, Duration::from_secs(60), now);
+
+        retention.collected(&expired, now);
+        assert_eq!(retention.expired(now + Duration::from_secs(60)), vec![job]);
+    }
+}
@@ -1 +1,64 @@
+mod dump;
 mod job;
+mod replication;
+mod watch;
+
+use tonic::Status;
+use uuid::Uuid;
+
+use crate::errors::*;
+use crate::librarian::Librarian;
+use neuromancer::{base::Identifier, read_lock, router};
+
+/// INVALID_ARGUMENT for an identifier that is empty or isn't a uuid
+pub(crate) fn parse_identifier(identifier: &Identifier) -> Result<Uuid, Status> {
+    if identifier.uuid.is_empty() {
+        return Err(Status::invalid_argument(
+            Error::NoIdentifierProvided.to_string(),
+        ));
+    }
+
+    match Uuid::parse_str(&identifier.uuid) {
+        Ok(uuid) => Ok(uuid),
+        Err(source) => Err(Status::invalid_argument(
+            Error::UuidEncodingError { source }.to_string(),
+        )),
+    }
+}
+
+/// FAILED_PRECONDITION carrying the owner of `uuid` when the librarian holds no copy of it, the
+/// request was routed with a different membership list than the librarian's
+pub(crate) fn verify_owner(librarian: &Librarian, uuid: Uuid) -> Result<(), Status> {
+    let cluster = read_lock!(librarian.cluster);
+    match cluster.redirect(uuid) {
+        Some(owner) => Err(router::redirect(
+            Error::MisroutedIdentifier {
+                uuid,
+                owner: owner.clone(),
+            }
+            .to_string(),
+            owner,
+            cluster.epoch(),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// FAILED_PRECONDITION carrying the owner of `uuid` unless this librarian is the owner itself.
+/// Output locations and completed jobs are only kept in memory by the owner, a replica would
+/// answer as though there were none
+pub(crate) fn verify_primary(librarian: &Librarian, uuid: Uuid) -> Result<(), Status> {
+    let cluster = read_lock!(librarian.cluster);
+    match cluster.redirect_to_owner(uuid) {
+        Some(owner) => Err(router::redirect(
+            Error::NotTheOwner {
+                uuid,
+                owner: owner.clone(),
+            }
+            .to_string(),
+            owner,
+            cluster.epoch(),
+        )),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,332 @@
+use std::pin::Pin;
+
+use futures::{stream, Stream};
+use petgraph::algo::is_cyclic_directed;
+use petgraph::Direction;
+use tonic::{Request, Response, Status, Streaming};
+use uuid::Uuid;
+
+use crate::errors::*;
+use crate::export;
+use crate::librarian::{self, Change, Librarian};
+use crate::services::parse_identifier;
+use neuromancer::{librarian::dump_server::*, librarian::*, read_lock};
+
+/// the number of bytes of a dump sent per chunk, a chunk stays far below the grpc message limit
+const DUMP_CHUNK_LEN: usize = 1 << 20;
+
+/// the number of imported identifiers the other librarians are asked about at once
+const KNOWN_CHECK_LEN: usize = 1024;
+
+#[tonic::async_trait]
+impl Dump for Librarian {
+    type ExportStream =
+        Pin<Box<dyn Stream<Item = Result<GraphDump, Status>> + Send + Sync + 'static>>;
+
+    /// Only exports the part of the graph this librarian holds. NOT_FOUND when the root isn't
+    /// part of it
+    async fn export(
+        &self,
+        request: Request<ExportRequest>,
+    ) -> Result<Response<Self::ExportStream>, Status> {
+        let request = request.into_inner();
+        let root = match &request.root {
+            Some(root) if !root.uuid.is_empty() => Some(parse_identifier(root)?),
+            _ => None,
+        };
+
+        let dump = {
+            let graph = read_lock!(self.graph);
+            if let Some(root) = root {
+                if !graph.contains_node(root) {
+                    return Err(Status::not_found(
+                        Error::IdentifierNotFound { uuid: root }.to_string(),
+                    ));
+                }
+            }
+            export::Dump::new(&graph, root)
+        };
+        let contents = match export_request::Format::from_i32(request.format) {
+            Some(export_request::Format::Dot) => dump.to_dot(),
+            _ => match dump.to_json() {
+                Ok(json) => json,
+                Err(e) => return Err(Status::internal(e.to_string())),
+            },
+        };
+
+        let format = request.format;
+        let chunks: Vec<Result<GraphDump, Status>> = contents
+            .as_bytes()
+            .chunks(DUMP_CHUNK_LEN)
+            .map(|chunk| {
+                Ok(GraphDump {
+                    format,
+                    contents: chunk.to_vec(),
+                    ..Default::default()
+                })
+            })
+            .collect();
+        Ok(Response::new(
+            Box::pin(stream::iter(chunks)) as Self::ExportStream
+        ))
+    }
+
+    /// FAILED_PRECONDITION when the dump isn't acyclic, when this librarian's graph isn't empty
+    /// or when any other librarian already knows about one of the imported identifiers. The
+    /// other librarians may hold graphs of their own as long as they don't overlap the dump,
+    /// UNAVAILABLE when one of them can't be asked
+    async fn import(&self, request: Request<Streaming<GraphDump>>) -> Result<Response<()>, Status> {
+        let mut chunks = request.into_inner();
+        let mut contents = Vec::new();
+        while let Some(chunk) = chunks.message().await? {
+            if chunk.format != export_request::Format::Json as i32 {
+                return Err(Status::invalid_argument(
+                    Error::UnsupportedImportFormat.to_string(),
+                ));
+            }
+            contents.extend_from_slice(&chunk.contents);
+        }
+        let imported = match export::Dump::from_json(&contents) {
+            Ok(dump) => dump.into_graph(),
+            Err(e) => return Err(Status::invalid_argument(e.to_string())),
+        };
+        if is_cyclic_directed(&imported) {
+            return Err(Status::failed_precondition(Error::CyclicImport.to_string()));
+        }
+
+        let writes = self.writes.lock().await;
+        if read_lock!(self.graph).node_count() != 0 {
+            return Err(Status::failed_precondition(
+                Error::GraphNotEmpty.to_string(),
+            ));
+        }
+        let nodes: Vec<Uuid> = imported.nodes().collect();
+        for nodes in nodes.chunks(KNOWN_CHECK_LEN) {
+            let known = self
+                .neighborhood(nodes.to_vec(), Direction::Outgoing, 1)
+                .await?;
+            if let Some(uuid) = known.nodes().find(|&node| imported.contains_node(node)) {
+                return Err(Status::failed_precondition(
+                    Error::ImportedIdentifierKnown { uuid }.to_string(),
+                ));
+            }
+        }
+
+        let nodes = imported.nodes().map(Change::AddNode);
+        let edges = imported
+            .all_edges()
+            .map(|(parent, child, _)| Change::AddEdge(parent, child));
+        let changes: Vec<Change> = nodes.chain(edges).collect();
+        let undo = {
+            let graph = read_lock!(self.graph);
+            librarian::undo(&graph, &changes)
+        };
+        self.commit(&writes, &changes, undo).await?;
+        Ok(Response::new(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::future::FutureExt;
+    use tokio::sync::oneshot;
+    use uuid::Uuid;
+
+    use super::*;
+    use neuromancer::base::Identifier;
+    use neuromancer::librarian::dump_client::DumpClient;
+    use neuromancer::{write_lock, Checksummable};
+
+    const DEFAULT_STARTUP_TIMEOUT: u64 = 1;
+    const EXPORT_ADDRESS: &str = "[::1]:1326";
+    const IMPORT_ADDRESS: &str = "[::1]:1325";
+    const LARGE_EXPORT_ADDRESS: &str = "[::1]:1355";
+    const LARGE_IMPORT_ADDRESS: &str = "[::1]:1356";
+
+    async fn gen_server(
+        addr: &'static str,
+        librarian: Librarian,
+    ) -> (oneshot::Sender<()>, tokio::task::JoinHandle<()>) {
+        let (tx, rx) = oneshot::channel::<()>();
+        let server = tokio::spawn(async move {
+            tonic::transport::Server::builder()
+                .add_service(DumpServer::new(librarian))
+                .serve_with_shutdown(addr.parse().unwrap(), rx.map(drop))
+                .await
+                .unwrap();
+        });
+        tokio::time::delay_for(Duration::from_millis(DEFAULT_STARTUP_TIMEOUT)).await;
+        (tx, server)
+    }
+
+    fn export_request(format: export_request::Format, root: Option<u128>) -> ExportRequest {
+        let mut request = ExportRequest {
+            format: format as i32,
+            root: root.map(|root| Identifier {
+                uuid: Uuid::from_u128(root).to_string(),
+            }),
+            ..Default::default()
+        };
+        request.checksum = request.checksum().unwrap();
+        request
+    }
+
+    fn with_checksum(mut dump: GraphDump) -> GraphDump {
+        dump.checksum = dump.checksum().unwrap();
+        dump
+    }
+
+    /// the format and the whole of the exported dump
+    async fn export(
+        client: &mut DumpClient<tonic::transport::Channel>,
+        request: ExportRequest,
+    ) -> Result<(i32, Vec<u8>), Status> {
+        let mut chunks = client.export(Request::new(request)).await?.into_inner();
+        let (mut format, mut contents) = (0, Vec::new());
+        while let Some(chunk) = chunks.message().await? {
+            format = chunk.format;
+            contents.extend_from_slice(&chunk.contents);
+        }
+        Ok((format, contents))
+    }
+
+    fn json_dump(contents: &[u8]) -> Vec<GraphDump> {
+        contents
+            .chunks(DUMP_CHUNK_LEN)
+            .map(|chunk| {
+                with_checksum(GraphDump {
+                    format: export_request::Format::Json as i32,
+                    contents: chunk.to_vec(),
+                    ..Default::default()
+                })
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn exports_subgraphs_as_dot_and_json() {
+        let librarian = Librarian::default();
+        {
+            let mut graph = write_lock!(librarian.graph);
+            for &(parent, child) in &[(1, 2), (2, 3), (4, 5)] {
+                graph.add_edge(Uuid::from_u128(parent), Uuid::from_u128(child), ());
+            }
+        }
+        let (tx, server) = gen_server(EXPORT_ADDRESS, librarian).await;
+        let mut client = DumpClient::connect(format!("http://{}", EXPORT_ADDRESS))
+            .await
+            .unwrap();
+
+        let (_, json) = export(
+            &mut client,
+            export_request(export_request::Format::Json, Some(2)),
+        )
+        .await
+        .unwrap();
+        let dump = export::Dump::from_json(&json).unwrap();
+        assert_eq!(dump.into_graph().node_count(), 2);
+
+        let (format, dot) = export(
+            &mut client,
+            export_request(export_request::Format::Dot, None),
+        )
+        .await
+        .unwrap();
+        assert_eq!(format, export_request::Format::Dot as i32);
+        assert_eq!(String::from_utf8(dot).unwrap().matches("->").count(), 3);
+
+        let err = export(
+            &mut client,
+            export_request(export_request::Format::Json, Some(6)),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::NotFound);
+
+        tx.send(()).unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn imports_json_into_empty_librarians() {
+        let librarian = Librarian::default();
+        let (tx, server) = gen_server(IMPORT_ADDRESS, librarian.clone()).await;
+        let mut client = DumpClient::connect(format!("http://{}", IMPORT_ADDRESS))
+            .await
+            .unwrap();
+
+        let (first, second) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let cyclic = format!(
+            "{{\"nodes\": [\"{0}\", \"{1}\"], \"edges\": [[\"{0}\", \"{1}\"], [\"{1}\", \"{0}\"]]}}",
+            first, second
+        );
+        let dump = |contents: &str| stream::iter(json_dump(contents.as_bytes()));
+        let err = client
+            .import(Request::new(dump(&cyclic)))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::FailedPrecondition);
+
+        let acyclic = format!(
+            "{{\"nodes\": [\"{0}\", \"{1}\"], \"edges\": [[\"{0}\", \"{1}\"]]}}",
+            first, second
+        );
+        client.import(Request::new(dump(&acyclic))).await.unwrap();
+        assert!(read_lock!(librarian.graph).contains_edge(first, second));
+
+        let err = client
+            .import(Request::new(dump(&acyclic)))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::FailedPrecondition);
+        let err = client
+            .import(Request::new(dump("not json")))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+
+        tx.send(()).unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn dumps_larger_than_a_message_round_trip() {
+        let exporter = Librarian::default();
+        {
+            let mut graph = write_lock!(exporter.graph);
+            for child in 1..100_000 {
+                graph.add_edge(Uuid::from_u128(0), Uuid::from_u128(child), ());
+            }
+        }
+        let (export_tx, export_server) = gen_server(LARGE_EXPORT_ADDRESS, exporter).await;
+        let importer = Librarian::default();
+        let (import_tx, import_server) = gen_server(LARGE_IMPORT_ADDRESS, importer.clone()).await;
+        let mut exporting = DumpClient::connect(format!("http://{}", LARGE_EXPORT_ADDRESS))
+            .await
+            .unwrap();
+        let mut importing = DumpClient::connect(format!("http://{}", LARGE_IMPORT_ADDRESS))
+            .await
+            .unwrap();
+
+        let (_, json) = export(
+            &mut exporting,
+            export_request(export_request::Format::Json, None),
+        )
+        .await
+        .unwrap();
+        // well past the 4MiB grpc message limit
+        assert!(json.len() > 4 * 1024 * 1024);
+        importing
+            .import(Request::new(stream::iter(json_dump(&json))))
+            .await
+            .unwrap();
+        assert_eq!(read_lock!(importer.graph).edge_count(), 99_999);
+
+        export_tx.send(()).unwrap();
+        import_tx.send(()).unwrap();
+        export_server.await.unwrap();
+        import_server.await.unwrap();
+    }
+}
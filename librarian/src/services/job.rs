@@ -1,32 +1,41 @@
-use tonic::{Request, Response, Status};
+use std::collections::BTreeSet;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::{stream, Stream, StreamExt};
+
+use petgraph::graphmap::DiGraphMap as Graph;
+use petgraph::Direction;
+use tonic::{Code, Request, Response, Status, Streaming};
 use uuid::Uuid;
 
 use crate::errors::*;
-use crate::librarian::Librarian;
-use neuromancer::{base::*, librarian::job_server::*, librarian::*, read_lock, Checksummable};
+use crate::librarian::{self, Change, Librarian};
+use crate::lineage;
+use crate::services::{parse_identifier, verify_owner, verify_primary, watch};
+use neuromancer::{
+    base::*, librarian::job_server::*, librarian::*, read_lock, status_with_details, write_lock,
+};
+
+/// the number of children sent per chunk by `StreamIdentifiers`, a chunk stays far below the
+/// grpc message limit
+const IDENTIFIER_CHUNK_LEN: usize = 1024;
 
 #[tonic::async_trait]
 impl Job for Librarian {
+    type StreamIdentifiersStream =
+        Pin<Box<dyn Stream<Item = Result<IdentifierChunk, Status>> + Send + Sync + 'static>>;
+    type WatchStream =
+        Pin<Box<dyn Stream<Item = Result<GraphEvent, Status>> + Send + Sync + 'static>>;
+
     async fn identifiers(
         &self,
         request: Request<Identifier>,
     ) -> Result<Response<RunIdentifiers>, Status> {
         let request = request.into_inner();
-
-        if request.uuid.is_empty() {
-            return Err(Status::invalid_argument(
-                Error::NoIdentifierProvided.to_string(),
-            ));
-        }
-
-        let uuid = match Uuid::parse_str(&request.uuid) {
-            Ok(uuid) => uuid,
-            Err(source) => {
-                return Err(Status::invalid_argument(
-                    Error::UuidEncodingError { source }.to_string(),
-                ));
-            }
-        };
+        let uuid = parse_identifier(&request)?;
+        verify_owner(self, uuid)?;
 
         let graph = read_lock!(self.graph);
         let neighbors = graph.neighbors(uuid);
@@ -44,21 +53,1124 @@ impl Job for Librarian {
             });
         }
 
-        let checksum = match identifiers.checksum() {
-            Ok(checksum) => checksum.to_ne_bytes().to_vec(),
-            Err(e) => return Err(Status::invalid_argument(e.to_string())),
-        };
-
         let run_identifiers = RunIdentifiers {
             run_ids: identifiers,
-            checksum,
+            ..Default::default()
         };
         Ok(Response::new(run_identifiers))
     }
 
-    async fn remap(&self, _request: Request<RemapRequest>) -> Result<Response<Identifier>, Status> {
-        Ok(Response::new(Identifier {
+    /// The children are read in one go so that the stream is a consistent view of the graph,
+    /// a resumed stream only sees the children that sort after its cursor
+    async fn stream_identifiers(
+        &self,
+        request: Request<IdentifiersRequest>,
+    ) -> Result<Response<Self::StreamIdentifiersStream>, Status> {
+        let request = request.into_inner();
+        let parent = parse_identifier(&request.parent.unwrap_or_default())?;
+        verify_owner(self, parent)?;
+        let cursor = match request.cursor {
+            Some(cursor) if !cursor.uuid.is_empty() => Some(parse_identifier(&cursor)?),
+            _ => None,
+        };
+
+        let mut children: Vec<Uuid> = read_lock!(self.graph)
+            .neighbors(parent)
+            .filter(|&child| !matches!(cursor, Some(cursor) if child <= cursor))
+            .collect();
+        children.sort();
+
+        let children = Arc::new(children);
+        let offsets = (0..children.len()).step_by(IDENTIFIER_CHUNK_LEN);
+        let chunks =
+            stream::iter(offsets).map(move |offset| Ok(identifier_chunk(&children, offset)));
+        Ok(Response::new(
+            Box::pin(chunks) as Self::StreamIdentifiersStream
+        ))
+    }
+
+    /// Makes `new_parent` the only parent of every child, the children's previous parents lose
+    /// their edge to the child whichever librarians hold them. Acknowledged once a majority of
+    /// the replicas of every identifier involved has the change, FAILED_PRECONDITION when the
+    /// remap would make the lineage cyclic
+    async fn remap(&self, request: Request<RemapRequest>) -> Result<Response<Identifier>, Status> {
+        let request = request.into_inner();
+        let new_parent = request.new_parent.unwrap_or_default();
+        let parent = parse_identifier(&new_parent)?;
+        verify_owner(self, parent)?;
+
+        let children = match request.children {
+            Some(children) => children,
+            None => {
+                return Err(Status::invalid_argument(
+                    Error::NoChildrenProvided.to_string(),
+                ))
+            }
+        };
+        // the codec already verified the checksum of the children along with the request's
+        let children = children
+            .run_ids
+            .iter()
+            .map(parse_identifier)
+            .collect::<Result<Vec<Uuid>, Status>>()?;
+
+        let writes = self.writes.lock().await;
+        // the previous parents may be held by any librarian, as may the descendants a cycle
+        // would run through
+        let mut roots = children.clone();
+        roots.push(parent);
+        let parents = self.neighborhood(roots, Direction::Incoming, 1).await?;
+        let descendants = self
+            .neighborhood(children.clone(), Direction::Outgoing, 0)
+            .await?;
+        for &child in &children {
+            // the children lose every other parent, so the only edges into them that are left
+            // once the remap is done come from `parent`
+            let path = lineage::path(&descendants, child, parent, |from, to| {
+                from != parent && children.contains(&to)
+            });
+            if let Some(path) = path {
+                return Err(cycle(None, parent, child, path));
+            }
+        }
+        let mut changes = vec![Change::AddNode(parent)];
+        for child in children {
+            let stale = parents
+                .neighbors_directed(child, Direction::Incoming)
+                .filter(|&previous| previous != parent)
+                .map(|previous| Change::RemoveEdge(previous, child));
+            changes.extend(stale);
+            changes.push(Change::AddEdge(parent, child));
+        }
+        let undo = librarian::undo(&parents, &changes);
+        self.commit(&writes, &changes, undo).await?;
+
+        Ok(Response::new(new_parent))
+    }
+
+    /// Every batch is checked and applied on its own under a single write lock of the graph, a
+    /// batch that would make the lineage cyclic is refused with FAILED_PRECONDITION. The parents
+    /// of a batch have to be held by the same librarians, which apply it as a whole. A batch
+    /// whose parents this librarian doesn't hold is redirected to their owner, one whose parents
+    /// belong to different librarians is refused with INVALID_ARGUMENT
+    async fn add_edges(
+        &self,
+        request: Request<Streaming<EdgeBatch>>,
+    ) -> Result<Response<EdgesAdded>, Status> {
+        let mut batches = request.into_inner();
+        let mut added = EdgesAdded::default();
+        // the codec verifies the checksums of a batch and of every pair in it as it reads it
+        while let Some(batch) = batches
+            .message()
+            .await
+            .map_err(|e| in_batch(added.batches, e))?
+        {
+            let index = added.batches;
+            let writes = self.writes.lock().await;
+            let (changes, undo) = self.add_batch(index, batch).await?;
+            self.commit(&writes, &changes, undo)
+                .await
+                .map_err(|e| in_batch(index, e))?;
+            added.batches += 1;
+            added.edges += changes
+                .iter()
+                .filter(|change| matches!(change, Change::AddEdge(..)))
+                .count() as u64;
+        }
+        Ok(Response::new(added))
+    }
+
+    async fn ancestors(
+        &self,
+        request: Request<TraversalRequest>,
+    ) -> Result<Response<RunIdentifiers>, Status> {
+        let request = request.into_inner();
+        let root = traversal_root(self, &request)?;
+        let graph = self
+            .neighborhood(vec![root], Direction::Incoming, request.max_depth)
+            .await?;
+        verify_found(&graph, root)?;
+        let ancestors = lineage::reachable(&graph, root, Direction::Incoming, request.max_depth);
+        Ok(Response::new(run_identifiers(&ancestors)))
+    }
+
+    async fn descendants(
+        &self,
+        request: Request<TraversalRequest>,
+    ) -> Result<Response<RunIdentifiers>, Status> {
+        let request = request.into_inner();
+        let root = traversal_root(self, &request)?;
+        let graph = self
+            .neighborhood(vec![root], Direction::Outgoing, request.max_depth)
+            .await?;
+        verify_found(&graph, root)?;
+        let descendants = lineage::reachable(&graph, root, Direction::Outgoing, request.max_depth);
+        Ok(Response::new(run_identifiers(&descendants)))
+    }
+
+    /// Both directions in one request, each is read like a traversal of its own
+    async fn lineage(
+        &self,
+        request: Request<TraversalRequest>,
+    ) -> Result<Response<RunLineage>, Status> {
+        let request = request.into_inner();
+        let root = traversal_root(self, &request)?;
+        let mut graph = self
+            .neighborhood(vec![root], Direction::Incoming, request.max_depth)
+            .await?;
+        let descendants = self
+            .neighborhood(vec![root], Direction::Outgoing, request.max_depth)
+            .await?;
+        lineage::merge(&mut graph, &descendants);
+        verify_found(&graph, root)?;
+        let lineage = RunLineage {
+            ancestors: identifiers(&lineage::reachable(
+                &graph,
+                root,
+                Direction::Incoming,
+                request.max_depth,
+            )),
+            descendants: identifiers(&lineage::reachable(
+                &graph,
+                root,
+                Direction::Outgoing,
+                request.max_depth,
+            )),
+            ..Default::default()
+        };
+        Ok(Response::new(lineage))
+    }
+
+    /// FAILED_PRECONDITION when the run graph isn't acyclic
+    async fn topological_order(
+        &self,
+        request: Request<Identifier>,
+    ) -> Result<Response<RunIdentifiers>, Status> {
+        let request = request.into_inner();
+        let job = parse_identifier(&request)?;
+        verify_owner(self, job)?;
+        // every parent within the run graph is a descendant of the job, so its edges out are all
+        // that's needed
+        let graph = self.neighborhood(vec![job], Direction::Outgoing, 0).await?;
+        verify_found(&graph, job)?;
+        match lineage::topological_order(&graph, job) {
+            Some(order) => Ok(Response::new(run_identifiers(&order))),
+            None => Err(Status::failed_precondition(
+                Error::CyclicRunGraph { uuid: job }.to_string(),
+            )),
+        }
+    }
+
+    /// Only the owner of the run keeps its locations, the other replicas redirect to it
+    async fn register_output(
+        &self,
+        request: Request<RegisterOutputRequest>,
+    ) -> Result<Response<()>, Status> {
+        let request = request.into_inner();
+        let run = parse_identifier(&request.run_id.unwrap_or_default())?;
+        verify_primary(self, run)?;
+        let location = match request.location {
+            Some(location) if !location.executor.is_empty() => location,
+            _ => {
+                return Err(Status::invalid_argument(
+                    Error::NoExecutorProvided { uuid: run }.to_string(),
+                ))
+            }
+        };
+
+        write_lock!(self.locations).register(run, location);
+        Ok(Response::new(()))
+    }
+
+    /// NOT_FOUND when no executor registered the output of the run, the replicas that aren't
+    /// its owner redirect to it since they don't know about any output
+    async fn locate(
+        &self,
+        request: Request<Identifier>,
+    ) -> Result<Response<OutputLocations>, Status> {
+        let request = request.into_inner();
+        let run = parse_identifier(&request)?;
+        verify_primary(self, run)?;
+        let locations = match read_lock!(self.locations).locate(run) {
+            Some(locations) => locations.to_vec(),
+            None => {
+                return Err(Status::not_found(
+                    Error::IdentifierNotFound { uuid: run }.to_string(),
+                ))
+            }
+        };
+
+        let response = OutputLocations {
+            run_id: Some(request),
+            locations,
+            ..Default::default()
+        };
+        Ok(Response::new(response))
+    }
+
+    /// Only the owner of the job keeps track of its ttl and collects it, the other replicas
+    /// redirect to it
+    async fn complete_job(
+        &self,
+        request: Request<CompleteJobRequest>,
+    ) -> Result<Response<()>, Status> {
+        let request = request.into_inner();
+        let job = parse_identifier(&request.job.unwrap_or_default())?;
+        verify_primary(self, job)?;
+        if !read_lock!(self.graph).contains_node(job) {
+            return Err(Status::not_found(
+                Error::IdentifierNotFound { uuid: job }.to_string(),
+            ));
+        }
+
+        self.retention
+            .lock()
+            .complete(job, Duration::from_secs(request.ttl), Instant::now());
+        Ok(Response::new(()))
+    }
+
+    async fn collect_garbage(&self, _: Request<()>) -> Result<Response<Reclaimed>, Status> {
+        Ok(Response::new(self.collect_expired().await?))
+    }
+
+    async fn watch(
+        &self,
+        request: Request<WatchRequest>,
+    ) -> Result<Response<Self::WatchStream>, Status> {
+        let events = watch::watch(self, request.into_inner())?;
+        Ok(Response::new(Box::pin(events) as Self::WatchStream))
+    }
+}
+
+impl Librarian {
+    /// Validates the `index`th batch and returns the changes that add it in one go along with
+    /// their undo
+    async fn add_batch(
+        &self,
+        index: u64,
+        batch: EdgeBatch,
+    ) -> Result<(Vec<Change>, Vec<Vec<Change>>), Status> {
+        let parents = batch
+            .edges
+            .iter()
+            .map(|edges| parse_identifier(edges.parent.as_ref().unwrap_or(&Identifier::default())))
+            .collect::<Result<Vec<Uuid>, Status>>()
+            .map_err(|e| in_batch(index, e))?;
+        // not prefixed, a redirect has to keep its details
+        self.verify_batch_owner(index, &parents)?;
+        let mut pending = Graph::new();
+        for (edges, parent) in batch.edges.into_iter().zip(parents) {
+            let children = match edges.children {
+                Some(children) => children,
+                None => {
+                    let status = Status::invalid_argument(Error::NoChildrenProvided.to_string());
+                    return Err(in_batch(index, status));
+                }
+            };
+            pending.add_node(parent);
+            for child in &children.run_ids {
+                let child = parse_identifier(child).map_err(|e| in_batch(index, e))?;
+                pending.add_edge(parent, child, ());
+            }
+        }
+
+        // every path out of the batch's nodes, wherever it runs, a cycle has to be one of them
+        let graph = self
+            .neighborhood(pending.nodes().collect(), Direction::Outgoing, 0)
+            .await?;
+        if let Some((parent, child, path)) = lineage::cycle_with(&graph, &pending) {
+            return Err(cycle(Some(index), parent, child, path));
+        }
+        let nodes = pending.nodes().map(Change::AddNode);
+        let edges = pending
+            .all_edges()
+            .map(|(parent, child, _)| Change::AddEdge(parent, child));
+        let changes: Vec<Change> = nodes.chain(edges).collect();
+        let undo = librarian::undo(&graph, &changes);
+        Ok((changes, undo))
+    }
+
+    /// Redirects the `index`th batch to the owner of its parents when this librarian holds none
+    /// of them. INVALID_ARGUMENT when it holds some of them but not all, or when they belong to
+    /// different librarians
+    fn verify_batch_owner(&self, index: u64, parents: &[Uuid]) -> Result<(), Status> {
+        let first = match parents.first() {
+            Some(&first) => first,
+            None => return Ok(()),
+        };
+        let cluster = read_lock!(self.cluster);
+        let owner = cluster.redirect(first);
+        if let Some(&other) = parents[1..]
+            .iter()
+            .find(|&&other| cluster.redirect(other) != owner)
+        {
+            let status =
+                Status::invalid_argument(Error::BatchSpansPartitions { first, other }.to_string());
+            return Err(in_batch(index, status));
+        }
+        drop(cluster);
+        verify_owner(self, first)
+    }
+
+    /// Deletes the nodes that only the complete jobs whose ttl expired reference, along with
+    /// their output locations. The deletions are replicated like any other write, the jobs are
+    /// only forgotten once that succeeded. The locations that are counted are the ones this
+    /// librarian owns
+    pub(crate) async fn collect_expired(&self) -> Result<Reclaimed, Status> {
+        let writes = self.writes.lock().await;
+        let now = Instant::now();
+        let expired = self.retention.lock().expired(now);
+        let mut reclaimed = Reclaimed {
+            jobs: expired.len() as u64,
+            ..Default::default()
+        };
+
+        let (changes, undo) = {
+            // the descendants of the jobs along with every parent they have, wherever those are
+            // held
+            let mut graph = self
+                .neighborhood(expired.clone(), Direction::Outgoing, 0)
+                .await?;
+            let parents = self
+                .neighborhood(graph.nodes().collect(), Direction::Incoming, 1)
+                .await?;
+            lineage::merge(&mut graph, &parents);
+            // a job that descends from another expired job would otherwise be deleted twice
+            let collectable: BTreeSet<Uuid> = expired
+                .iter()
+                .copied()
+                .filter(|&job| graph.contains_node(job))
+                .flat_map(|job| lineage::collectable(&graph, job))
+                .collect();
+            let edges: BTreeSet<(Uuid, Uuid)> = collectable
+                .iter()
+                .flat_map(|&node| {
+                    let parents = graph
+                        .neighbors_directed(node, Direction::Incoming)
+                        .map(move |parent| (parent, node));
+                    let children = graph
+                        .neighbors_directed(node, Direction::Outgoing)
+                        .map(move |child| (node, child));
+                    parents.chain(children)
+                })
+                .collect();
+            reclaimed.nodes = collectable.len() as u64;
+            reclaimed.edges = edges.len() as u64;
+            let changes: Vec<Change> = collectable.into_iter().map(Change::RemoveNode).collect();
+            let undo = librarian::undo(&graph, &changes);
+            (changes, undo)
+        };
+        // the locations this librarian owns, the owners of the other nodes forget theirs when
+        // they apply the deletion
+        reclaimed.locations = {
+            let locations = read_lock!(self.locations);
+            changes
+                .iter()
+                .filter_map(|change| locations.locate(change.key()))
+                .map(|located| located.len() as u64)
+                .sum()
+        };
+        self.commit(&writes, &changes, undo).await?;
+        self.retention.lock().collected(&expired, now);
+        Ok(reclaimed)
+    }
+}
+
+/// Prefixes the message of `status` with the index of the batch that caused it. The details are
+/// dropped, tonic hands them back encoded so a status carrying some is built with the prefix
+/// from the start instead, see `cycle`
+fn in_batch(index: u64, status: Status) -> Status {
+    Status::new(
+        status.code(),
+        format!("batch {}: {}", index, status.message()),
+    )
+}
+
+/// FAILED_PRECONDITION carrying the cycle that adding the edge from `parent` to `child` would
+/// close, `path` leads from `child` back to `parent`. The message is prefixed with the index of
+/// the batch that added the edge if there is one
+fn cycle(batch: Option<u64>, parent: Uuid, child: Uuid, path: Vec<Uuid>) -> Status {
+    let mut cycle = vec![parent];
+    cycle.extend(path);
+    let message = Error::CycleDetected { parent, child }.to_string();
+    status_with_details(
+        Code::FailedPrecondition,
+        match batch {
+            Some(index) => format!("batch {}: {}", index, message),
+            None => message,
+        },
+        &Cycle {
+            path: identifiers(&cycle),
+        },
+    )
+}
+
+/// Checks the request and returns its root
+fn traversal_root(librarian: &Librarian, request: &TraversalRequest) -> Result<Uuid, Status> {
+    let root = parse_identifier(&request.root.clone().unwrap_or_default())?;
+    verify_owner(librarian, root)?;
+    Ok(root)
+}
+
+/// NOT_FOUND when the part of the graph a traversal put together doesn't know about `root`
+fn verify_found(graph: &Graph<Uuid, ()>, root: Uuid) -> Result<(), Status> {
+    if !graph.contains_node(root) {
+        return Err(Status::not_found(
+            Error::IdentifierNotFound { uuid: root }.to_string(),
+        ));
+    }
+    Ok(())
+}
+
+fn identifier_chunk(children: &[Uuid], offset: usize) -> IdentifierChunk {
+    let end = children.len().min(offset + IDENTIFIER_CHUNK_LEN);
+    IdentifierChunk {
+        run_ids: identifiers(&children[offset..end]),
+        cursor: Some(Identifier {
+            uuid: children[end - 1].to_string(),
+        }),
+        ..Default::default()
+    }
+}
+
+fn identifiers(uuids: &[Uuid]) -> Vec<Identifier> {
+    uuids
+        .iter()
+        .map(|uuid| Identifier {
+            uuid: uuid.to_string(),
+        })
+        .collect()
+}
+
+fn run_identifiers(uuids: &[Uuid]) -> RunIdentifiers {
+    RunIdentifiers {
+        run_ids: identifiers(uuids),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::future::FutureExt;
+    use tokio::sync::oneshot;
+    use tokio::sync::oneshot::Receiver;
+
+    use super::*;
+    use neuromancer::librarian::job_client::JobClient;
+    use neuromancer::router::redirected_to;
+    use neuromancer::{details_from_status, Algorithm, Checksummable};
+
+    const DEFAULT_STARTUP_TIMEOUT: u64 = 1;
+    const REMAP_ADDRESS: &str = "[::1]:1349";
+    const REMAP_ERRORS_ADDRESS: &str = "[::1]:1348";
+    const TRAVERSAL_ADDRESS: &str = "[::1]:1347";
+    const LOCATE_ADDRESS: &str = "[::1]:1339";
+    const GARBAGE_COLLECTION_ADDRESS: &str = "[::1]:1338";
+    const CYCLE_ADDRESS: &str = "[::1]:1329";
+    const STREAM_ADDRESS: &str = "[::1]:1328";
+    const WATCH_ADDRESS: &str = "[::1]:1327";
+    const ADD_EDGES_ADDRESS: &str = "[::1]:1324";
+
+    async fn gen_server(addr: &'static str, rx: Receiver<()>) -> tokio::task::JoinHandle<()> {
+        gen_server_with(addr, rx, Librarian::default()).await
+    }
+
+    async fn gen_server_with(
+        addr: &'static str,
+        rx: Receiver<()>,
+        librarian: Librarian,
+    ) -> tokio::task::JoinHandle<()> {
+        let server = tokio::spawn(async move {
+            tonic::transport::Server::builder()
+                .add_service(JobServer::new(librarian))
+                .serve_with_shutdown(addr.parse().unwrap(), rx.map(drop))
+                .await
+                .unwrap();
+        });
+        tokio::time::delay_for(Duration::from_millis(DEFAULT_STARTUP_TIMEOUT)).await;
+        server
+    }
+
+    fn identifier(uuid: u128) -> Identifier {
+        Identifier {
+            uuid: Uuid::from_u128(uuid).to_string(),
+        }
+    }
+
+    fn remap_request(parent: u128, children: &[u128]) -> RemapRequest {
+        let mut children = RunIdentifiers {
+            run_ids: children.iter().cloned().map(identifier).collect(),
             ..Default::default()
-        }))
+        };
+        children.checksum = children.checksum().unwrap();
+        RemapRequest {
+            new_parent: Some(identifier(parent)),
+            children: Some(children),
+            ..Default::default()
+        }
+    }
+
+    fn traversal_request(root: u128, max_depth: u32) -> TraversalRequest {
+        let mut request = TraversalRequest {
+            root: Some(identifier(root)),
+            max_depth,
+            ..Default::default()
+        };
+        request.checksum = request.checksum().unwrap();
+        request
+    }
+
+    #[tokio::test]
+    async fn remap_reparents_children() {
+        let (tx, rx) = oneshot::channel::<()>();
+        let server = gen_server(REMAP_ADDRESS, rx).await;
+        let mut client_address = String::from("http://");
+        client_address += REMAP_ADDRESS;
+        let mut client = JobClient::connect(client_address).await.unwrap();
+
+        let response = client
+            .remap(Request::new(remap_request(1, &[3, 4])))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response, identifier(1));
+        client
+            .remap(Request::new(remap_request(2, &[4])))
+            .await
+            .unwrap();
+
+        let first = client
+            .identifiers(Request::new(identifier(1)))
+            .await
+            .unwrap();
+        let second = client
+            .identifiers(Request::new(identifier(2)))
+            .await
+            .unwrap();
+        assert_eq!(first.into_inner().run_ids, vec![identifier(3)]);
+        assert_eq!(second.into_inner().run_ids, vec![identifier(4)]);
+
+        tx.send(()).unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn remap_rejects_bad_requests() {
+        let (tx, rx) = oneshot::channel::<()>();
+        let server = gen_server(REMAP_ERRORS_ADDRESS, rx).await;
+        let mut client_address = String::from("http://");
+        client_address += REMAP_ERRORS_ADDRESS;
+        let mut client = JobClient::connect(client_address).await.unwrap();
+
+        let mut payload = remap_request(1, &[2]);
+        payload.new_parent = Some(Identifier {
+            uuid: "not a uuid".to_string(),
+        });
+        let err = client.remap(Request::new(payload)).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+
+        let mut payload = remap_request(1, &[2]);
+        payload.children.as_mut().unwrap().run_ids[0].uuid = "not a uuid".to_string();
+        let err = client
+            .remap(Request::new(payload.clone()))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+        assert_eq!(&err.message()[0..29], "checksum mismatch for payload");
+
+        let children = payload.children.as_mut().unwrap();
+        children.checksum = children.checksum().unwrap();
+        let err = client.remap(Request::new(payload)).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+        assert_eq!(&err.message()[0..19], "uuid encoding error");
+
+        // the request's checksum covers the new parent
+        let mut payload = remap_request(1, &[2]);
+        payload.checksum = payload.checksum().unwrap();
+        payload.new_parent = Some(identifier(3));
+        let err = client.remap(Request::new(payload)).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+        assert_eq!(&err.message()[0..29], "checksum mismatch for payload");
+
+        let mut payload = remap_request(1, &[2]);
+        payload.children.as_mut().unwrap().checksum = vec![Algorithm::Xxh3 as u8, 1, 2, 3, 4];
+        let err = client.remap(Request::new(payload)).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::OutOfRange);
+
+        tx.send(()).unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn traverses_lineage() {
+        let (tx, rx) = oneshot::channel::<()>();
+        let server = gen_server(TRAVERSAL_ADDRESS, rx).await;
+        let mut client_address = String::from("http://");
+        client_address += TRAVERSAL_ADDRESS;
+        let mut client = JobClient::connect(client_address).await.unwrap();
+
+        // 1 -> 2 -> 3 -> 4
+        for &(parent, child) in &[(1, 2), (2, 3), (3, 4)] {
+            client
+                .remap(Request::new(remap_request(parent, &[child])))
+                .await
+                .unwrap();
+        }
+
+        let descendants = client
+            .descendants(Request::new(traversal_request(1, 2)))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(descendants.run_ids, vec![identifier(2), identifier(3)]);
+        let ancestors = client
+            .ancestors(Request::new(traversal_request(4, 0)))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(
+            ancestors.run_ids,
+            vec![identifier(3), identifier(2), identifier(1)]
+        );
+        let lineage = client
+            .lineage(Request::new(traversal_request(2, 1)))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(lineage.ancestors, vec![identifier(1)]);
+        assert_eq!(lineage.descendants, vec![identifier(3)]);
+        let order = client
+            .topological_order(Request::new(identifier(2)))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(
+            order.run_ids,
+            vec![identifier(2), identifier(3), identifier(4)]
+        );
+
+        let err = client
+            .descendants(Request::new(traversal_request(5, 0)))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::NotFound);
+        let mut payload = traversal_request(1, 0);
+        payload.max_depth = 3;
+        let err = client.lineage(Request::new(payload)).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+
+        tx.send(()).unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn locates_registered_outputs() {
+        let (tx, rx) = oneshot::channel::<()>();
+        let server = gen_server(LOCATE_ADDRESS, rx).await;
+        let mut client_address = String::from("http://");
+        client_address += LOCATE_ADDRESS;
+        let mut client = JobClient::connect(client_address).await.unwrap();
+
+        let err = client
+            .locate(Request::new(identifier(1)))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::NotFound);
+
+        let location = OutputLocation {
+            executor: "http://[::1]:9001".to_string(),
+            size: 1024,
+            output_checksum: vec![0xde, 0xad],
+        };
+        let mut request = RegisterOutputRequest {
+            run_id: Some(identifier(1)),
+            location: Some(location.clone()),
+            ..Default::default()
+        };
+        request.checksum = request.checksum().unwrap();
+        client
+            .register_output(Request::new(request.clone()))
+            .await
+            .unwrap();
+
+        let located = client
+            .locate(Request::new(identifier(1)))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(located.checksum().unwrap(), located.checksum);
+        assert_eq!(located.run_id, Some(identifier(1)));
+        assert_eq!(located.locations, vec![location]);
+
+        request.location.as_mut().unwrap().executor.clear();
+        request.checksum = request.checksum().unwrap();
+        let err = client
+            .register_output(Request::new(request))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+
+        tx.send(()).unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn collects_expired_jobs() {
+        // job 1 runs 3 and 4, job 2 runs 5 which reuses the output of 4
+        let librarian = Librarian::default();
+        {
+            let mut graph = write_lock!(librarian.graph);
+            for &(parent, child) in &[(1, 3), (1, 4), (2, 5), (5, 4)] {
+                graph.add_edge(Uuid::from_u128(parent), Uuid::from_u128(child), ());
+            }
+        }
+        let (tx, rx) = oneshot::channel::<()>();
+        let server = gen_server_with(GARBAGE_COLLECTION_ADDRESS, rx, librarian).await;
+        let mut client_address = String::from("http://");
+        client_address += GARBAGE_COLLECTION_ADDRESS;
+        let mut client = JobClient::connect(client_address).await.unwrap();
+
+        let complete = |job, ttl| {
+            let mut request = CompleteJobRequest {
+                job: Some(identifier(job)),
+                ttl,
+                ..Default::default()
+            };
+            request.checksum = request.checksum().unwrap();
+            request
+        };
+        client
+            .complete_job(Request::new(complete(1, 0)))
+            .await
+            .unwrap();
+        client
+            .complete_job(Request::new(complete(2, 3600)))
+            .await
+            .unwrap();
+        let err = client
+            .complete_job(Request::new(complete(6, 0)))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::NotFound);
+
+        let reclaimed = client
+            .collect_garbage(Request::new(()))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(reclaimed.jobs, 1);
+        assert_eq!(reclaimed.nodes, 2);
+        assert_eq!(reclaimed.edges, 2);
+
+        let err = client
+            .descendants(Request::new(traversal_request(1, 0)))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::NotFound);
+        let descendants = client
+            .descendants(Request::new(traversal_request(2, 0)))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(descendants.run_ids, vec![identifier(5), identifier(4)]);
+
+        tx.send(()).unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn remap_rejects_cycles() {
+        let (tx, rx) = oneshot::channel::<()>();
+        let server = gen_server(CYCLE_ADDRESS, rx).await;
+        let mut client_address = String::from("http://");
+        client_address += CYCLE_ADDRESS;
+        let mut client = JobClient::connect(client_address).await.unwrap();
+
+        // 1 -> 2 -> 3
+        for &(parent, child) in &[(1, 2), (2, 3)] {
+            client
+                .remap(Request::new(remap_request(parent, &[child])))
+                .await
+                .unwrap();
+        }
+
+        let err = client
+            .remap(Request::new(remap_request(3, &[1])))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::FailedPrecondition);
+        // the same code, but nothing to follow
+        assert_eq!(redirected_to(&err), None);
+        let details: Cycle = details_from_status(&err).unwrap();
+        assert_eq!(
+            details.path,
+            vec![identifier(3), identifier(1), identifier(2), identifier(3)]
+        );
+        let err = client
+            .remap(Request::new(remap_request(2, &[2])))
+            .await
+            .unwrap_err();
+        let details: Cycle = details_from_status(&err).unwrap();
+        assert_eq!(details.path, vec![identifier(2), identifier(2)]);
+
+        // 3 loses its edge from 2 so moving 2 below it doesn't close a loop
+        client
+            .remap(Request::new(remap_request(1, &[3])))
+            .await
+            .unwrap();
+        client
+            .remap(Request::new(remap_request(3, &[2])))
+            .await
+            .unwrap();
+
+        tx.send(()).unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn streams_identifiers_from_a_cursor() {
+        let librarian = Librarian::default();
+        {
+            let mut graph = write_lock!(librarian.graph);
+            for child in 2..2502 {
+                graph.add_edge(Uuid::from_u128(1), Uuid::from_u128(child), ());
+            }
+        }
+        let (tx, rx) = oneshot::channel::<()>();
+        let server = gen_server_with(STREAM_ADDRESS, rx, librarian).await;
+        let mut client_address = String::from("http://");
+        client_address += STREAM_ADDRESS;
+        let mut client = JobClient::connect(client_address).await.unwrap();
+
+        let request = |cursor: Option<Identifier>| {
+            let mut request = IdentifiersRequest {
+                parent: Some(identifier(1)),
+                cursor,
+                ..Default::default()
+            };
+            request.checksum = request.checksum().unwrap();
+            request
+        };
+
+        let mut stream = client
+            .stream_identifiers(Request::new(request(None)))
+            .await
+            .unwrap()
+            .into_inner();
+        let mut chunks = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.unwrap();
+            assert_eq!(chunk.checksum().unwrap(), chunk.checksum);
+            assert_eq!(chunk.cursor.as_ref(), chunk.run_ids.last());
+            chunks.push(chunk);
+        }
+        let lengths: Vec<usize> = chunks.iter().map(|chunk| chunk.run_ids.len()).collect();
+        assert_eq!(lengths, vec![1024, 1024, 452]);
+        let children: Vec<Identifier> = chunks
+            .iter()
+            .flat_map(|chunk| chunk.run_ids.clone())
+            .collect();
+        let expected: Vec<Identifier> = (2..2502).map(identifier).collect();
+        assert_eq!(children, expected);
+
+        // pick up where the first chunk left off
+        let mut stream = client
+            .stream_identifiers(Request::new(request(chunks[0].cursor.clone())))
+            .await
+            .unwrap()
+            .into_inner();
+        let resumed = stream.next().await.unwrap().unwrap();
+        assert_eq!(resumed.run_ids, chunks[1].run_ids);
+
+        let err = client
+            .stream_identifiers(Request::new(request(Some(Identifier {
+                uuid: "not a uuid".to_string(),
+            }))))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+
+        tx.send(()).unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn watches_a_subtree_and_resumes() {
+        let (tx, rx) = oneshot::channel::<()>();
+        let server = gen_server(WATCH_ADDRESS, rx).await;
+        let mut client_address = String::from("http://");
+        client_address += WATCH_ADDRESS;
+        let mut client = JobClient::connect(client_address).await.unwrap();
+
+        let watch = |root: Option<u128>, epoch, after| {
+            let mut request = WatchRequest {
+                root: root.map(identifier),
+                epoch,
+                after,
+                ..Default::default()
+            };
+            request.checksum = request.checksum().unwrap();
+            request
+        };
+        let mut subtree = client
+            .watch(Request::new(watch(Some(1), 0, 0)))
+            .await
+            .unwrap()
+            .into_inner();
+
+        // 3 is moved below 1 and then out of the subtree again, so its child 4 isn't watched.
+        // Remapping 5 to the parent it already has changes nothing and isn't published
+        let remaps = [
+            (1, 2),
+            (9, 8),
+            (2, 3),
+            (1, 3),
+            (9, 3),
+            (3, 4),
+            (2, 5),
+            (2, 5),
+            (1, 6),
+        ];
+        for &(parent, child) in &remaps {
+            client
+                .remap(Request::new(remap_request(parent, &[child])))
+                .await
+                .unwrap();
+        }
+
+        let mut events = Vec::new();
+        let mut epoch = 0;
+        for _ in 0..7 {
+            let event = subtree.next().await.unwrap().unwrap();
+            assert_eq!(event.checksum().unwrap(), event.checksum);
+            epoch = event.epoch;
+            events.push((event.sequence, event.kind, event.node, event.child));
+        }
+        let added = graph_event::Kind::EdgeAdded as i32;
+        let removed = graph_event::Kind::EdgeRemoved as i32;
+        assert_eq!(
+            events,
+            vec![
+                (1, added, Some(identifier(1)), Some(identifier(2))),
+                (3, added, Some(identifier(2)), Some(identifier(3))),
+                (4, removed, Some(identifier(2)), Some(identifier(3))),
+                (5, added, Some(identifier(1)), Some(identifier(3))),
+                (6, removed, Some(identifier(1)), Some(identifier(3))),
+                (9, added, Some(identifier(2)), Some(identifier(5))),
+                (10, added, Some(identifier(1)), Some(identifier(6))),
+            ]
+        );
+        assert_ne!(epoch, 0);
+
+        let mut resumed = client
+            .watch(Request::new(watch(None, epoch, 1)))
+            .await
+            .unwrap()
+            .into_inner();
+        let event = resumed.next().await.unwrap().unwrap();
+        assert_eq!(event.sequence, 2);
+        assert_eq!(event.node, Some(identifier(9)));
+
+        let err = client
+            .watch(Request::new(watch(None, epoch, 11)))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::OutOfRange);
+        // sequence numbers of an earlier epoch mean nothing after a restart
+        let err = client
+            .watch(Request::new(watch(None, epoch - 1, 1)))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::OutOfRange);
+
+        drop(subtree);
+        drop(resumed);
+        tx.send(()).unwrap();
+        server.await.unwrap();
+    }
+
+    fn edge_batch(edges: &[(u128, &[u128])]) -> EdgeBatch {
+        let mut batch = EdgeBatch {
+            edges: edges
+                .iter()
+                .map(|&(parent, children)| {
+                    let mut children = RunIdentifiers {
+                        run_ids: children.iter().cloned().map(identifier).collect(),
+                        ..Default::default()
+                    };
+                    children.checksum = children.checksum().unwrap();
+                    ParentEdges {
+                        parent: Some(identifier(parent)),
+                        children: Some(children),
+                    }
+                })
+                .collect(),
+            ..Default::default()
+        };
+        batch.checksum = batch.checksum().unwrap();
+        batch
+    }
+
+    #[tokio::test]
+    async fn adds_edges_atomically_per_batch() {
+        let librarian = Librarian::default();
+        let (tx, rx) = oneshot::channel::<()>();
+        let server = gen_server_with(ADD_EDGES_ADDRESS, rx, librarian.clone()).await;
+        let mut client_address = String::from("http://");
+        client_address += ADD_EDGES_ADDRESS;
+        let mut client = JobClient::connect(client_address).await.unwrap();
+
+        let batches = vec![
+            edge_batch(&[(1, &[2, 3]), (2, &[4])]),
+            edge_batch(&[(5, &[4])]),
+        ];
+        let added = client
+            .add_edges(Request::new(stream::iter(batches)))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!((added.batches, added.edges), (2, 4));
+        {
+            // unlike remap, 4 keeps both of its parents
+            let graph = read_lock!(librarian.graph);
+            assert!(graph.contains_edge(Uuid::from_u128(2), Uuid::from_u128(4)));
+            assert!(graph.contains_edge(Uuid::from_u128(5), Uuid::from_u128(4)));
+        }
+
+        // the second batch closes 4 -> 6 -> 1 -> 2 -> 4 through an edge of its own
+        let batches = vec![
+            edge_batch(&[(7, &[8])]),
+            edge_batch(&[(4, &[6]), (6, &[1]), (8, &[9])]),
+        ];
+        let err = client
+            .add_edges(Request::new(stream::iter(batches)))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::FailedPrecondition);
+        assert!(err.message().starts_with("batch 1: "));
+        let details: Cycle = details_from_status(&err).unwrap();
+        assert_eq!(details.path.first(), details.path.last());
+        {
+            let graph = read_lock!(librarian.graph);
+            assert!(graph.contains_edge(Uuid::from_u128(7), Uuid::from_u128(8)));
+            assert!(!graph.contains_node(Uuid::from_u128(6)));
+            assert!(!graph.contains_node(Uuid::from_u128(9)));
+        }
+
+        // every pair of a batch carries the checksum of its children
+        let mut corrupted = edge_batch(&[(10, &[11])]);
+        corrupted.edges[0].children.as_mut().unwrap().run_ids[0] = identifier(12);
+        corrupted.checksum = corrupted.checksum().unwrap();
+        let err = client
+            .add_edges(Request::new(stream::iter(vec![corrupted])))
+            .await
+            .unwrap_err();
+        assert!(err.message().starts_with("batch 0: checksum mismatch"));
+        assert!(!read_lock!(librarian.graph).contains_node(Uuid::from_u128(10)));
+
+        tx.send(()).unwrap();
+        server.await.unwrap();
     }
 }
@@ -0,0 +1,972 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use futures::future::join_all;
+use petgraph::graphmap::DiGraphMap as Graph;
+use petgraph::Direction;
+use tokio::sync::MutexGuard;
+use tonic::transport::Channel;
+use tonic::{Code, Request, Response, Status};
+use uuid::Uuid;
+
+use crate::errors::*;
+use crate::librarian::{Change, Librarian};
+use crate::lineage;
+use crate::services::parse_identifier;
+use neuromancer::{
+    base::{Identifier, MembershipEpoch},
+    librarian::replication_client::ReplicationClient,
+    librarian::replication_server::*,
+    librarian::*,
+    read_lock, socket, status_with_details, write_lock,
+};
+
+#[tonic::async_trait]
+impl Replication for Librarian {
+    /// Applies the changes that the owner of an identifier already applied, replicas never
+    /// forward them any further
+    async fn replicate(&self, request: Request<ReplicateRequest>) -> Result<Response<()>, Status> {
+        let request = request.into_inner();
+        let changes = request
+            .changes
+            .iter()
+            .map(from_graph_change)
+            .collect::<Result<Vec<Change>, Status>>()?;
+
+        let mut graph = write_lock!(self.graph);
+        if let Err(e) = self.apply(&mut graph, &changes) {
+            return Err(Status::unavailable(e.to_string()));
+        }
+        Ok(Response::new(()))
+    }
+
+    async fn membership_change(
+        &self,
+        request: Request<LibrarianMembership>,
+    ) -> Result<Response<()>, Status> {
+        let request = request.into_inner();
+
+        let mut cluster = write_lock!(self.cluster);
+        if !cluster.is_newer(request.epoch) {
+            return Err(stale_epoch(cluster.epoch(), request.epoch));
+        }
+        cluster.modify_membership(&request.librarians, request.epoch);
+        self.peers
+            .lock()
+            .retain(|peer, _| request.librarians.contains(peer));
+        Ok(Response::new(()))
+    }
+
+    /// Answers from whatever this librarian's graph holds, whether or not it is a replica of the
+    /// nodes. The librarian that asked puts the answers of every librarian together
+    async fn neighbors(
+        &self,
+        request: Request<NeighborsRequest>,
+    ) -> Result<Response<Adjacency>, Status> {
+        let request = request.into_inner();
+        let nodes = request
+            .nodes
+            .iter()
+            .map(parse_identifier)
+            .collect::<Result<Vec<Uuid>, Status>>()?;
+        let direction = match neighbors_request::Direction::from_i32(request.direction) {
+            Some(neighbors_request::Direction::Outgoing) => Direction::Outgoing,
+            Some(neighbors_request::Direction::Incoming) => Direction::Incoming,
+            None => {
+                return Err(Status::invalid_argument(
+                    Error::UnknownDirection {
+                        direction: request.direction,
+                    }
+                    .to_string(),
+                ))
+            }
+        };
+
+        let (known, edges) = {
+            let graph = read_lock!(self.graph);
+            lineage::adjacent(&graph, &nodes, direction)
+        };
+        let edges = edges
+            .into_iter()
+            .map(|(parent, child)| Edge {
+                parent: Some(to_identifier(parent)),
+                child: Some(to_identifier(child)),
+            })
+            .collect();
+        Ok(Response::new(Adjacency {
+            known: known.into_iter().map(to_identifier).collect(),
+            edges,
+            ..Default::default()
+        }))
+    }
+}
+
+impl Librarian {
+    /// Replicates `changes` and applies them locally once a majority of the replicas of every
+    /// identifier they touch has them, the local copy counts towards the majority. `undo` holds
+    /// the changes that undo each of `changes`, see `librarian::undo`, and callers hold
+    /// `self.writes` from computing both until this returns so that the graph they were computed
+    /// against is the one they are applied to. Without a majority nothing is applied locally and
+    /// the replicas that did apply the changes are sent their undo
+    pub(crate) async fn commit(
+        &self,
+        _writes: &MutexGuard<'_, ()>,
+        changes: &[Change],
+        undo: Vec<Vec<Change>>,
+    ) -> Result<(), Status> {
+        // the replicas of every identifier, and the changes every peer has to be sent
+        let mut replicas: BTreeMap<Uuid, Vec<String>> = BTreeMap::new();
+        let mut batches: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+        {
+            let cluster = read_lock!(self.cluster);
+            for (index, &change) in changes.iter().enumerate() {
+                let owners = replicas
+                    .entry(change.key())
+                    .or_insert_with(|| cluster.replicas(change.key()));
+                for peer in owners.iter().filter(|peer| !cluster.is_local(peer)) {
+                    batches.entry(peer.clone()).or_default().push(index);
+                }
+            }
+        }
+
+        let sends = batches.iter().map(|(peer, indices)| async move {
+            let changes = indices.iter().map(|&i| to_graph_change(changes[i]));
+            self.send(peer, changes.collect()).await.ok().map(|_| peer)
+        });
+        let acknowledged: BTreeSet<&String> = join_all(sends).await.into_iter().flatten().collect();
+
+        let committed = match self.quorum(&replicas, &acknowledged) {
+            Err(status) => Err(status),
+            Ok(()) => {
+                let mut graph = write_lock!(self.graph);
+                self.apply(&mut graph, changes)
+                    .map_err(|e| Status::unavailable(e.to_string()))
+            }
+        };
+        if committed.is_err() {
+            let undos = batches
+                .iter()
+                .filter(|(peer, _)| acknowledged.contains(peer))
+                .map(|(peer, indices)| {
+                    let undo = indices
+                        .iter()
+                        .rev()
+                        .flat_map(|&i| undo[i].iter().map(|&change| to_graph_change(change)));
+                    self.send(peer, undo.collect())
+                });
+            // a replica that misses its undo is left with the write, which a client told that
+            // the write failed may retry anyway
+            join_all(undos).await;
+        }
+        committed
+    }
+
+    /// UNAVAILABLE unless a majority of the `replicas` of every identifier, counting the local
+    /// copy, are among the `acknowledged` peers
+    fn quorum(
+        &self,
+        replicas: &BTreeMap<Uuid, Vec<String>>,
+        acknowledged: &BTreeSet<&String>,
+    ) -> Result<(), Status> {
+        let cluster = read_lock!(self.cluster);
+        for (&uuid, owners) in replicas {
+            // no membership list yet, the librarian is on its own
+            if owners.is_empty() {
+                continue;
+            }
+            let acknowledged = owners
+                .iter()
+                .filter(|owner| cluster.is_local(owner) || acknowledged.contains(owner))
+                .count();
+            let quorum = owners.len() / 2 + 1;
+            if acknowledged < quorum {
+                return Err(Status::unavailable(
+                    Error::NoQuorum {
+                        uuid,
+                        acknowledged,
+                        replicas: owners.len(),
+                        quorum,
+                    }
+                    .to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// The part of the graph that is reachable from `roots` by following at most `max_depth`
+    /// edges in `direction`, 0 doesn't limit the traversal. A librarian's graph only holds the
+    /// edges of the identifiers it is a replica of, so while it doesn't hold every identifier
+    /// each level of the traversal asks every other librarian for the edges it holds as well.
+    /// UNAVAILABLE when one of them can't answer, the view would be missing part of the graph.
+    /// The levels aren't read atomically, writes coordinated by other librarians may land in
+    /// between them
+    pub(crate) async fn neighborhood(
+        &self,
+        roots: Vec<Uuid>,
+        direction: Direction,
+        max_depth: u32,
+    ) -> Result<Graph<Uuid, ()>, Status> {
+        let peers = read_lock!(self.cluster).other_partitions();
+        let mut view = Graph::new();
+        let mut seen: BTreeSet<Uuid> = roots.iter().copied().collect();
+        let mut frontier = roots;
+        let mut depth = 0;
+        while !frontier.is_empty() && (max_depth == 0 || depth < max_depth) {
+            let (mut known, mut edges) = {
+                let graph = read_lock!(self.graph);
+                lineage::adjacent(&graph, &frontier, direction)
+            };
+            let request = NeighborsRequest {
+                nodes: frontier.iter().copied().map(to_identifier).collect(),
+                direction: match direction {
+                    Direction::Outgoing => neighbors_request::Direction::Outgoing,
+                    Direction::Incoming => neighbors_request::Direction::Incoming,
+                } as i32,
+                ..Default::default()
+            };
+            let answers = peers
+                .iter()
+                .map(|peer| self.neighbors_from(peer, request.clone()));
+            for answer in join_all(answers).await {
+                let answer = answer?;
+                for node in &answer.known {
+                    known.push(parse_identifier(node)?);
+                }
+                for edge in &answer.edges {
+                    let parent =
+                        parse_identifier(edge.parent.as_ref().unwrap_or(&Identifier::default()))?;
+                    let child =
+                        parse_identifier(edge.child.as_ref().unwrap_or(&Identifier::default()))?;
+                    edges.push((parent, child));
+                }
+            }
+
+            for node in known {
+                view.add_node(node);
+            }
+            frontier = Vec::new();
+            for (parent, child) in edges {
+                view.add_edge(parent, child, ());
+                let next = match direction {
+                    Direction::Outgoing => child,
+                    Direction::Incoming => parent,
+                };
+                if seen.insert(next) {
+                    frontier.push(next);
+                }
+            }
+            depth += 1;
+        }
+        Ok(view)
+    }
+
+    /// Asks `peer` what its graph holds of the nodes of `request`
+    async fn neighbors_from(
+        &self,
+        peer: &str,
+        request: NeighborsRequest,
+    ) -> Result<Adjacency, Status> {
+        let unreachable = |status: Status| {
+            Status::unavailable(
+                Error::PartitionUnreachable {
+                    peer: peer.to_string(),
+                    message: status.message().to_string(),
+                }
+                .to_string(),
+            )
+        };
+        let mut client = self.client(peer).await.map_err(unreachable)?;
+        let neighbors = client.neighbors(Request::new(request)).await;
+        self.forget_unavailable(peer, &neighbors);
+        neighbors.map(Response::into_inner).map_err(unreachable)
+    }
+
+    /// Sends `changes` to `peer` over the client kept for it
+    async fn send(&self, peer: &str, changes: Vec<GraphChange>) -> Result<(), Status> {
+        let request = ReplicateRequest {
+            changes,
+            ..Default::default()
+        };
+        let mut client = self.client(peer).await?;
+        let replicated = client.replicate(Request::new(request)).await;
+        self.forget_unavailable(peer, &replicated);
+        replicated.map(drop)
+    }
+
+    /// The client kept for `peer`, connecting to it first if there is none
+    async fn client(&self, peer: &str) -> Result<ReplicationClient<Channel>, Status> {
+        let pooled = self.peers.lock().get(peer).cloned();
+        match pooled {
+            Some(client) => Ok(client),
+            None => {
+                let channel = socket::connect(peer.to_string())
+                    .await
+                    .map_err(|e| Status::unavailable(e.to_string()))?;
+                let client = ReplicationClient::new(channel);
+                self.peers.lock().insert(peer.to_string(), client.clone());
+                Ok(client)
+            }
+        }
+    }
+
+    /// Drops the client of a peer that couldn't be reached, it gets a new connection next time
+    fn forget_unavailable<T>(&self, peer: &str, result: &Result<T, Status>) {
+        if let Err(status) = result {
+            if status.code() == Code::Unavailable {
+                self.peers.lock().remove(peer);
+            }
+        }
+    }
+}
+
+fn to_identifier(uuid: Uuid) -> Identifier {
+    Identifier {
+        uuid: uuid.to_string(),
+    }
+}
+
+fn to_graph_change(change: Change) -> GraphChange {
+    let identifier = |uuid: Uuid| Some(to_identifier(uuid));
+    let (kind, first, second) = match change {
+        Change::AddNode(node) => (graph_change::Kind::AddNode, node, None),
+        Change::RemoveNode(node) => (graph_change::Kind::RemoveNode, node, None),
+        Change::AddEdge(parent, child) => (graph_change::Kind::AddEdge, parent, Some(child)),
+        Change::RemoveEdge(parent, child) => (graph_change::Kind::RemoveEdge, parent, Some(child)),
+    };
+    GraphChange {
+        kind: kind as i32,
+        first: identifier(first),
+        second: second.and_then(identifier),
+    }
+}
+
+/// INVALID_ARGUMENT for a change with an unknown kind or missing identifiers
+fn from_graph_change(change: &GraphChange) -> Result<Change, Status> {
+    let first = parse_identifier(change.first.as_ref().unwrap_or(&Identifier::default()))?;
+    let second = || parse_identifier(change.second.as_ref().unwrap_or(&Identifier::default()));
+    match graph_change::Kind::from_i32(change.kind) {
+        Some(graph_change::Kind::AddNode) => Ok(Change::AddNode(first)),
+        Some(graph_change::Kind::RemoveNode) => Ok(Change::RemoveNode(first)),
+        Some(graph_change::Kind::AddEdge) => Ok(Change::AddEdge(first, second()?)),
+        Some(graph_change::Kind::RemoveEdge) => Ok(Change::RemoveEdge(first, second()?)),
+        None => Err(Status::invalid_argument(
+            Error::UnknownChangeKind { kind: change.kind }.to_string(),
+        )),
+    }
+}
+
+/// FAILED_PRECONDITION carrying the librarian's epoch so that the sender can resynchronise
+fn stale_epoch(current: u64, request: u64) -> Status {
+    status_with_details(
+        Code::FailedPrecondition,
+        Error::StaleMembershipEpoch { current, request }.to_string(),
+        &MembershipEpoch { epoch: current },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::future::FutureExt;
+    use tokio::sync::oneshot;
+    use tokio::sync::oneshot::Sender;
+
+    use super::*;
+    use crate::cluster::Cluster;
+    use neuromancer::base::RunIdentifiers;
+    use neuromancer::librarian::dump_client::DumpClient;
+    use neuromancer::librarian::dump_server::DumpServer;
+    use neuromancer::librarian::export_request::Format;
+    use neuromancer::librarian::job_client::JobClient;
+    use neuromancer::librarian::job_server::JobServer;
+    use neuromancer::router::{redirected_to, LibrarianRouter};
+    use neuromancer::{details_from_status, Checksummable};
+
+    const DEFAULT_STARTUP_TIMEOUT: u64 = 1;
+    const REPLICATION_ADDRESSES: [&str; 3] = ["[::1]:1340", "[::1]:1341", "[::1]:1342"];
+    const QUORUM_ADDRESSES: [&str; 3] = ["[::1]:1343", "[::1]:1344", "[::1]:1345"];
+    const MEMBERSHIP_ADDRESS: &str = "[::1]:1346";
+    const REDIRECT_ADDRESSES: [&str; 2] = ["[::1]:1320", "[::1]:1321"];
+    const SPANNING_ADDRESSES: [&str; 2] = ["[::1]:1318", "[::1]:1319"];
+    const PARTITION_ADDRESSES: [&str; 2] = ["[::1]:1322", "[::1]:1323"];
+    const TRAVERSAL_ADDRESSES: [&str; 2] = ["[::1]:1350", "[::1]:1351"];
+    const OWNER_ADDRESSES: [&str; 3] = ["[::1]:1352", "[::1]:1353", "[::1]:1354"];
+    const IMPORT_ADDRESSES: [&str; 2] = ["[::1]:1357", "[::1]:1358"];
+    const RETENTION_ADDRESSES: [&str; 3] = ["[::1]:1315", "[::1]:1316", "[::1]:1317"];
+    const UNDO_ADDRESSES: [&str; 5] = [
+        "[::1]:1310",
+        "[::1]:1311",
+        "[::1]:1312",
+        "[::1]:1313",
+        "[::1]:1314",
+    ];
+
+    struct Node {
+        address: String,
+        librarian: Librarian,
+        shutdown: Sender<()>,
+        server: tokio::task::JoinHandle<()>,
+    }
+
+    impl Node {
+        async fn stop(self) {
+            self.shutdown.send(()).unwrap();
+            self.server.await.unwrap();
+        }
+    }
+
+    fn members(addresses: &[&str]) -> Vec<String> {
+        addresses
+            .iter()
+            .map(|address| format!("http://{}", address))
+            .collect()
+    }
+
+    /// starts a librarian for every address, all of them knowing about each other
+    async fn gen_cluster(addresses: &[&'static str], replication: usize) -> Vec<Node> {
+        let members = members(addresses);
+        let mut nodes = Vec::new();
+        for (&addr, address) in addresses.iter().zip(&members) {
+            let mut cluster = Cluster::new(address.clone(), replication);
+            cluster.modify_membership(&members, 1);
+            let librarian = Librarian::in_cluster(cluster);
+            let (shutdown, rx) = oneshot::channel::<()>();
+            let service = librarian.clone();
+            let server = tokio::spawn(async move {
+                tonic::transport::Server::builder()
+                    .add_service(JobServer::new(service.clone()))
+                    .add_service(DumpServer::new(service.clone()))
+                    .add_service(ReplicationServer::new(service))
+                    .serve_with_shutdown(addr.parse().unwrap(), rx.map(drop))
+                    .await
+                    .unwrap();
+            });
+            nodes.push(Node {
+                address: address.clone(),
+                librarian,
+                shutdown,
+                server,
+            });
+        }
+        tokio::time::delay_for(Duration::from_millis(DEFAULT_STARTUP_TIMEOUT)).await;
+        nodes
+    }
+
+    fn identifier(uuid: u128) -> Identifier {
+        Identifier {
+            uuid: Uuid::from_u128(uuid).to_string(),
+        }
+    }
+
+    fn remap_request(parent: u128, children: &[u128]) -> RemapRequest {
+        let mut children = RunIdentifiers {
+            run_ids: children.iter().cloned().map(identifier).collect(),
+            ..Default::default()
+        };
+        children.checksum = children.checksum().unwrap();
+        RemapRequest {
+            new_parent: Some(identifier(parent)),
+            children: Some(children),
+            ..Default::default()
+        }
+    }
+
+    /// a batch with an edge from every parent to its child
+    fn edge_batch(edges: &[(u128, u128)]) -> EdgeBatch {
+        let edges = edges
+            .iter()
+            .map(|&(parent, child)| {
+                let mut children = RunIdentifiers {
+                    run_ids: vec![identifier(child)],
+                    ..Default::default()
+                };
+                children.checksum = children.checksum().unwrap();
+                ParentEdges {
+                    parent: Some(identifier(parent)),
+                    children: Some(children),
+                }
+            })
+            .collect();
+        let mut batch = EdgeBatch {
+            edges,
+            ..Default::default()
+        };
+        batch.checksum = batch.checksum().unwrap();
+        batch
+    }
+
+    #[tokio::test]
+    async fn writes_reach_every_replica_and_reads_survive_the_owner() {
+        let mut nodes = gen_cluster(&REPLICATION_ADDRESSES, 3).await;
+        let mut client = JobClient::connect(nodes[0].address.clone()).await.unwrap();
+
+        client
+            .remap(Request::new(remap_request(1, &[2, 3])))
+            .await
+            .unwrap();
+        for node in &nodes {
+            let graph = read_lock!(node.librarian.graph);
+            let mut children: Vec<Uuid> = graph.neighbors(Uuid::from_u128(1)).collect();
+            children.sort();
+            assert_eq!(children, vec![Uuid::from_u128(2), Uuid::from_u128(3)]);
+        }
+
+        let router = LibrarianRouter::new(&members(&REPLICATION_ADDRESSES), 3);
+        let owner = router.replicas(Uuid::from_u128(1))[0].clone();
+        let position = nodes.iter().position(|node| node.address == owner).unwrap();
+        nodes.remove(position).stop().await;
+
+        let response = router.identifiers(identifier(1)).await.unwrap();
+        assert_eq!(response.run_ids.len(), 2);
+
+        for node in nodes {
+            node.stop().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn writes_without_a_quorum_are_unavailable() {
+        let mut nodes = gen_cluster(&QUORUM_ADDRESSES, 3).await;
+        let survivor = nodes.remove(0);
+        for node in nodes {
+            node.stop().await;
+        }
+
+        let mut client = JobClient::connect(survivor.address.clone()).await.unwrap();
+        let err = client
+            .remap(Request::new(remap_request(1, &[2])))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::Unavailable);
+        // a write that failed is nowhere to be seen
+        assert_eq!(read_lock!(survivor.librarian.graph).node_count(), 0);
+
+        survivor.stop().await;
+    }
+
+    #[tokio::test]
+    async fn expired_jobs_are_retained_until_collected() {
+        let mut nodes = gen_cluster(&RETENTION_ADDRESSES, 3).await;
+        let survivor = nodes.remove(0);
+        let job = Uuid::from_u128(1);
+        write_lock!(survivor.librarian.graph).add_node(job);
+        let now = std::time::Instant::now();
+        survivor
+            .librarian
+            .retention
+            .lock()
+            .complete(job, Duration::from_secs(0), now);
+        for node in nodes {
+            node.stop().await;
+        }
+
+        let err = survivor.librarian.collect_expired().await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::Unavailable);
+        assert!(read_lock!(survivor.librarian.graph).contains_node(job));
+        assert_eq!(survivor.librarian.retention.lock().expired(now), vec![job]);
+
+        survivor.stop().await;
+    }
+
+    #[tokio::test]
+    async fn replicas_that_acknowledged_a_failed_write_undo_it() {
+        let mut nodes = gen_cluster(&UNDO_ADDRESSES, 5).await;
+        let mut client = JobClient::connect(nodes[0].address.clone()).await.unwrap();
+        client
+            .remap(Request::new(remap_request(1, &[2])))
+            .await
+            .unwrap();
+        for node in nodes.split_off(2) {
+            node.stop().await;
+        }
+
+        // the coordinator and the one replica that is left are two of the three needed
+        let err = client
+            .remap(Request::new(remap_request(3, &[2])))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::Unavailable);
+        for node in &nodes {
+            let graph = read_lock!(node.librarian.graph);
+            assert!(graph.contains_edge(Uuid::from_u128(1), Uuid::from_u128(2)));
+            assert!(!graph.contains_node(Uuid::from_u128(3)));
+            assert_eq!(graph.edge_count(), 1);
+        }
+
+        for node in nodes {
+            node.stop().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn misrouted_requests_are_redirected_to_the_owner() {
+        let nodes = gen_cluster(&REDIRECT_ADDRESSES, 1).await;
+        let router = LibrarianRouter::new(&members(&REDIRECT_ADDRESSES), 1);
+        let parent = (0..)
+            .find(|&i| router.replicas(Uuid::from_u128(i))[0] == &nodes[1].address)
+            .unwrap();
+
+        let mut owner = JobClient::connect(nodes[1].address.clone()).await.unwrap();
+        owner
+            .remap(Request::new(remap_request(parent, &[parent + 1])))
+            .await
+            .unwrap();
+
+        let mut client = JobClient::connect(nodes[0].address.clone()).await.unwrap();
+        let err = client
+            .identifiers(Request::new(identifier(parent)))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::FailedPrecondition);
+        assert_eq!(redirected_to(&err), Some(nodes[1].address.clone()));
+
+        let batch = edge_batch(&[(parent, parent + 2)]);
+        let err = client
+            .add_edges(Request::new(futures::stream::iter(vec![batch])))
+            .await
+            .unwrap_err();
+        assert_eq!(redirected_to(&err), Some(nodes[1].address.clone()));
+        assert_eq!(read_lock!(nodes[0].librarian.graph).node_count(), 0);
+
+        // a router that only knows about the first librarian still finds the children
+        let stale = LibrarianRouter::new(&members(&REDIRECT_ADDRESSES[..1]), 1);
+        let response = stale.identifiers(identifier(parent)).await.unwrap();
+        assert_eq!(response.run_ids, vec![identifier(parent + 1)]);
+
+        for node in nodes {
+            node.stop().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn batches_spanning_partitions_are_refused() {
+        let nodes = gen_cluster(&SPANNING_ADDRESSES, 1).await;
+        let router = LibrarianRouter::new(&members(&SPANNING_ADDRESSES), 1);
+        let owned_by = |node: &Node| {
+            (100..)
+                .find(|&i| router.replicas(Uuid::from_u128(i))[0] == &node.address)
+                .unwrap()
+        };
+        let (first, second) = (owned_by(&nodes[0]), owned_by(&nodes[1]));
+
+        for node in &nodes {
+            let mut client = JobClient::connect(node.address.clone()).await.unwrap();
+            let batch = edge_batch(&[(first, 1), (second, 2)]);
+            let err = client
+                .add_edges(Request::new(futures::stream::iter(vec![batch])))
+                .await
+                .unwrap_err();
+            assert_eq!(err.code(), tonic::Code::InvalidArgument);
+            assert!(err.message().starts_with("batch 0: "), "{}", err.message());
+            assert_eq!(redirected_to(&err), None);
+        }
+
+        // split by owner, each part is taken by the librarian that holds its parent
+        for (node, parent) in nodes.iter().zip(&[first, second]) {
+            let mut client = JobClient::connect(node.address.clone()).await.unwrap();
+            let batch = edge_batch(&[(*parent, 1)]);
+            client
+                .add_edges(Request::new(futures::stream::iter(vec![batch])))
+                .await
+                .unwrap();
+            let graph = read_lock!(node.librarian.graph);
+            assert_eq!(graph.edge_count(), 1);
+            assert!(graph.contains_edge(Uuid::from_u128(*parent), Uuid::from_u128(1)));
+        }
+
+        for node in nodes {
+            node.stop().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn writes_see_the_edges_of_other_partitions() {
+        let nodes = gen_cluster(&PARTITION_ADDRESSES, 1).await;
+        let router = LibrarianRouter::new(&members(&PARTITION_ADDRESSES), 1);
+        let owned_by = |node: &Node, after: u128| {
+            (after + 1..)
+                .find(|&i| router.replicas(Uuid::from_u128(i))[0] == &node.address)
+                .unwrap()
+        };
+        let a = owned_by(&nodes[0], 100);
+        let b = owned_by(&nodes[1], a);
+        let c = owned_by(&nodes[0], b);
+        let mut clients = Vec::new();
+        for node in &nodes {
+            clients.push(JobClient::connect(node.address.clone()).await.unwrap());
+        }
+        let add = |owner: usize, parent, child| {
+            let batch = edge_batch(&[(parent, child)]);
+            let mut client = clients[owner].clone();
+            async move {
+                client
+                    .add_edges(Request::new(futures::stream::iter(vec![batch])))
+                    .await
+            }
+        };
+
+        // a -> b is held by the first librarian, b -> c by the second
+        add(0, a, b).await.unwrap();
+        add(1, b, c).await.unwrap();
+        let err = add(0, c, a).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::FailedPrecondition);
+        let cycle: Cycle = details_from_status(&err).unwrap();
+        assert_eq!(
+            cycle.path,
+            [c, a, b, c]
+                .iter()
+                .cloned()
+                .map(identifier)
+                .collect::<Vec<_>>()
+        );
+
+        // b's previous parent is held by the other librarian, it still loses its edge
+        let d = owned_by(&nodes[1], c);
+        clients[1]
+            .clone()
+            .remap(Request::new(remap_request(d, &[b])))
+            .await
+            .unwrap();
+        let uuid = Uuid::from_u128;
+        assert!(!read_lock!(nodes[0].librarian.graph).contains_edge(uuid(a), uuid(b)));
+        assert!(read_lock!(nodes[1].librarian.graph).contains_edge(uuid(d), uuid(b)));
+
+        // d is held by the other librarian and points at b as well, so only a can go
+        add(0, a, b).await.unwrap();
+        let now = std::time::Instant::now();
+        nodes[0]
+            .librarian
+            .retention
+            .lock()
+            .complete(uuid(a), Duration::from_secs(0), now);
+        let reclaimed = nodes[0].librarian.collect_expired().await.unwrap();
+        assert_eq!(reclaimed.nodes, 1);
+        assert!(read_lock!(nodes[1].librarian.graph).contains_edge(uuid(b), uuid(c)));
+
+        for node in nodes {
+            node.stop().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn traversals_follow_edges_into_other_partitions() {
+        let mut nodes = gen_cluster(&TRAVERSAL_ADDRESSES, 1).await;
+        let router = LibrarianRouter::new(&members(&TRAVERSAL_ADDRESSES), 1);
+        let owned_by = |node: &Node, after: u128| {
+            (after + 1..)
+                .find(|&i| router.replicas(Uuid::from_u128(i))[0] == &node.address)
+                .unwrap()
+        };
+        let a = owned_by(&nodes[0], 100);
+        let b = owned_by(&nodes[1], a);
+        let c = owned_by(&nodes[0], b);
+        let mut clients = Vec::new();
+        for node in &nodes {
+            clients.push(JobClient::connect(node.address.clone()).await.unwrap());
+        }
+        for &(owner, parent, child) in &[(0, a, b), (1, b, c)] {
+            let batch = edge_batch(&[(parent, child)]);
+            clients[owner]
+                .add_edges(Request::new(futures::stream::iter(vec![batch])))
+                .await
+                .unwrap();
+        }
+        let traversal = |root| TraversalRequest {
+            root: Some(identifier(root)),
+            ..Default::default()
+        };
+        let uuids = |uuids: &[u128]| uuids.iter().cloned().map(identifier).collect::<Vec<_>>();
+
+        let descendants = clients[0]
+            .descendants(Request::new(traversal(a)))
+            .await
+            .unwrap();
+        assert_eq!(descendants.into_inner().run_ids, uuids(&[b, c]));
+        let ancestors = clients[0]
+            .ancestors(Request::new(traversal(c)))
+            .await
+            .unwrap();
+        assert_eq!(ancestors.into_inner().run_ids, uuids(&[b, a]));
+        let lineage = clients[1]
+            .lineage(Request::new(traversal(b)))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(lineage.ancestors, uuids(&[a]));
+        assert_eq!(lineage.descendants, uuids(&[c]));
+        let order = clients[0]
+            .topological_order(Request::new(identifier(a)))
+            .await
+            .unwrap();
+        assert_eq!(order.into_inner().run_ids, uuids(&[a, b, c]));
+
+        // with the rest of the graph out of reach a partial answer would look complete
+        nodes.pop().unwrap().stop().await;
+        let err = clients[0]
+            .descendants(Request::new(traversal(a)))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::Unavailable);
+
+        for node in nodes {
+            node.stop().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn imports_refuse_identifiers_other_partitions_know() {
+        let nodes = gen_cluster(&IMPORT_ADDRESSES, 1).await;
+        let router = LibrarianRouter::new(&members(&IMPORT_ADDRESSES), 1);
+        let owned_by = |node: &Node, after: u128| {
+            (after + 1..)
+                .find(|&i| router.replicas(Uuid::from_u128(i))[0] == &node.address)
+                .unwrap()
+        };
+        let known = owned_by(&nodes[1], 100);
+        write_lock!(nodes[1].librarian.graph).add_node(Uuid::from_u128(known));
+        let mut client = DumpClient::connect(nodes[0].address.clone()).await.unwrap();
+        let dump = |parent: u128, child: u128| {
+            let (parent, child) = (Uuid::from_u128(parent), Uuid::from_u128(child));
+            let contents = format!(
+                "{{\"nodes\": [\"{0}\", \"{1}\"], \"edges\": [[\"{0}\", \"{1}\"]]}}",
+                parent, child
+            );
+            let chunk = GraphDump {
+                format: Format::Json as i32,
+                contents: contents.into_bytes(),
+                ..Default::default()
+            };
+            Request::new(futures::stream::iter(vec![chunk]))
+        };
+
+        // the importing librarian's own graph is empty, the other one's isn't
+        let other = owned_by(&nodes[0], known);
+        let err = client.import(dump(known, other)).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::FailedPrecondition);
+        assert!(err.message().contains(&Uuid::from_u128(known).to_string()));
+        assert_eq!(read_lock!(nodes[0].librarian.graph).node_count(), 0);
+
+        let parent = owned_by(&nodes[1], other);
+        client.import(dump(parent, other)).await.unwrap();
+        assert!(read_lock!(nodes[1].librarian.graph)
+            .contains_edge(Uuid::from_u128(parent), Uuid::from_u128(other)));
+
+        for node in nodes {
+            node.stop().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn outputs_are_tracked_by_the_owner_alone() {
+        let nodes = gen_cluster(&OWNER_ADDRESSES, 2).await;
+        let router = LibrarianRouter::new(&members(&OWNER_ADDRESSES), 2);
+        let node = |address: &String| nodes.iter().position(|node| &node.address == address);
+        let run = 100;
+        let replicas: Vec<usize> = router
+            .replicas(Uuid::from_u128(run))
+            .into_iter()
+            .filter_map(node)
+            .collect();
+        let (owner, replica) = (replicas[0], replicas[1]);
+        // a job held by another librarian, whose collection deletes the run
+        let job = (run + 1..)
+            .find(|&i| router.replicas(Uuid::from_u128(i))[0] != &nodes[owner].address)
+            .unwrap();
+        let job_owner = node(router.replicas(Uuid::from_u128(job))[0]).unwrap();
+
+        let mut request = RegisterOutputRequest {
+            run_id: Some(identifier(run)),
+            location: Some(OutputLocation {
+                executor: "http://[::1]:9001".to_string(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        request.checksum = request.checksum().unwrap();
+        let mut client = JobClient::connect(nodes[replica].address.clone())
+            .await
+            .unwrap();
+        let err = client
+            .register_output(Request::new(request.clone()))
+            .await
+            .unwrap_err();
+        assert_eq!(redirected_to(&err), Some(nodes[owner].address.clone()));
+        let mut client = JobClient::connect(nodes[owner].address.clone())
+            .await
+            .unwrap();
+        client.register_output(Request::new(request)).await.unwrap();
+        let mut client = JobClient::connect(nodes[replica].address.clone())
+            .await
+            .unwrap();
+        let err = client
+            .locate(Request::new(identifier(run)))
+            .await
+            .unwrap_err();
+        assert_eq!(redirected_to(&err), Some(nodes[owner].address.clone()));
+
+        let mut client = JobClient::connect(nodes[job_owner].address.clone())
+            .await
+            .unwrap();
+        let batch = edge_batch(&[(job, run)]);
+        client
+            .add_edges(Request::new(futures::stream::iter(vec![batch])))
+            .await
+            .unwrap();
+        nodes[job_owner].librarian.retention.lock().complete(
+            Uuid::from_u128(job),
+            Duration::from_secs(0),
+            std::time::Instant::now(),
+        );
+        let reclaimed = nodes[job_owner].librarian.collect_expired().await.unwrap();
+        assert_eq!(reclaimed.nodes, 2);
+        let locations = read_lock!(nodes[owner].librarian.locations);
+        assert!(locations.locate(Uuid::from_u128(run)).is_none());
+        drop(locations);
+
+        for node in nodes {
+            node.stop().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn membership_changes_must_be_newer() {
+        let (shutdown, rx) = oneshot::channel::<()>();
+        let librarian = Librarian::default();
+        let server = tokio::spawn(async move {
+            tonic::transport::Server::builder()
+                .add_service(ReplicationServer::new(librarian))
+                .serve_with_shutdown(MEMBERSHIP_ADDRESS.parse().unwrap(), rx.map(drop))
+                .await
+                .unwrap();
+        });
+        tokio::time::delay_for(Duration::from_millis(DEFAULT_STARTUP_TIMEOUT)).await;
+        let mut client = ReplicationClient::connect(format!("http://{}", MEMBERSHIP_ADDRESS))
+            .await
+            .unwrap();
+
+        let membership = |epoch| {
+            let mut request = LibrarianMembership {
+                librarians: vec!["foo".into(), "bar".into()],
+                epoch,
+                ..Default::default()
+            };
+            request.checksum = request.checksum().unwrap();
+            request
+        };
+        client
+            .membership_change(Request::new(membership(2)))
+            .await
+            .unwrap();
+        let err = client
+            .membership_change(Request::new(membership(2)))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::FailedPrecondition);
+        let details: MembershipEpoch = details_from_status(&err).unwrap();
+        assert_eq!(details.epoch, 2);
+
+        shutdown.send(()).unwrap();
+        server.await.unwrap();
+    }
+}
@@ -0,0 +1,208 @@
+use std::collections::BTreeSet;
+
+use petgraph::Direction;
+use tokio::sync::broadcast::{self, RecvError};
+use tokio::sync::mpsc;
+use tonic::Status;
+use uuid::Uuid;
+
+use crate::errors::*;
+use crate::events::{Event, Gap};
+use crate::librarian::{Change, Librarian};
+use crate::lineage;
+use crate::services::parse_identifier;
+use neuromancer::{base::Identifier, librarian::*, read_lock};
+
+/// how many events may be waiting for a slow watcher before the librarian stops reading more
+const WATCH_BUFFER: usize = 64;
+
+/// Subscribes to the librarian's events and forwards the ones within the watched subtree until
+/// the watcher goes away
+pub(crate) fn watch(
+    librarian: &Librarian,
+    request: WatchRequest,
+) -> Result<mpsc::Receiver<Result<GraphEvent, Status>>, Status> {
+    let root = match request.root {
+        Some(root) if !root.uuid.is_empty() => Some(parse_identifier(&root)?),
+        _ => None,
+    };
+
+    let events = librarian.events.lock();
+    let epoch = events.epoch();
+    let (backlog, receiver) = match events.subscribe(request.epoch, request.after) {
+        Ok(subscription) => subscription,
+        Err(Gap::Expired { oldest }) => {
+            return Err(Status::out_of_range(
+                Error::EventsExpired {
+                    after: request.after,
+                    oldest,
+                }
+                .to_string(),
+            ))
+        }
+        Err(Gap::Unknown { last }) => {
+            return Err(Status::out_of_range(
+                Error::UnknownEvent {
+                    after: request.after,
+                    last,
+                }
+                .to_string(),
+            ))
+        }
+        Err(Gap::Restarted { epoch }) => {
+            return Err(Status::out_of_range(
+                Error::EventsRestarted {
+                    epoch: request.epoch,
+                    current: epoch,
+                }
+                .to_string(),
+            ))
+        }
+    };
+    drop(events);
+    let subtree = root.map(|root| Subtree::new(librarian, root));
+
+    let (sender, events) = mpsc::channel(WATCH_BUFFER);
+    tokio::spawn(forward(
+        librarian.clone(),
+        subtree,
+        epoch,
+        backlog,
+        receiver,
+        sender,
+    ));
+    Ok(events)
+}
+
+async fn forward(
+    librarian: Librarian,
+    mut subtree: Option<Subtree>,
+    epoch: u64,
+    backlog: Vec<Event>,
+    mut receiver: broadcast::Receiver<Event>,
+    mut sender: mpsc::Sender<Result<GraphEvent, Status>>,
+) {
+    let mut backlog = backlog.into_iter();
+    loop {
+        let event = match backlog.next() {
+            Some(event) => event,
+            None => match receiver.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(missed)) => {
+                    let status = Status::out_of_range(Error::WatchLagged { missed }.to_string());
+                    let _ = sender.send(Err(status)).await;
+                    return;
+                }
+                Err(RecvError::Closed) => return,
+            },
+        };
+        if let Some(subtree) = &mut subtree {
+            let admitted = subtree.admits(&librarian, event.change);
+            if event.ends_write {
+                subtree.detach(&librarian);
+            }
+            if !admitted {
+                continue;
+            }
+        }
+        let event = graph_event(epoch, event.sequence, event.change);
+        // the watcher went away
+        if sender.send(Ok(event)).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// The nodes below the watched root, kept up to date with the events that go by so that a
+/// deleted node can still be placed. Membership is decided against the graph as it is when an
+/// event is forwarded rather than as it was when the event was published, so a watcher that
+/// lags behind a reparenting may see the moved nodes' events on the side they ended up on.
+/// Nodes cut off by a removed edge are only forgotten once the rest of the write went by
+struct Subtree {
+    root: Uuid,
+    nodes: BTreeSet<Uuid>,
+    /// the children whose edge from within the subtree was removed by the current write
+    cut: Vec<Uuid>,
+}
+
+impl Subtree {
+    fn new(librarian: &Librarian, root: Uuid) -> Self {
+        let graph = read_lock!(librarian.graph);
+        let mut nodes: BTreeSet<Uuid> = lineage::reachable(&graph, root, Direction::Outgoing, 0)
+            .into_iter()
+            .collect();
+        nodes.insert(root);
+        Self {
+            root,
+            nodes,
+            cut: Vec::new(),
+        }
+    }
+
+    fn admits(&mut self, librarian: &Librarian, change: Change) -> bool {
+        match change {
+            Change::AddEdge(parent, child) if self.nodes.contains(&parent) => {
+                let graph = read_lock!(librarian.graph);
+                self.nodes
+                    .extend(lineage::reachable(&graph, child, Direction::Outgoing, 0));
+                self.nodes.insert(child);
+                true
+            }
+            Change::RemoveEdge(parent, child) if self.nodes.contains(&parent) => {
+                self.cut.push(child);
+                true
+            }
+            Change::RemoveNode(node) => self.nodes.remove(&node),
+            _ => false,
+        }
+    }
+
+    /// Forgets the children that were cut off by the write and their descendants unless another
+    /// path still leads to them from the root, the nodes still attached are only looked up once
+    /// per write. Nodes that are already deleted stay until their own event goes by
+    fn detach(&mut self, librarian: &Librarian) {
+        if self.cut.is_empty() {
+            return;
+        }
+        let graph = read_lock!(librarian.graph);
+        let mut attached: BTreeSet<Uuid> =
+            lineage::reachable(&graph, self.root, Direction::Outgoing, 0)
+                .into_iter()
+                .collect();
+        attached.insert(self.root);
+        for child in self.cut.drain(..) {
+            if !graph.contains_node(child) {
+                continue;
+            }
+            let mut detached = lineage::reachable(&graph, child, Direction::Outgoing, 0);
+            detached.push(child);
+            for node in detached {
+                if !attached.contains(&node) {
+                    self.nodes.remove(&node);
+                }
+            }
+        }
+    }
+}
+
+fn graph_event(epoch: u64, sequence: u64, change: Change) -> GraphEvent {
+    let identifier = |uuid: Uuid| {
+        Some(Identifier {
+            uuid: uuid.to_string(),
+        })
+    };
+    let (kind, node, child) = match change {
+        Change::AddEdge(parent, child) => (graph_event::Kind::EdgeAdded, parent, Some(child)),
+        Change::RemoveEdge(parent, child) => (graph_event::Kind::EdgeRemoved, parent, Some(child)),
+        Change::RemoveNode(node) => (graph_event::Kind::NodeDeleted, node, None),
+        Change::AddNode(_) => unreachable!("added nodes are never published"),
+    };
+    GraphEvent {
+        epoch,
+        sequence,
+        kind: kind as i32,
+        node: identifier(node),
+        child: child.and_then(identifier),
+        ..Default::default()
+    }
+}
@@ -0,0 +1,759 @@
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use petgraph::graphmap::DiGraphMap as Graph;
+use uuid::Uuid;
+
+use crate::errors::*;
+use crate::librarian::Change;
+
+const LOG_FILE: &str = "edges.log";
+/// the log that is being folded into a snapshot, replayed before `LOG_FILE` until the snapshot
+/// replaces it
+const COMPACTING_LOG_FILE: &str = "edges.log.compacting";
+const SNAPSHOT_FILE: &str = "graph.snapshot";
+const SNAPSHOT_SCRATCH_FILE: &str = "graph.snapshot.tmp";
+const SNAPSHOT_MAGIC: &[u8; 8] = b"NMSNAP\x00\x01";
+const DURABILITY_VARIABLE: &str = "LIBRARIAN_DURABILITY";
+
+// record count + crc32 of the count
+const FRAME_HEADER_LEN: usize = 4 + 4;
+// tag + two uuids
+const RECORD_LEN: usize = 1 + 16 + 16;
+// crc32 of the records
+const FRAME_TRAILER_LEN: usize = 4;
+
+/// How eagerly the edge log is flushed to disk, a write is only acknowledged after the log
+/// has been written, so this decides how many acknowledged writes a power loss can take with it
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Durability {
+    /// never fsync, the kernel decides when the log reaches the disk
+    Never,
+    /// fsync after every `n` records
+    Batched(usize),
+    /// fsync before every write is acknowledged
+    Always,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Options {
+    pub(crate) durability: Durability,
+    /// the number of records the log may grow to before it is compacted into a snapshot
+    pub(crate) compact_after: usize,
+}
+
+/// Append-only log of graph changes on top of a periodically compacted snapshot of the graph.
+///
+/// The changes of one request are written as a single frame, a count and the records each
+/// covered by their own checksum, so that a request is replayed either whole or not at all.
+/// Replaying a change that is already reflected in the graph is a no-op, so a crash between
+/// writing a snapshot and removing the log it replaces only costs replay time.
+pub(crate) struct Storage {
+    directory: PathBuf,
+    log: File,
+    options: Options,
+    /// records written since the last fsync
+    unsynced: usize,
+    /// records in the log since the last snapshot
+    logged: usize,
+    /// whether a snapshot is being written from a copy of the graph
+    compacting: bool,
+}
+
+/// What replay found at some offset of the log
+enum Frame {
+    Complete {
+        changes: Vec<Change>,
+        len: usize,
+    },
+    /// the end of a write that never completed, which was therefore never acknowledged
+    Torn,
+    Corrupt,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            durability: Durability::Always,
+            compact_after: 1 << 16,
+        }
+    }
+}
+
+impl Options {
+    /// The default options with the durability level taken from `LIBRARIAN_DURABILITY` when it
+    /// is set
+    pub(crate) fn from_env() -> Result<Self> {
+        let mut options = Self::default();
+        if let Ok(durability) = std::env::var(DURABILITY_VARIABLE) {
+            options.durability = durability.parse()?;
+        }
+        Ok(options)
+    }
+}
+
+/// `always`, `never` or `batched:<records>`
+impl FromStr for Durability {
+    type Err = LibrarianError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "always" => Ok(Durability::Always),
+            "never" => Ok(Durability::Never),
+            _ => match s.strip_prefix("batched:").map(str::parse) {
+                Some(Ok(records)) if records > 0 => Ok(Durability::Batched(records)),
+                _ => Err(Error::InvalidDurability {
+                    durability: s.to_string(),
+                }
+                .into()),
+            },
+        }
+    }
+}
+
+impl Storage {
+    /// Opens the storage in `directory`, creating it if needed, and returns the graph that was
+    /// persisted. A torn frame at the end of the log is discarded since it was never
+    /// acknowledged, corruption anywhere else is an error and the log is left as it is.
+    pub(crate) fn open(
+        directory: impl AsRef<Path>,
+        options: Options,
+    ) -> Result<(Self, Graph<Uuid, ()>)> {
+        let directory = directory.as_ref().to_path_buf();
+        fs::create_dir_all(&directory).context(StorageIo { path: &directory })?;
+
+        let mut graph = Self::read_snapshot(&directory.join(SNAPSHOT_FILE))?;
+
+        let compacting_path = directory.join(COMPACTING_LOG_FILE);
+        let compacting = compacting_path.exists();
+        if compacting {
+            let mut log = File::open(&compacting_path).context(StorageIo {
+                path: &compacting_path,
+            })?;
+            replay(&mut log, &compacting_path, &mut graph)?;
+        }
+
+        let log_path = directory.join(LOG_FILE);
+        let mut log = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&log_path)
+            .context(StorageIo { path: &log_path })?;
+        let logged = replay(&mut log, &log_path, &mut graph)?;
+
+        if compacting {
+            // a compaction didn't get to finish, what it would have written is known by now
+            Self::write_snapshot(&directory, &graph)?;
+        }
+
+        let storage = Self {
+            directory,
+            log,
+            options,
+            unsynced: 0,
+            logged,
+            compacting: false,
+        };
+        Ok((storage, graph))
+    }
+
+    /// Appends `changes` to the log, syncing according to the durability level
+    pub(crate) fn append(&mut self, changes: &[Change]) -> Result<()> {
+        self.append_with(changes, |log, buffer| log.write_all(buffer))
+    }
+
+    /// Appends `changes` with `write`. A write that fails, or doesn't reach the disk when it has
+    /// to, is cut off the log again, a torn frame followed by later writes would make the log
+    /// unreadable
+    fn append_with(
+        &mut self,
+        changes: &[Change],
+        write: impl FnOnce(&mut File, &[u8]) -> io::Result<()>,
+    ) -> Result<()> {
+        let buffer = encode(changes);
+        let log_path = self.directory.join(LOG_FILE);
+        let len = self
+            .log
+            .metadata()
+            .context(StorageIo { path: &log_path })?
+            .len();
+        let sync = match self.options.durability {
+            Durability::Never => false,
+            Durability::Batched(n) => self.unsynced + changes.len() >= n,
+            Durability::Always => true,
+        };
+        let written = write(&mut self.log, &buffer).and_then(|()| {
+            if sync {
+                self.log.sync_data()?;
+            }
+            Ok(())
+        });
+        if let Err(e) = written {
+            self.log
+                .set_len(len)
+                .and_then(|()| self.log.seek(SeekFrom::Start(len)))
+                .context(StorageIo { path: &log_path })?;
+            return Err(e).context(StorageIo { path: &log_path })?;
+        }
+        self.logged += changes.len();
+        self.unsynced = if sync {
+            0
+        } else {
+            self.unsynced + changes.len()
+        };
+        Ok(())
+    }
+
+    /// Whether the log has grown enough that it should be folded into a snapshot, and no
+    /// snapshot is being written already
+    pub(crate) fn needs_compaction(&self) -> bool {
+        !self.compacting && self.logged >= self.options.compact_after
+    }
+
+    /// Persists `graph` as the new snapshot and empties the log, `graph` has to reflect every
+    /// change that was appended so far
+    #[cfg(test)]
+    pub(crate) fn compact(&mut self, graph: &Graph<Uuid, ()>) -> Result<()> {
+        self.start_compaction()?;
+        let written = Self::write_snapshot(&self.directory, graph);
+        self.finish_compaction();
+        written
+    }
+
+    /// Sets the log aside to be folded into a snapshot and starts a fresh one. The snapshot has
+    /// to be written from a graph that reflects every change appended so far, with
+    /// `write_snapshot`, after which `finish_compaction` is called whether or not it succeeded
+    pub(crate) fn start_compaction(&mut self) -> Result<()> {
+        let compacting = self.directory.join(COMPACTING_LOG_FILE);
+        let log_path = self.directory.join(LOG_FILE);
+        // a log left over from a snapshot that failed is still waiting to be folded, the current
+        // one is kept alongside it and folded into the same snapshot
+        if !compacting.exists() {
+            self.log
+                .sync_data()
+                .context(StorageIo { path: &log_path })?;
+            fs::rename(&log_path, &compacting).context(StorageIo { path: &compacting })?;
+            self.log = OpenOptions::new()
+                .read(true)
+                .append(true)
+                .create(true)
+                .open(&log_path)
+                .context(StorageIo { path: &log_path })?;
+            sync_directory(&self.directory)?;
+            self.unsynced = 0;
+            self.logged = 0;
+        }
+        self.compacting = true;
+        Ok(())
+    }
+
+    pub(crate) fn finish_compaction(&mut self) {
+        self.compacting = false;
+    }
+
+    pub(crate) fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    /// Persists `graph` as the snapshot in `directory` and removes the log that was set aside
+    /// for it, the storage itself isn't needed so the log can keep growing in the meantime
+    pub(crate) fn write_snapshot(directory: &Path, graph: &Graph<Uuid, ()>) -> Result<()> {
+        let scratch = directory.join(SNAPSHOT_SCRATCH_FILE);
+        let snapshot = directory.join(SNAPSHOT_FILE);
+        {
+            let file = File::create(&scratch).context(StorageIo { path: &scratch })?;
+            let mut writer = BufWriter::new(file);
+            let mut contents = Vec::with_capacity(
+                SNAPSHOT_MAGIC.len() + 16 + graph.node_count() * 16 + graph.edge_count() * 32,
+            );
+            contents.extend_from_slice(SNAPSHOT_MAGIC);
+            contents.extend_from_slice(&(graph.node_count() as u64).to_le_bytes());
+            contents.extend_from_slice(&(graph.edge_count() as u64).to_le_bytes());
+            for node in graph.nodes() {
+                contents.extend_from_slice(node.as_bytes());
+            }
+            for (parent, child, _) in graph.all_edges() {
+                contents.extend_from_slice(parent.as_bytes());
+                contents.extend_from_slice(child.as_bytes());
+            }
+            let crc = crc32fast::hash(&contents);
+            writer
+                .write_all(&contents)
+                .and_then(|_| writer.write_all(&crc.to_le_bytes()))
+                .and_then(|_| writer.flush())
+                .context(StorageIo { path: &scratch })?;
+            writer
+                .get_ref()
+                .sync_all()
+                .context(StorageIo { path: &scratch })?;
+        }
+        fs::rename(&scratch, &snapshot).context(StorageIo { path: &snapshot })?;
+        sync_directory(directory)?;
+
+        let compacting = directory.join(COMPACTING_LOG_FILE);
+        match fs::remove_file(&compacting) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(source) => {
+                return Err(Error::StorageIo {
+                    source,
+                    path: compacting,
+                }
+                .into())
+            }
+        }
+        sync_directory(directory)
+    }
+
+    fn read_snapshot(path: &Path) -> Result<Graph<Uuid, ()>> {
+        let mut graph = Graph::new();
+        let contents = match fs::read(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(graph),
+            Err(source) => {
+                return Err(Error::StorageIo {
+                    source,
+                    path: path.into(),
+                }
+                .into())
+            }
+        };
+
+        let corrupt = || Error::CorruptSnapshot { path: path.into() };
+        let header = SNAPSHOT_MAGIC.len() + 16;
+        if contents.len() < header + 4 || &contents[..SNAPSHOT_MAGIC.len()] != SNAPSHOT_MAGIC {
+            return Err(corrupt().into());
+        }
+        let (body, crc) = contents.split_at(contents.len() - 4);
+        if crc32fast::hash(body) != u32::from_le_bytes(crc.try_into().unwrap()) {
+            return Err(corrupt().into());
+        }
+        let nodes = u64::from_le_bytes(body[8..16].try_into().unwrap()) as usize;
+        let edges = u64::from_le_bytes(body[16..24].try_into().unwrap()) as usize;
+        if body.len() != header + nodes * 16 + edges * 32 {
+            return Err(corrupt().into());
+        }
+
+        let (nodes, edges) = body[header..].split_at(nodes * 16);
+        for node in nodes.chunks_exact(16) {
+            graph.add_node(uuid(node));
+        }
+        for edge in edges.chunks_exact(32) {
+            graph.add_edge(uuid(&edge[..16]), uuid(&edge[16..]), ());
+        }
+        Ok(graph)
+    }
+}
+
+fn uuid(bytes: &[u8]) -> Uuid {
+    Uuid::from_bytes(bytes.try_into().unwrap())
+}
+
+fn sync_directory(directory: &Path) -> Result<()> {
+    File::open(directory)
+        .and_then(|directory| directory.sync_all())
+        .context(StorageIo { path: directory })?;
+    Ok(())
+}
+
+/// Applies every frame of `log` to `graph` and returns the number of records replayed, a torn
+/// frame at the end is cut off so that the log can be appended to again
+fn replay(log: &mut File, path: &Path, graph: &mut Graph<Uuid, ()>) -> Result<usize> {
+    let mut contents = Vec::new();
+    log.read_to_end(&mut contents).context(StorageIo { path })?;
+
+    let mut offset = 0;
+    let mut logged = 0;
+    while offset < contents.len() {
+        match decode(&contents[offset..]) {
+            Frame::Complete { changes, len } => {
+                for change in &changes {
+                    change.apply(graph);
+                }
+                logged += changes.len();
+                offset += len;
+            }
+            Frame::Torn => {
+                log.set_len(offset as u64).context(StorageIo { path })?;
+                log.sync_all().context(StorageIo { path })?;
+                break;
+            }
+            Frame::Corrupt => {
+                return Err(Error::CorruptLog {
+                    path: path.to_path_buf(),
+                    offset: offset as u64,
+                }
+                .into())
+            }
+        }
+    }
+    Ok(logged)
+}
+
+fn encode(changes: &[Change]) -> Vec<u8> {
+    let count = (changes.len() as u32).to_le_bytes();
+    let mut buffer =
+        Vec::with_capacity(FRAME_HEADER_LEN + changes.len() * RECORD_LEN + FRAME_TRAILER_LEN);
+    buffer.extend_from_slice(&count);
+    buffer.extend_from_slice(&crc32fast::hash(&count).to_le_bytes());
+    for change in changes {
+        let (tag, first, second) = match *change {
+            Change::AddNode(node) => (1u8, node, Uuid::nil()),
+            Change::RemoveNode(node) => (2, node, Uuid::nil()),
+            Change::AddEdge(parent, child) => (3, parent, child),
+            Change::RemoveEdge(parent, child) => (4, parent, child),
+        };
+        buffer.push(tag);
+        buffer.extend_from_slice(first.as_bytes());
+        buffer.extend_from_slice(second.as_bytes());
+    }
+    let crc = crc32fast::hash(&buffer[FRAME_HEADER_LEN..]);
+    buffer.extend_from_slice(&crc.to_le_bytes());
+    buffer
+}
+
+/// Decodes the frame at the start of `contents`, which runs to the end of the log. Only a frame
+/// that nothing follows can have been torn, the kernel may also have extended the file with
+/// zeroes that never got their data
+fn decode(contents: &[u8]) -> Frame {
+    if contents.len() < FRAME_HEADER_LEN {
+        return Frame::Torn;
+    }
+    let (count, crc) = contents[..FRAME_HEADER_LEN].split_at(4);
+    if crc32fast::hash(count) != u32::from_le_bytes(crc.try_into().unwrap()) {
+        return if contents.iter().all(|&byte| byte == 0) {
+            Frame::Torn
+        } else {
+            Frame::Corrupt
+        };
+    }
+    let count = u32::from_le_bytes(count.try_into().unwrap()) as usize;
+    let len = FRAME_HEADER_LEN + count * RECORD_LEN + FRAME_TRAILER_LEN;
+    if contents.len() < len {
+        return Frame::Torn;
+    }
+    let (records, crc) = contents[FRAME_HEADER_LEN..len].split_at(count * RECORD_LEN);
+    if crc32fast::hash(records) != u32::from_le_bytes(crc.try_into().unwrap()) {
+        return if contents.len() == len {
+            Frame::Torn
+        } else {
+            Frame::Corrupt
+        };
+    }
+    let mut changes = Vec::with_capacity(count);
+    for record in records.chunks_exact(RECORD_LEN) {
+        let first = uuid(&record[1..17]);
+        let second = uuid(&record[17..33]);
+        changes.push(match record[0] {
+            1 => Change::AddNode(first),
+            2 => Change::RemoveNode(first),
+            3 => Change::AddEdge(first, second),
+            4 => Change::RemoveEdge(first, second),
+            _ => return Frame::Corrupt,
+        });
+    }
+    Frame::Complete { changes, len }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+    use std::process::{Command, Stdio};
+
+    use super::*;
+
+    const CRASH_DIRECTORY_VARIABLE: &str = "LIBRARIAN_CRASH_DIRECTORY";
+
+    fn options(durability: Durability, compact_after: usize) -> Options {
+        Options {
+            durability,
+            compact_after,
+        }
+    }
+
+    fn edge(i: u128) -> Change {
+        Change::AddEdge(Uuid::from_u128(i), Uuid::from_u128(i + 1))
+    }
+
+    fn write(storage: &mut Storage, graph: &mut Graph<Uuid, ()>, change: Change) {
+        storage.append(&[change]).unwrap();
+        change.apply(graph);
+        if storage.needs_compaction() {
+            storage.compact(graph).unwrap();
+        }
+    }
+
+    #[test]
+    fn parses_durability() {
+        assert_eq!("always".parse::<Durability>().unwrap(), Durability::Always);
+        assert_eq!("never".parse::<Durability>().unwrap(), Durability::Never);
+        assert_eq!(
+            "batched:32".parse::<Durability>().unwrap(),
+            Durability::Batched(32)
+        );
+        assert!("batched:0".parse::<Durability>().is_err());
+        assert!("sometimes".parse::<Durability>().is_err());
+    }
+
+    #[test]
+    fn replays_snapshot_and_log() {
+        let directory = tempfile::tempdir().unwrap();
+        let expected = {
+            let (mut storage, mut graph) =
+                Storage::open(directory.path(), options(Durability::Batched(3), 4)).unwrap();
+            for i in 0..10 {
+                write(&mut storage, &mut graph, edge(i));
+            }
+            write(
+                &mut storage,
+                &mut graph,
+                Change::RemoveEdge(Uuid::from_u128(3), Uuid::from_u128(4)),
+            );
+            write(
+                &mut storage,
+                &mut graph,
+                Change::RemoveNode(Uuid::from_u128(7)),
+            );
+            write(
+                &mut storage,
+                &mut graph,
+                Change::AddNode(Uuid::from_u128(42)),
+            );
+            // the last records never made it into a snapshot
+            assert!(storage.logged > 0);
+            graph
+        };
+
+        let (_, graph) = Storage::open(directory.path(), Options::default()).unwrap();
+
+        assert_eq!(edges(&graph), edges(&expected));
+        assert_eq!(nodes(&graph), nodes(&expected));
+    }
+
+    /// The length of a frame holding `records` changes
+    fn frame_len(records: usize) -> usize {
+        FRAME_HEADER_LEN + records * RECORD_LEN + FRAME_TRAILER_LEN
+    }
+
+    #[test]
+    fn discards_torn_frames() {
+        let directory = tempfile::tempdir().unwrap();
+        {
+            let (mut storage, mut graph) =
+                Storage::open(directory.path(), Options::default()).unwrap();
+            for i in 0..2 {
+                write(&mut storage, &mut graph, edge(i));
+            }
+            // a remap is a single request, it has to survive whole or not at all
+            storage
+                .append(&[
+                    Change::RemoveEdge(Uuid::from_u128(0), Uuid::from_u128(1)),
+                    Change::AddEdge(Uuid::from_u128(5), Uuid::from_u128(1)),
+                ])
+                .unwrap();
+        }
+        let log_path = directory.path().join(LOG_FILE);
+        let log = fs::read(&log_path).unwrap();
+        let acknowledged = 2 * frame_len(1);
+        assert_eq!(log.len(), acknowledged + frame_len(2));
+
+        // every possible point at which the last write could have been interrupted, along with
+        // the kernel having extended the log without writing to it
+        let mut zeroed = log[..acknowledged].to_vec();
+        zeroed.resize(log.len(), 0);
+        let torn = (acknowledged..log.len())
+            .map(|torn| log[..torn].to_vec())
+            .chain(Some(zeroed));
+        for torn in torn {
+            fs::write(&log_path, &torn).unwrap();
+
+            let (mut storage, mut graph) =
+                Storage::open(directory.path(), Options::default()).unwrap();
+            assert_eq!(edges(&graph), vec![edge_of(0), edge_of(1)]);
+            assert_eq!(fs::metadata(&log_path).unwrap().len(), acknowledged as u64);
+
+            // the log has to be appendable after the torn frame was dropped
+            write(&mut storage, &mut graph, edge(10));
+            drop(storage);
+            let (_, graph) = Storage::open(directory.path(), Options::default()).unwrap();
+            assert_eq!(graph.edge_count(), 3);
+            assert!(graph.contains_edge(Uuid::from_u128(10), Uuid::from_u128(11)));
+        }
+    }
+
+    #[test]
+    fn failed_writes_are_cut_off_the_log() {
+        let directory = tempfile::tempdir().unwrap();
+        let (mut storage, mut graph) =
+            Storage::open(directory.path(), options(Durability::Always, 1 << 10)).unwrap();
+        write(&mut storage, &mut graph, edge(1));
+
+        // half of the frame makes it to the file before the disk fills up
+        let err = storage
+            .append_with(&[edge(3), edge(5)], |log, buffer| {
+                log.write_all(&buffer[..buffer.len() / 2])?;
+                Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "no space left on device",
+                ))
+            })
+            .unwrap_err();
+        assert!(err.to_string().contains("no space left"), "{}", err);
+        write(&mut storage, &mut graph, edge(7));
+        drop(storage);
+
+        let (_, replayed) =
+            Storage::open(directory.path(), options(Durability::Always, 1 << 10)).unwrap();
+        assert_eq!(edges(&replayed), vec![edge_of(1), edge_of(7)]);
+    }
+
+    #[test]
+    fn refuses_to_replay_past_corruption() {
+        let directory = tempfile::tempdir().unwrap();
+        {
+            let (mut storage, mut graph) =
+                Storage::open(directory.path(), Options::default()).unwrap();
+            for i in 0..3 {
+                write(&mut storage, &mut graph, edge(i));
+            }
+        }
+        let log_path = directory.path().join(LOG_FILE);
+        let log = fs::read(&log_path).unwrap();
+
+        // a flipped bit in the header and in the records of the first of three frames
+        for &corrupt in &[1, FRAME_HEADER_LEN + 1] {
+            let mut damaged = log.clone();
+            damaged[corrupt] ^= 0x01;
+            fs::write(&log_path, &damaged).unwrap();
+
+            assert!(Storage::open(directory.path(), Options::default()).is_err());
+            // the acknowledged frames after it are left for someone to look at
+            assert_eq!(fs::read(&log_path).unwrap(), damaged);
+        }
+    }
+
+    #[test]
+    fn replays_a_compaction_that_did_not_finish() {
+        let directory = tempfile::tempdir().unwrap();
+        {
+            let (mut storage, mut graph) =
+                Storage::open(directory.path(), Options::default()).unwrap();
+            write(&mut storage, &mut graph, edge(0));
+            storage.start_compaction().unwrap();
+            // the snapshot was never written
+            write(&mut storage, &mut graph, edge(1));
+        }
+        assert!(directory.path().join(COMPACTING_LOG_FILE).exists());
+
+        let (_, graph) = Storage::open(directory.path(), Options::default()).unwrap();
+
+        assert_eq!(edges(&graph), vec![edge_of(0), edge_of(1)]);
+        assert!(!directory.path().join(COMPACTING_LOG_FILE).exists());
+        let (_, graph) = Storage::open(directory.path(), Options::default()).unwrap();
+        assert_eq!(edges(&graph), vec![edge_of(0), edge_of(1)]);
+    }
+
+    #[test]
+    fn rejects_corrupt_snapshot() {
+        let directory = tempfile::tempdir().unwrap();
+        {
+            let (mut storage, mut graph) =
+                Storage::open(directory.path(), options(Durability::Always, 1)).unwrap();
+            write(&mut storage, &mut graph, edge(0));
+        }
+        let snapshot_path = directory.path().join(SNAPSHOT_FILE);
+        let mut snapshot = fs::read(&snapshot_path).unwrap();
+        snapshot[SNAPSHOT_MAGIC.len() + 16] ^= 0xff;
+        fs::write(&snapshot_path, snapshot).unwrap();
+
+        assert!(Storage::open(directory.path(), Options::default()).is_err());
+    }
+
+    /// Not a test on its own, `survives_being_killed_mid_write` runs it in a child process
+    /// and kills it while it's writing
+    #[test]
+    #[ignore]
+    fn crash_writer() {
+        let directory = match std::env::var(CRASH_DIRECTORY_VARIABLE) {
+            Ok(directory) => directory,
+            Err(_) => return,
+        };
+        let (mut storage, mut graph) =
+            Storage::open(directory, options(Durability::Always, 50)).unwrap();
+        let stdout = std::io::stdout();
+        for i in 0.. {
+            write(&mut storage, &mut graph, edge(i * 2));
+            // the write has been acknowledged
+            writeln!(stdout.lock(), "ack {}", i).unwrap();
+        }
+    }
+
+    #[test]
+    fn survives_being_killed_mid_write() {
+        let directory = tempfile::tempdir().unwrap();
+        for round in 0..3 {
+            let mut child = Command::new(std::env::current_exe().unwrap())
+                .args([
+                    "--exact",
+                    "storage::tests::crash_writer",
+                    "--ignored",
+                    "--nocapture",
+                ])
+                .env(CRASH_DIRECTORY_VARIABLE, directory.path())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .unwrap();
+            let mut acknowledged = None;
+            let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+            for line in &mut lines {
+                let line = line.unwrap();
+                if let Some(ack) = line.strip_prefix("ack ") {
+                    acknowledged = Some(ack.parse::<u128>().unwrap());
+                    // vary the point at which the writer dies
+                    if acknowledged >= Some(100 + round * 37) {
+                        break;
+                    }
+                }
+            }
+            child.kill().unwrap();
+            child.wait().unwrap();
+            // anything printed after the kill was still acknowledged before it
+            for line in lines {
+                if let Some(ack) = line.unwrap().strip_prefix("ack ") {
+                    acknowledged = Some(ack.parse().unwrap());
+                }
+            }
+            let acknowledged = acknowledged.unwrap();
+
+            let (_, graph) = Storage::open(directory.path(), Options::default()).unwrap();
+            // every acknowledged write survived, and whatever survived beyond that is a prefix
+            // of the writes that were attempted
+            let survived = graph.edge_count() as u128;
+            assert!(survived > acknowledged);
+            for i in 0..survived {
+                assert!(graph.contains_edge(Uuid::from_u128(i * 2), Uuid::from_u128(i * 2 + 1)));
+            }
+        }
+    }
+
+    fn edge_of(i: u128) -> (Uuid, Uuid) {
+        (Uuid::from_u128(i), Uuid::from_u128(i + 1))
+    }
+
+    fn edges(graph: &Graph<Uuid, ()>) -> Vec<(Uuid, Uuid)> {
+        let mut edges: Vec<_> = graph.all_edges().map(|(a, b, _)| (a, b)).collect();
+        edges.sort();
+        edges
+    }
+
+    fn nodes(graph: &Graph<Uuid, ()>) -> Vec<Uuid> {
+        let mut nodes: Vec<_> = graph.nodes().collect();
+        nodes.sort();
+        nodes
+    }
+}
@@ -0,0 +1,96 @@
+//! Derives `Hashable` for the messages generated from neuromancer's protos. It's only meant to be
+//! used from within the neuromancer crate, the generated code refers to it through `crate::`
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Field, Fields, Lit, Meta, NestedMeta};
+
+/// the field that carries the checksum, it can't be part of what it is computed from
+const CHECKSUM_FIELD: &str = "checksum";
+
+/// the protobuf tag prost was told the field has, `#[prost(string, tag = "1")]`
+fn prost_tag(field: &Field) -> Option<u32> {
+    field
+        .attrs
+        .iter()
+        .filter(|attr| attr.path.is_ident("prost"))
+        .filter_map(|attr| match attr.parse_meta() {
+            Ok(Meta::List(list)) => Some(list.nested),
+            _ => None,
+        })
+        .flatten()
+        .find_map(|nested| match nested {
+            NestedMeta::Meta(Meta::NameValue(pair)) if pair.path.is_ident("tag") => {
+                match pair.lit {
+                    Lit::Str(tag) => tag.value().parse().ok(),
+                    _ => None,
+                }
+            }
+            _ => None,
+        })
+}
+
+/// Hashes every field but the checksum in declaration order, each led by its protobuf tag. Enums
+/// are left alone since messages only ever hold them as an `i32`
+#[proc_macro_derive(Hashable)]
+pub fn derive_hashable(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let name = &input.ident;
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => fields
+                .named
+                .iter()
+                .filter(|field| matches!(&field.ident, Some(ident) if ident != CHECKSUM_FIELD))
+                .collect::<Vec<_>>(),
+            _ => {
+                return syn::Error::new_spanned(name, "only structs with named fields are hashable")
+                    .to_compile_error()
+                    .into()
+            }
+        },
+        Data::Enum(_) => return TokenStream::new(),
+        Data::Union(_) => {
+            return syn::Error::new_spanned(name, "unions can't be hashable")
+                .to_compile_error()
+                .into()
+        }
+    };
+
+    let mut idents = Vec::with_capacity(fields.len());
+    let mut tags = Vec::with_capacity(fields.len());
+    for field in fields {
+        match prost_tag(field) {
+            Some(tag) => {
+                idents.push(field.ident.as_ref());
+                tags.push(tag);
+            }
+            None => {
+                return syn::Error::new_spanned(field, "hashable fields need a prost tag")
+                    .to_compile_error()
+                    .into()
+            }
+        }
+    }
+
+    let expanded = quote! {
+        impl crate::checksum_impls::NeuromancerMessage for #name {}
+
+        impl crate::Hashable for #name {
+            fn bytes(&self) -> crate::Result<bytes::Bytes> {
+                let mut result = bytes::BytesMut::new();
+                #(
+                    bytes::BufMut::put_u32_le(&mut result, #tags);
+                    crate::checksum_impls::EncodeIntoBuffer::encode_into_buffer(
+                        &self.#idents,
+                        &mut result,
+                    )?;
+                )*
+                Ok(result.freeze())
+            }
+        }
+    };
+    expanded.into()
+}
@@ -0,0 +1,156 @@
+//! Moves the same partition between two sockets on the loopback interface, once streamed as
+//! `ShuffleChunk`s over grpc and once spilled and sent over the bulk channel
+//!
+//! ```text
+//! cargo bench -p neuromancer --bench shuffle
+//! ```
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::thread;
+
+use bytes::BytesMut;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use futures::{stream, Stream, StreamExt};
+use prost::Message;
+use tokio::runtime::Runtime;
+use tonic::{Request, Response, Status};
+
+use neuromancer::base::Map;
+use neuromancer::executor::shuffle_client::ShuffleClient;
+use neuromancer::executor::shuffle_server::{Shuffle, ShuffleServer};
+use neuromancer::executor::{BulkHeader, FetchRequest, ShuffleChunk};
+use neuromancer::socket::{self, bulk, Address, Options};
+use neuromancer::{Algorithm, Checksummable};
+
+const RECORDS: usize = 16 * 1024;
+const VALUE_LEN: usize = 1024;
+/// the same chunk size the executor streams with
+const CHUNK_RECORDS: usize = 512;
+const GRPC_ADDRESS: &str = "[::1]:1340";
+
+fn records() -> Arc<Vec<Map>> {
+    Arc::new(
+        (0..RECORDS)
+            .map(|i| Map {
+                key: format!("key-{}", i).into_bytes(),
+                value: vec![i as u8; VALUE_LEN],
+                ..Default::default()
+            })
+            .collect(),
+    )
+}
+
+struct Partition(Arc<Vec<Map>>);
+
+#[tonic::async_trait]
+impl Shuffle for Partition {
+    type FetchStream =
+        Pin<Box<dyn Stream<Item = Result<ShuffleChunk, Status>> + Send + Sync + 'static>>;
+
+    async fn fetch(&self, _: Request<FetchRequest>) -> Result<Response<Self::FetchStream>, Status> {
+        let records = self.0.clone();
+        let chunks = stream::iter((0..records.len()).step_by(CHUNK_RECORDS)).map(move |offset| {
+            let end = records.len().min(offset + CHUNK_RECORDS);
+            Ok(ShuffleChunk {
+                offset: offset as u64,
+                records: records[offset..end].to_vec(),
+                ..Default::default()
+            })
+        });
+        Ok(Response::new(Box::pin(chunks) as Self::FetchStream))
+    }
+}
+
+/// The partition as the executor spills it, along with its length
+fn spill(records: &[Map]) -> (File, u64) {
+    let file = tempfile::tempfile().unwrap();
+    let mut writer = BufWriter::new(&file);
+    let mut encoded = BytesMut::new();
+    let mut length = 0;
+    for record in records {
+        encoded.clear();
+        record.encode_length_delimited(&mut encoded).unwrap();
+        writer.write_all(&encoded).unwrap();
+        length += encoded.len() as u64;
+    }
+    writer.flush().unwrap();
+    drop(writer);
+    (file, length)
+}
+
+fn serve_bulk(records: &[Map]) -> Address {
+    let (file, length) = spill(records);
+    let mut header = BulkHeader {
+        records: records.len() as u64,
+        length,
+        digest: bulk::digest_file(&file, 0, length, Algorithm::Xxh3).unwrap(),
+        ..Default::default()
+    };
+    header.checksum = header.checksum().unwrap();
+    let listener = bulk::listen(&"[::1]:0".parse().unwrap(), Options::default()).unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || loop {
+        let mut stream = listener.accept().unwrap();
+        let _: FetchRequest = bulk::read_frame(&mut stream).unwrap();
+        bulk::write_frame(&mut stream, &header).unwrap();
+        bulk::send_file(&mut stream, &file, 0, length).unwrap();
+    });
+    addr
+}
+
+fn shuffle(c: &mut Criterion) {
+    let records = records();
+    let bytes: u64 = records.iter().map(|r| r.encoded_len() as u64).sum();
+    let mut group = c.benchmark_group("shuffle");
+    group.throughput(Throughput::Bytes(bytes)).sample_size(20);
+
+    let mut runtime = Runtime::new().unwrap();
+    let partition = Partition(records.clone());
+    // listening before the server is spawned, so that the client can't connect too early
+    let incoming = runtime
+        .enter(|| socket::incoming(GRPC_ADDRESS.parse::<Address>().unwrap(), Options::default()))
+        .unwrap();
+    runtime.spawn(async move {
+        tonic::transport::Server::builder()
+            .add_service(ShuffleServer::new(partition))
+            .serve_with_incoming(incoming)
+            .await
+            .unwrap();
+    });
+    let mut client = runtime
+        .block_on(ShuffleClient::connect(format!("http://{}", GRPC_ADDRESS)))
+        .unwrap();
+    group.bench_function("grpc", |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                let mut chunks = client
+                    .fetch(Request::new(FetchRequest::default()))
+                    .await
+                    .unwrap()
+                    .into_inner();
+                let mut received = 0;
+                while let Some(chunk) = chunks.next().await {
+                    received += chunk.unwrap().records.len();
+                }
+                assert_eq!(received, RECORDS);
+            })
+        })
+    });
+
+    let addr = serve_bulk(&records);
+    group.bench_function("bulk", |b| {
+        b.iter(|| {
+            let file = tempfile::tempfile().unwrap();
+            let header =
+                bulk::fetch(&addr, &Options::default(), FetchRequest::default(), &file).unwrap();
+            assert_eq!(header.records, RECORDS as u64);
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, shuffle);
+criterion_main!(benches);
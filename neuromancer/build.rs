@@ -1,15 +1,40 @@
+use std::path::PathBuf;
+use std::{env, fs};
+
+const PROST_CODEC: &str = "tonic::codec::ProstCodec";
+
 fn main() {
     println!("cargo:rerun-if-changed=./protos/base.proto");
     println!("cargo:rerun-if-changed=./protos/executor.proto");
     println!("cargo:rerun-if-changed=./protos/librarian.proto");
+    println!("cargo:rerun-if-changed=./protos/supervisor.proto");
     tonic_build::configure()
+        .type_attribute(".", "#[derive(neuromancer_derive::Hashable)]")
         .compile(
             &[
                 "./protos/executor.proto",
                 "./protos/librarian.proto",
+                "./protos/supervisor.proto",
                 "./protos/base.proto",
             ],
             &["./protos"],
         )
         .unwrap();
+
+    // tonic_build always emits the prost codec, every generated client and server goes through
+    // the checksum verifying one instead. Packages with services have to name the codec, if
+    // tonic_build ever spells it differently the build fails instead of silently skipping checks
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    for package in &["executor", "librarian", "supervisor"] {
+        let path = out_dir.join(format!("{}.rs", package));
+        let generated = fs::read_to_string(&path).unwrap();
+        assert!(
+            generated.contains(PROST_CODEC),
+            "{} doesn't use {}, its services would skip checksum verification",
+            path.display(),
+            PROST_CODEC
+        );
+        let generated = generated.replace(PROST_CODEC, "crate::checksum::ChecksumCodec");
+        fs::write(&path, generated).unwrap();
+    }
 }
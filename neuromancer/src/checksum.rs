@@ -0,0 +1,426 @@
+//! Checksum verification shared by every service. Interceptors only get to see the metadata of a
+//! request, so the checks happen in the codec that the generated clients and servers use instead:
+//! inbound messages are verified as they are decoded, nested ones included, and outbound messages
+//! that don't carry a checksum yet get one as they are encoded
+//!
+//! Checksums are led by a tag naming the algorithm they were computed with and are verified with
+//! that algorithm, whatever this process sends with. Switching algorithms is done by rolling out a
+//! version that knows the new one first, then setting `NEUROMANCER_CHECKSUM` everywhere
+
+use std::marker::PhantomData;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use prost::Message;
+use tonic::codec::{Codec, DecodeBuf, Decoder, EncodeBuf, Encoder};
+use tonic::Status;
+
+use crate::errors::*;
+use crate::Checksummable;
+
+/// the environment variable naming the algorithm outbound checksums are computed with
+pub const ALGORITHM_VARIABLE: &str = "NEUROMANCER_CHECKSUM";
+
+static ALGORITHM: AtomicU8 = AtomicU8::new(Algorithm::Xxh3 as u8);
+
+/// The algorithms a checksum can be computed with. The discriminant is the tag that leads the
+/// checksum bytes, tags are never reused
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum Algorithm {
+    Xxh3 = 1,
+    Crc32c = 2,
+    Blake3 = 3,
+}
+
+impl Algorithm {
+    pub fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            1 => Some(Algorithm::Xxh3),
+            2 => Some(Algorithm::Crc32c),
+            3 => Some(Algorithm::Blake3),
+            _ => None,
+        }
+    }
+
+    /// how many bytes the digest takes up after the tag
+    pub fn digest_len(self) -> usize {
+        match self {
+            Algorithm::Xxh3 => 8,
+            Algorithm::Crc32c => 4,
+            Algorithm::Blake3 => blake3::OUT_LEN,
+        }
+    }
+
+    /// The tagged checksum of `bytes`, digests are little endian so that every platform agrees
+    pub fn checksum(self, bytes: &[u8]) -> Vec<u8> {
+        let mut checksum = Vec::with_capacity(1 + self.digest_len());
+        checksum.push(self as u8);
+        match self {
+            Algorithm::Xxh3 => {
+                checksum.extend_from_slice(&xxhash_rust::xxh3::xxh3_64(bytes).to_le_bytes())
+            }
+            Algorithm::Crc32c => checksum.extend_from_slice(&crc32c::crc32c(bytes).to_le_bytes()),
+            Algorithm::Blake3 => checksum.extend_from_slice(blake3::hash(bytes).as_bytes()),
+        }
+        checksum
+    }
+}
+
+/// A tagged checksum of bytes that arrive in pieces, the same as `Algorithm::checksum` of all of
+/// them at once
+pub struct Digest {
+    state: DigestState,
+}
+
+enum DigestState {
+    Xxh3(Box<xxhash_rust::xxh3::Xxh3>),
+    Crc32c(u32),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Digest {
+    pub fn new(algorithm: Algorithm) -> Self {
+        let state = match algorithm {
+            Algorithm::Xxh3 => DigestState::Xxh3(Box::default()),
+            Algorithm::Crc32c => DigestState::Crc32c(0),
+            Algorithm::Blake3 => DigestState::Blake3(Box::default()),
+        };
+        Self { state }
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        match &mut self.state {
+            DigestState::Xxh3(hasher) => hasher.update(bytes),
+            DigestState::Crc32c(crc) => *crc = crc32c::crc32c_append(*crc, bytes),
+            DigestState::Blake3(hasher) => {
+                hasher.update(bytes);
+            }
+        }
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        match self.state {
+            DigestState::Xxh3(hasher) => tagged(Algorithm::Xxh3, &hasher.digest().to_le_bytes()),
+            DigestState::Crc32c(crc) => tagged(Algorithm::Crc32c, &crc.to_le_bytes()),
+            DigestState::Blake3(hasher) => tagged(Algorithm::Blake3, hasher.finalize().as_bytes()),
+        }
+    }
+}
+
+fn tagged(algorithm: Algorithm, digest: &[u8]) -> Vec<u8> {
+    let mut checksum = Vec::with_capacity(1 + digest.len());
+    checksum.push(algorithm as u8);
+    checksum.extend_from_slice(digest);
+    checksum
+}
+
+/// `xxh3`, `crc32c` or `blake3`
+impl FromStr for Algorithm {
+    type Err = NeuromancerError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "xxh3" => Ok(Algorithm::Xxh3),
+            "crc32c" => Ok(Algorithm::Crc32c),
+            "blake3" => Ok(Algorithm::Blake3),
+            _ => Err(Error::UnknownChecksumAlgorithm {
+                algorithm: s.to_string(),
+            }
+            .into()),
+        }
+    }
+}
+
+/// The algorithm outbound checksums are computed with, xxh3 unless configured otherwise
+pub fn algorithm() -> Algorithm {
+    Algorithm::from_tag(ALGORITHM.load(Ordering::Relaxed)).unwrap_or(Algorithm::Xxh3)
+}
+
+pub fn set_algorithm(algorithm: Algorithm) {
+    ALGORITHM.store(algorithm as u8, Ordering::Relaxed);
+}
+
+/// Sends checksums with the algorithm named by `NEUROMANCER_CHECKSUM` when it is set
+pub fn configure_from_env() -> Result<()> {
+    if let Ok(algorithm) = std::env::var(ALGORITHM_VARIABLE) {
+        set_algorithm(algorithm.parse()?);
+    }
+    Ok(())
+}
+
+/// Access to the checksum a message carries. Messages without a checksum field return `None`
+/// from `checksum_field` and are never asked for the checksum of their payload
+pub trait Checksummed {
+    fn checksum_field(&self) -> Option<&[u8]>;
+
+    fn set_checksum_field(&mut self, checksum: Vec<u8>);
+
+    /// the checksum of everything but the checksum field
+    fn payload_checksum(&self, algorithm: Algorithm) -> Result<Vec<u8>>;
+
+    /// The nested messages that carry a checksum of their own. The checksum of this message
+    /// leaves theirs out, so they are verified and filled in along with it
+    fn nested(&self) -> Vec<&dyn Checksummed> {
+        Vec::new()
+    }
+
+    fn nested_mut(&mut self) -> Vec<&mut dyn Checksummed> {
+        Vec::new()
+    }
+}
+
+/// Verifies the checksum `message` carries and those of the messages nested in it, the way the
+/// codec does with every message it decodes
+pub fn verify_message(message: &dyn Checksummed) -> Result<(), Status> {
+    if let Some(checksum) = message.checksum_field() {
+        verify_with(checksum, |algorithm| message.payload_checksum(algorithm))?;
+    }
+    message.nested().into_iter().try_for_each(verify_message)
+}
+
+/// Fills in the checksum of `message` and of the messages nested in it where the sender didn't
+fn fill_checksums(message: &mut dyn Checksummed, algorithm: Algorithm) -> Result<()> {
+    for nested in message.nested_mut() {
+        fill_checksums(nested, algorithm)?;
+    }
+    if let Some(&[]) = message.checksum_field() {
+        let checksum = message.payload_checksum(algorithm)?;
+        message.set_checksum_field(checksum);
+    }
+    Ok(())
+}
+
+/// OUT_OF_RANGE for a truncated checksum, INVALID_ARGUMENT when it doesn't match the payload or
+/// was computed with an algorithm this process doesn't know about
+pub fn verify_checksum(message: &impl Checksummable, checksum: &[u8]) -> Result<(), Status> {
+    verify_with(checksum, |algorithm| message.checksum_with(algorithm))
+}
+
+/// The checksum of `message` as it's sent over the wire, ABORTED when it can't be computed
+pub fn checksum_of(message: &impl Checksummable) -> Result<Vec<u8>, Status> {
+    match message.checksum() {
+        Ok(checksum) => Ok(checksum),
+        Err(e) => Err(Status::aborted(e.to_string())),
+    }
+}
+
+/// Like `verify_checksum` for a checksum of anything but a message, `compute` is handed the
+/// algorithm that `checksum` names
+pub fn verify_with(
+    checksum: &[u8],
+    compute: impl FnOnce(Algorithm) -> Result<Vec<u8>>,
+) -> Result<(), Status> {
+    let algorithm = match checksum.first() {
+        Some(&tag) => match Algorithm::from_tag(tag) {
+            Some(algorithm) => algorithm,
+            None => {
+                return Err(Status::invalid_argument(
+                    Error::UnknownChecksumTag { tag }.to_string(),
+                ))
+            }
+        },
+        None => {
+            return Err(Status::out_of_range(
+                Error::ChecksumLengthMismatch.to_string(),
+            ))
+        }
+    };
+    if checksum.len() != 1 + algorithm.digest_len() {
+        return Err(Status::out_of_range(
+            Error::ChecksumLengthMismatch.to_string(),
+        ));
+    }
+
+    match compute(algorithm) {
+        Ok(computed) if computed != checksum => Err(Status::invalid_argument(
+            Error::ChecksumMismatch {
+                computed: hex(&computed),
+                request: hex(checksum),
+            }
+            .to_string(),
+        )),
+        Err(e) => Err(Status::aborted(e.to_string())),
+        Ok(_) => Ok(()), // all is well
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// A prost codec that verifies the checksum of every message it decodes and fills in the
+/// checksum of every message it encodes, unless the sender already did
+#[derive(Debug, Clone)]
+pub struct ChecksumCodec<T, U> {
+    _pd: PhantomData<(T, U)>,
+}
+
+impl<T, U> Default for ChecksumCodec<T, U> {
+    fn default() -> Self {
+        Self { _pd: PhantomData }
+    }
+}
+
+impl<T, U> Codec for ChecksumCodec<T, U>
+where
+    T: Message + Checksummed + 'static,
+    U: Message + Checksummed + Default + 'static,
+{
+    type Encode = T;
+    type Decode = U;
+
+    type Encoder = ChecksumEncoder<T>;
+    type Decoder = ChecksumDecoder<U>;
+
+    fn encoder(&mut self) -> Self::Encoder {
+        ChecksumEncoder(PhantomData)
+    }
+
+    fn decoder(&mut self) -> Self::Decoder {
+        ChecksumDecoder(PhantomData)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ChecksumEncoder<T>(PhantomData<T>);
+
+impl<T: Message + Checksummed> Encoder for ChecksumEncoder<T> {
+    type Item = T;
+    type Error = Status;
+
+    fn encode(&mut self, mut item: T, buf: &mut EncodeBuf<'_>) -> Result<(), Status> {
+        if let Err(e) = fill_checksums(&mut item, algorithm()) {
+            return Err(Status::aborted(e.to_string()));
+        }
+        item.encode(buf)
+            .expect("Message only errors if not enough space");
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ChecksumDecoder<U>(PhantomData<U>);
+
+impl<U: Message + Checksummed + Default> Decoder for ChecksumDecoder<U> {
+    type Item = U;
+    type Error = Status;
+
+    fn decode(&mut self, buf: &mut DecodeBuf<'_>) -> Result<Option<U>, Status> {
+        let item = match U::decode(buf) {
+            Ok(item) => item,
+            Err(e) => return Err(Status::internal(e.to_string())),
+        };
+        verify_message(&item)?;
+        Ok(Some(item))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::base::{Identifier, RunIdentifiers};
+    use crate::librarian::{EdgeBatch, ParentEdges, RemapRequest};
+
+    #[test]
+    fn only_messages_with_a_checksum_field_are_checked() {
+        let mut identifiers = RunIdentifiers::default();
+        let checksum = identifiers.payload_checksum(Algorithm::Xxh3).unwrap();
+        assert_eq!(
+            verify_checksum(&identifiers, &checksum[..4])
+                .unwrap_err()
+                .code(),
+            tonic::Code::OutOfRange
+        );
+        let mut corrupt = checksum.clone();
+        corrupt[1] ^= 1;
+        assert_eq!(
+            verify_checksum(&identifiers, &corrupt).unwrap_err().code(),
+            tonic::Code::InvalidArgument
+        );
+
+        identifiers.set_checksum_field(checksum_of(&identifiers).unwrap());
+        assert!(verify_checksum(&identifiers, identifiers.checksum_field().unwrap()).is_ok());
+
+        assert!(Identifier::default().checksum_field().is_none());
+    }
+
+    #[test]
+    fn checksums_are_verified_with_the_algorithm_they_are_tagged_with() {
+        let identifier = Identifier {
+            uuid: "foo".to_string(),
+        };
+        for &algorithm in &[Algorithm::Xxh3, Algorithm::Crc32c, Algorithm::Blake3] {
+            let checksum = identifier.checksum_with(algorithm).unwrap();
+            assert_eq!(checksum[0], algorithm as u8);
+            assert_eq!(checksum.len(), 1 + algorithm.digest_len());
+            assert!(verify_checksum(&identifier, &checksum).is_ok());
+        }
+        // stable across processes
+        assert_eq!(
+            identifier.checksum_with(Algorithm::Crc32c).unwrap()[1..],
+            crc32c::crc32c(b"\x01\0\0\0\x03\0\0\0\0\0\0\0foo").to_le_bytes()
+        );
+
+        let unknown = [0xff, 0, 0, 0, 0];
+        assert_eq!(
+            verify_checksum(&identifier, &unknown).unwrap_err().code(),
+            tonic::Code::InvalidArgument
+        );
+        assert!("md5".parse::<Algorithm>().is_err());
+        assert_eq!("blake3".parse::<Algorithm>().unwrap(), Algorithm::Blake3);
+    }
+
+    #[test]
+    fn nested_checksums_are_filled_and_verified() {
+        let children = RunIdentifiers {
+            run_ids: vec![Identifier {
+                uuid: "foo".to_string(),
+            }],
+            ..Default::default()
+        };
+        let mut batch = EdgeBatch {
+            edges: vec![ParentEdges {
+                parent: None,
+                children: Some(children),
+            }],
+            ..Default::default()
+        };
+        fill_checksums(&mut batch, Algorithm::Xxh3).unwrap();
+        assert!(!batch.checksum.is_empty());
+        assert!(verify_message(&batch).is_ok());
+
+        // the batch's checksum leaves out the children's, only theirs catches a corrupt one
+        let children = batch.edges[0].children.as_mut().unwrap();
+        children.checksum[1] ^= 1;
+        assert!(verify_checksum(&batch, &batch.checksum).is_ok());
+        assert_eq!(
+            verify_message(&batch).unwrap_err().code(),
+            tonic::Code::InvalidArgument
+        );
+
+        let mut remap = RemapRequest {
+            new_parent: None,
+            children: batch.edges[0].children.take(),
+            ..Default::default()
+        };
+        assert!(verify_message(&remap).is_err());
+        remap.children.as_mut().unwrap().checksum.clear();
+        fill_checksums(&mut remap, Algorithm::Crc32c).unwrap();
+        assert!(verify_message(&remap).is_ok());
+        remap.new_parent = Some(Identifier::default());
+        assert!(verify_message(&remap).is_err());
+    }
+
+    #[test]
+    fn digests_match_checksums_of_the_whole() {
+        let bytes: Vec<u8> = (0..10_000).map(|i| (i % 251) as u8).collect();
+        for &algorithm in &[Algorithm::Xxh3, Algorithm::Crc32c, Algorithm::Blake3] {
+            let mut digest = Digest::new(algorithm);
+            for piece in bytes.chunks(999) {
+                digest.update(piece);
+            }
+            assert_eq!(digest.finish(), algorithm.checksum(&bytes));
+        }
+    }
+}
@@ -1,27 +1,38 @@
-use std::mem;
+use bytes::{BufMut, BytesMut};
 
-use bytes::{Buf, BufMut, Bytes, BytesMut};
-use prost::Message;
-
-use super::{base, executor, Hashable};
+use super::{base, executor, librarian, supervisor};
+use crate::checksum::{Algorithm, Checksummed};
 use crate::errors::*;
+use crate::{Checksummable, Hashable};
 
-trait EncodeIntoBuffer {
+/// How a field of a message is hashed, `#[derive(Hashable)]` goes through it for every field but
+/// the checksum, leading each one with its protobuf tag.
+///
+/// The encoding is canonical and unambiguous so that distinct messages never hash the same
+/// bytes: scalars are fixed width and little endian, strings and bytes are prefixed with their
+/// length, repeated fields with their count, optional messages with whether they are present
+/// and nested messages with the length of their own encoding. Every process has to agree on it,
+/// changing it changes every checksum on the wire
+pub(crate) trait EncodeIntoBuffer {
     fn encode_into_buffer(&self, buffer: &mut BytesMut) -> Result<()>;
 }
 
 // Vec<T>, Option<T>, and T blanket impls don't fully overlap, forcing us to use a marker trait
-// to please the trait solver
-trait NeuromancerMessage: Message {}
+// to please the trait solver. `#[derive(Hashable)]` implements it for every generated message
+pub(crate) trait NeuromancerMessage: Hashable {}
+
+fn put_length(length: usize, buffer: &mut BytesMut) {
+    buffer.put_u64_le(length as u64);
+}
 
 impl<T> EncodeIntoBuffer for T
 where
     T: NeuromancerMessage,
 {
     fn encode_into_buffer(&self, buffer: &mut BytesMut) -> Result<()> {
-        let mut result: Vec<u8> = Vec::with_capacity(mem::size_of_val(self));
-        self.encode(&mut result).context(ProtobufEncodeError)?;
-        buffer.extend_from_slice(&result);
+        let encoded = self.bytes()?;
+        put_length(encoded.len(), buffer);
+        buffer.extend_from_slice(&encoded);
         Ok(())
     }
 }
@@ -31,13 +42,16 @@ where
     T: NeuromancerMessage,
 {
     fn encode_into_buffer(&self, buffer: &mut BytesMut) -> Result<()> {
-        let mut result: Vec<u8> = Vec::with_capacity(mem::size_of_val(self));
-        match &self {
-            Some(message) => message.encode(&mut result).context(ProtobufEncodeError)?,
-            None => result.extend_from_slice(&[0u8; 0]),
+        match self {
+            Some(message) => {
+                buffer.put_u8(1);
+                message.encode_into_buffer(buffer)
+            }
+            None => {
+                buffer.put_u8(0);
+                Ok(())
+            }
         }
-        buffer.extend_from_slice(&result);
-        Ok(())
     }
 }
 
@@ -46,93 +60,311 @@ where
     T: NeuromancerMessage,
 {
     fn encode_into_buffer(&self, buffer: &mut BytesMut) -> Result<()> {
-        let mut result = BytesMut::new();
+        put_length(self.len(), buffer);
         for message in self.iter() {
-            message.encode(&mut result).context(ProtobufEncodeError)?;
-            buffer.extend_from_slice(&result);
-            result.clear(); // allow for next iteration
+            message.encode_into_buffer(buffer)?;
         }
         Ok(())
     }
 }
 
-impl NeuromancerMessage for base::Identifier {}
-impl NeuromancerMessage for base::Map {}
-impl NeuromancerMessage for executor::ExecutionCommand {}
-
-impl Hashable for base::Map {
-    fn bytes(&self) -> Result<Bytes> {
-        let mut result = BytesMut::new();
-        result.extend_from_slice(self.key.as_bytes());
-        result.extend_from_slice(self.value.as_bytes());
-        Ok(result.freeze())
+impl EncodeIntoBuffer for String {
+    fn encode_into_buffer(&self, buffer: &mut BytesMut) -> Result<()> {
+        put_length(self.len(), buffer);
+        buffer.extend_from_slice(self.as_bytes());
+        Ok(())
     }
 }
 
-impl Hashable for base::Reduction {
-    fn bytes(&self) -> Result<Bytes> {
-        let mut result = BytesMut::new();
-        result.extend(self.key.as_bytes());
-        result.extend(self.values.iter().flat_map(|s| s.as_bytes()));
-        Ok(result.freeze())
+impl EncodeIntoBuffer for Vec<String> {
+    fn encode_into_buffer(&self, buffer: &mut BytesMut) -> Result<()> {
+        put_length(self.len(), buffer);
+        for string in self.iter() {
+            string.encode_into_buffer(buffer)?;
+        }
+        Ok(())
     }
 }
 
-impl Hashable for base::RunIdentifiers {
-    fn bytes(&self) -> Result<Bytes> {
-        let mut result = BytesMut::new();
-        self.run_ids.encode_into_buffer(&mut result)?;
-        Ok(result.freeze())
+impl EncodeIntoBuffer for Vec<u8> {
+    fn encode_into_buffer(&self, buffer: &mut BytesMut) -> Result<()> {
+        put_length(self.len(), buffer);
+        buffer.extend_from_slice(self);
+        Ok(())
     }
 }
 
-impl Hashable for executor::ExecutionCommand {
-    fn bytes(&self) -> Result<Bytes> {
-        let mut result = BytesMut::new();
-        self.run_id.encode_into_buffer(&mut result)?;
-        result.extend_from_slice(&self.program);
-        Ok(result.freeze())
+impl EncodeIntoBuffer for Vec<Vec<u8>> {
+    fn encode_into_buffer(&self, buffer: &mut BytesMut) -> Result<()> {
+        put_length(self.len(), buffer);
+        for bytes in self.iter() {
+            bytes.encode_into_buffer(buffer)?;
+        }
+        Ok(())
     }
 }
 
-impl Hashable for executor::MapRequest {
-    fn bytes(&self) -> Result<Bytes> {
-        let mut result = BytesMut::new();
-        self.command.encode_into_buffer(&mut result)?;
-        self.data.encode_into_buffer(&mut result)?;
-        self.job.encode_into_buffer(&mut result)?;
-        Ok(result.freeze())
+impl EncodeIntoBuffer for u64 {
+    fn encode_into_buffer(&self, buffer: &mut BytesMut) -> Result<()> {
+        buffer.put_u64_le(*self);
+        Ok(())
     }
 }
 
-impl Hashable for executor::ReductionResult {
-    fn bytes(&self) -> Result<Bytes> {
-        let mut result = BytesMut::new();
-        self.run_id.encode_into_buffer(&mut result)?;
-        result.extend_from_slice(self.output.as_bytes());
-        Ok(result.freeze())
+impl EncodeIntoBuffer for u32 {
+    fn encode_into_buffer(&self, buffer: &mut BytesMut) -> Result<()> {
+        buffer.put_u32_le(*self);
+        Ok(())
     }
 }
 
-impl Hashable for executor::RunProgression {
-    fn bytes(&self) -> Result<Bytes> {
-        let mut result = BytesMut::new();
-        result.put_i32_le(self.status);
-        result.put_u64_le(self.time_taken);
-        Ok(result.freeze())
+// enums are held as their discriminant
+impl EncodeIntoBuffer for i32 {
+    fn encode_into_buffer(&self, buffer: &mut BytesMut) -> Result<()> {
+        buffer.put_i32_le(*self);
+        Ok(())
     }
 }
 
-impl Hashable for executor::LibrarianMembershipChangeRequest {
-    fn bytes(&self) -> Result<Bytes> {
-        let mut result = BytesMut::new();
-        result.extend(self.librarians.iter().flat_map(|s| s.as_bytes()));
-        Ok(result.freeze())
-    }
+// a message can be followed by a block of the other `Checksummed` items it overrides, like the
+// nested messages that carry a checksum of their own
+macro_rules! checksummed {
+    ($($message:ty $({ $($nested:tt)* })?),* $(,)?) => {$(
+        impl Checksummed for $message {
+            fn checksum_field(&self) -> Option<&[u8]> {
+                Some(&self.checksum)
+            }
+
+            fn set_checksum_field(&mut self, checksum: Vec<u8>) {
+                self.checksum = checksum;
+            }
+
+            fn payload_checksum(&self, algorithm: Algorithm) -> Result<Vec<u8>> {
+                self.checksum_with(algorithm)
+            }
+
+            $($($nested)*)?
+        }
+    )*};
+}
+
+macro_rules! unchecked {
+    ($($message:ty),* $(,)?) => {$(
+        impl Checksummed for $message {
+            fn checksum_field(&self) -> Option<&[u8]> {
+                None
+            }
+
+            fn set_checksum_field(&mut self, _checksum: Vec<u8>) {}
+
+            fn payload_checksum(&self, _algorithm: Algorithm) -> Result<Vec<u8>> {
+                Ok(Vec::new())
+            }
+        }
+    )*};
 }
 
-impl Hashable for base::Identifier {
-    fn bytes(&self) -> Result<Bytes> {
-        Ok(self.uuid.as_bytes().to_bytes())
+checksummed!(
+    base::Map,
+    base::Reduction,
+    base::RunIdentifiers,
+    executor::ExecutionCommand,
+    executor::MapRequest,
+    executor::ReductionResult,
+    executor::RunProgression,
+    executor::LibrarianMembershipChangeRequest,
+    executor::LibrarianMembershipDelta,
+    executor::Membership,
+    executor::ExecutorState,
+    executor::FetchRequest,
+    executor::ShuffleChunk,
+    executor::BulkHeader,
+    librarian::IdentifiersRequest,
+    librarian::IdentifierChunk,
+    librarian::WatchRequest,
+    librarian::GraphEvent,
+    librarian::RemapRequest {
+        fn nested(&self) -> Vec<&dyn Checksummed> {
+            self.children.iter().map(|children| children as _).collect()
+        }
+
+        fn nested_mut(&mut self) -> Vec<&mut dyn Checksummed> {
+            self.children.iter_mut().map(|children| children as _).collect()
+        }
+    },
+    librarian::EdgeBatch {
+        fn nested(&self) -> Vec<&dyn Checksummed> {
+            self.edges
+                .iter()
+                .filter_map(|edges| edges.children.as_ref())
+                .map(|children| children as _)
+                .collect()
+        }
+
+        fn nested_mut(&mut self) -> Vec<&mut dyn Checksummed> {
+            self.edges
+                .iter_mut()
+                .filter_map(|edges| edges.children.as_mut())
+                .map(|children| children as _)
+                .collect()
+        }
+    },
+    librarian::EdgesAdded,
+    librarian::TraversalRequest,
+    librarian::RunLineage,
+    librarian::RegisterOutputRequest,
+    librarian::OutputLocations,
+    librarian::CompleteJobRequest,
+    librarian::Reclaimed,
+    librarian::ExportRequest,
+    librarian::GraphDump,
+    librarian::ReplicateRequest,
+    librarian::LibrarianMembership,
+    librarian::NeighborsRequest,
+    librarian::Adjacency,
+    supervisor::ExecutorDeparture,
+);
+
+unchecked!((), base::Identifier);
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    // a tiny alphabet so that generated messages share prefixes and splits as often as possible
+    fn text() -> impl Strategy<Value = String> {
+        "[ab]{0,3}"
+    }
+
+    fn texts() -> impl Strategy<Value = Vec<String>> {
+        prop::collection::vec(text(), 0..4)
+    }
+
+    fn blob() -> impl Strategy<Value = Vec<u8>> {
+        prop::collection::vec(0..2u8, 0..3)
+    }
+
+    fn blobs() -> impl Strategy<Value = Vec<Vec<u8>>> {
+        prop::collection::vec(blob(), 0..4)
+    }
+
+    fn identifier() -> impl Strategy<Value = base::Identifier> {
+        text().prop_map(|uuid| base::Identifier { uuid })
+    }
+
+    fn map() -> impl Strategy<Value = base::Map> {
+        (blob(), blob()).prop_map(|(key, value)| base::Map {
+            key,
+            value,
+            ..Default::default()
+        })
+    }
+
+    fn reduction() -> impl Strategy<Value = base::Reduction> {
+        (blob(), blobs()).prop_map(|(key, values)| base::Reduction {
+            key,
+            values,
+            ..Default::default()
+        })
+    }
+
+    fn delta() -> impl Strategy<Value = executor::LibrarianMembershipDelta> {
+        (texts(), 0..3u64).prop_map(|(librarians, epoch)| executor::LibrarianMembershipDelta {
+            librarians,
+            epoch,
+            ..Default::default()
+        })
+    }
+
+    fn run_identifiers() -> impl Strategy<Value = base::RunIdentifiers> {
+        prop::collection::vec(identifier(), 0..4).prop_map(|run_ids| base::RunIdentifiers {
+            run_ids,
+            ..Default::default()
+        })
+    }
+
+    fn fetch() -> impl Strategy<Value = executor::FetchRequest> {
+        (prop::option::of(identifier()), 0..2u32, 0..2u64).prop_map(
+            |(run_id, partition, offset)| executor::FetchRequest {
+                run_id,
+                partition,
+                offset,
+                ..Default::default()
+            },
+        )
+    }
+
+    proptest! {
+        #[test]
+        fn distinct_maps_encode_distinctly(a in map(), b in map()) {
+            prop_assert_eq!(a == b, a.bytes().unwrap() == b.bytes().unwrap());
+        }
+
+        #[test]
+        fn distinct_reductions_encode_distinctly(a in reduction(), b in reduction()) {
+            prop_assert_eq!(a == b, a.bytes().unwrap() == b.bytes().unwrap());
+        }
+
+        #[test]
+        fn distinct_librarian_lists_encode_distinctly(a in delta(), b in delta()) {
+            prop_assert_eq!(a == b, a.bytes().unwrap() == b.bytes().unwrap());
+        }
+
+        #[test]
+        fn distinct_nested_messages_encode_distinctly(
+            a in run_identifiers(),
+            b in run_identifiers(),
+            c in fetch(),
+            d in fetch(),
+        ) {
+            prop_assert_eq!(a == b, a.bytes().unwrap() == b.bytes().unwrap());
+            prop_assert_eq!(c == d, c.bytes().unwrap() == d.bytes().unwrap());
+        }
+    }
+
+    #[test]
+    fn shifting_bytes_between_fields_changes_the_encoding() {
+        let map = |key: &str, value: &str| base::Map {
+            key: key.into(),
+            value: value.into(),
+            ..Default::default()
+        };
+        assert_ne!(
+            map("ab", "c").bytes().unwrap(),
+            map("a", "bc").bytes().unwrap()
+        );
+
+        let reduction = |values: &[&str]| base::Reduction {
+            values: values.iter().map(|&s| s.into()).collect(),
+            ..Default::default()
+        };
+        assert_ne!(
+            reduction(&["ab", "c"]).bytes().unwrap(),
+            reduction(&["a", "bc"]).bytes().unwrap()
+        );
+        assert_ne!(
+            reduction(&[""]).bytes().unwrap(),
+            reduction(&[]).bytes().unwrap()
+        );
+    }
+
+    #[test]
+    fn derived_hashes_skip_the_checksum() {
+        let mut request = executor::FetchRequest {
+            run_id: Some(base::Identifier {
+                uuid: "foo".to_string(),
+            }),
+            partition: 1,
+            offset: 2,
+            ..Default::default()
+        };
+        let checksum = request.checksum().unwrap();
+        request.checksum = vec![1, 2, 3];
+        assert_eq!(request.checksum().unwrap(), checksum);
+
+        request.offset = 3;
+        assert_ne!(request.checksum().unwrap(), checksum);
     }
 }
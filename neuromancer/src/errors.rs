@@ -1,14 +1,38 @@
 use snafu::Snafu;
 
-pub(crate) use snafu::{OptionExt, ResultExt};
+pub(crate) use snafu::ResultExt;
 
 #[derive(Debug, Snafu)]
 #[snafu(visibility = "pub(crate)")]
+#[allow(clippy::enum_variant_names)]
 pub(crate) enum Error {
     #[snafu(display("Error decoding protobuf message: {}", source))]
     ProtobufDecodeError { source: prost::DecodeError },
-    #[snafu(display("Error encoding protobuf message: {}", source))]
-    ProtobufEncodeError { source: prost::EncodeError },
+    #[snafu(display("length mismatch for checksum"))]
+    ChecksumLengthMismatch,
+    #[snafu(display(
+        "checksum mismatch for payload, computed: {} got: {}",
+        computed,
+        request
+    ))]
+    ChecksumMismatch { computed: String, request: String },
+    #[snafu(display("unknown checksum algorithm tag: {}", tag))]
+    UnknownChecksumTag { tag: u8 },
+    #[snafu(display("unknown checksum algorithm: {}", algorithm))]
+    UnknownChecksumAlgorithm { algorithm: String },
+    #[snafu(display("Error encoding record as bincode: {}", source))]
+    BincodeRecordError { source: bincode::Error },
+    #[snafu(display("Error encoding record as JSON: {}", source))]
+    JsonRecordError { source: serde_json::Error },
+    #[snafu(display("Error listening on {}: {}", addr, source))]
+    SocketError {
+        addr: String,
+        source: std::io::Error,
+    },
+    #[snafu(display("Error reading a received payload back: {}", source))]
+    ReadPayload { source: std::io::Error },
+    #[snafu(display("expected a record of type {}, got {}", expected, found))]
+    UnexpectedRecordType { expected: String, found: String },
 }
 
 #[derive(Debug, Snafu)]
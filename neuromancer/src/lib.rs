@@ -1,23 +1,26 @@
-use std::hash::{BuildHasherDefault, Hasher};
+use std::hash::BuildHasherDefault;
 
-use bytes::{Buf, Bytes, BytesMut};
-use lazy_static::lazy_static;
+use bytes::{Bytes, BytesMut};
+use prost::Message;
+use tonic::{Code, Status};
 use wyhash::WyHash;
 
 mod errors;
 
-#[cfg(target_os = "linux")]
+pub mod checksum;
+pub mod membership;
+pub mod record;
+pub mod ring;
+pub mod router;
+#[cfg(unix)]
 pub mod socket;
 
 // boilerplate for Hashable
 mod checksum_impls;
 
+pub use checksum::Algorithm;
 pub use errors::*;
 
-lazy_static! {
-    static ref HASH_SEED: u64 = rand::random();
-}
-
 pub mod base {
     tonic::include_proto!("base");
 }
@@ -30,8 +33,18 @@ pub mod executor {
     tonic::include_proto!("executor");
 }
 
+pub mod supervisor {
+    tonic::include_proto!("supervisor");
+}
+
 pub trait Checksummable {
-    fn checksum(&self) -> Result<u64>;
+    /// the checksum computed with the algorithm this process sends checksums with
+    fn checksum(&self) -> Result<Vec<u8>> {
+        self.checksum_with(checksum::algorithm())
+    }
+
+    /// the checksum bytes as they are sent over the wire, led by the algorithm's tag
+    fn checksum_with(&self, algorithm: Algorithm) -> Result<Vec<u8>>;
 }
 
 pub type DefaultHasher = BuildHasherDefault<WyHash>;
@@ -39,40 +52,32 @@ pub type DefaultHasher = BuildHasherDefault<WyHash>;
 /// abstraction that permits a trait to opt-in to checksumming by
 /// trying to convert the hashable fields into a byte array
 pub trait Hashable {
-    /// returns err for types that wrap a protobuf message
+    /// the canonical encoding of everything but the checksum, see `EncodeIntoBuffer`
     fn bytes(&self) -> Result<Bytes>;
 }
 
 impl<T: Hashable> Checksummable for T {
-    fn checksum(&self) -> Result<u64> {
-        let mut hasher = WyHash::with_seed(*HASH_SEED);
-        hasher.write(&self.bytes()?);
-        Ok(hasher.finish())
+    fn checksum_with(&self, algorithm: Algorithm) -> Result<Vec<u8>> {
+        Ok(algorithm.checksum(&self.bytes()?))
     }
 }
 
-impl Hashable for String {
-    fn bytes(&self) -> Result<Bytes> {
-        Ok(self.as_bytes().to_bytes())
-    }
+/// builds a status that carries `details` as an encoded protobuf message so that the caller
+/// can act on the failure without having to parse the status message
+pub fn status_with_details<M: Message>(
+    code: Code,
+    message: impl Into<String>,
+    details: &M,
+) -> Status {
+    let mut encoded = BytesMut::with_capacity(details.encoded_len());
+    // encoding into a buffer that can grow is infallible
+    let _ = details.encode(&mut encoded);
+    Status::with_details(code, message, encoded.freeze())
 }
 
-impl Hashable for smol_str::SmolStr {
-    fn bytes(&self) -> Result<Bytes> {
-        Ok(self.as_bytes().to_bytes())
-    }
-}
-
-impl<T: Hashable> Hashable for Vec<T> {
-    fn bytes(&self) -> Result<Bytes> {
-        let mut result = BytesMut::new();
-        for item in self.iter() {
-            if let Ok(bytes) = item.bytes() {
-                result.extend(bytes);
-            }
-        }
-        Ok(result.freeze())
-    }
+/// the inverse of `status_with_details`
+pub fn details_from_status<M: Message + Default>(status: &Status) -> Result<M> {
+    Ok(M::decode(status.details()).context(ProtobufDecodeError)?)
 }
 
 #[macro_export]
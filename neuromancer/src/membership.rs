@@ -0,0 +1,48 @@
+/// The epoch of the last membership change a node applied. Executors and librarians version
+/// their membership lists the same way so that both refuse the same stale or replayed changes
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Epoch(Option<u64>);
+
+impl Epoch {
+    /// The epoch that was last applied, 0 while no change has been
+    pub fn current(self) -> u64 {
+        self.0.unwrap_or(0)
+    }
+
+    /// Whether a change sent at `epoch` is newer than the membership that is currently known.
+    /// A fresh node takes any change, including one without an epoch, but once a change has
+    /// been applied only strictly newer ones are, so an unversioned change can't be replayed
+    pub fn is_newer(self, epoch: u64) -> bool {
+        match self.0 {
+            None => true,
+            Some(current) => epoch > current,
+        }
+    }
+
+    pub fn apply(&mut self, epoch: u64) {
+        self.0 = Some(epoch);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_strictly_newer_changes_follow_the_first() {
+        let mut epoch = Epoch::default();
+        assert_eq!(epoch.current(), 0);
+        assert!(epoch.is_newer(0));
+
+        epoch.apply(0);
+        assert!(!epoch.is_newer(0));
+        assert!(epoch.is_newer(1));
+
+        epoch.apply(2);
+        assert_eq!(epoch.current(), 2);
+        for stale in &[0, 1, 2] {
+            assert!(!epoch.is_newer(*stale));
+        }
+        assert!(epoch.is_newer(3));
+    }
+}
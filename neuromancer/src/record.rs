@@ -0,0 +1,229 @@
+//! `Map` and `Reduction` carry their keys and values as opaque bytes, programs pick how their
+//! own types are turned into them by choosing a `RecordCodec` for the keys and one for the
+//! values. Every stage of a job has to agree on the codecs, nothing on the wire says which ones
+//! were used
+
+use bytes::BytesMut;
+use prost::Message;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::base::{Map, Reduction};
+use crate::errors::*;
+
+/// Turns a program's keys or values into the bytes a record carries and back
+pub trait RecordCodec<T> {
+    fn encode(&self, value: &T) -> Result<Vec<u8>>;
+
+    fn decode(&self, bytes: &[u8]) -> Result<T>;
+}
+
+/// Compact and fast, but only readable by programs that share the type definitions
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Bincode;
+
+impl<T: Serialize + DeserializeOwned> RecordCodec<T> for Bincode {
+    fn encode(&self, value: &T) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(value).context(BincodeRecordError)?)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<T> {
+        Ok(bincode::deserialize(bytes).context(BincodeRecordError)?)
+    }
+}
+
+/// Self describing, at the cost of size
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Json;
+
+impl<T: Serialize + DeserializeOwned> RecordCodec<T> for Json {
+    fn encode(&self, value: &T) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(value).context(JsonRecordError)?)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<T> {
+        Ok(serde_json::from_slice(bytes).context(JsonRecordError)?)
+    }
+}
+
+/// Protobuf messages wrapped in an `Any`, decoding refuses messages of any other type
+#[derive(Clone, Debug)]
+pub struct ProtobufAny {
+    type_url: String,
+}
+
+impl ProtobufAny {
+    /// `type_url` names the message, `type.googleapis.com/<package>.<message>` by convention
+    pub fn new(type_url: impl Into<String>) -> Self {
+        Self {
+            type_url: type_url.into(),
+        }
+    }
+}
+
+fn encode_message(message: &impl Message) -> Vec<u8> {
+    let mut encoded = BytesMut::with_capacity(message.encoded_len());
+    // encoding into a buffer that can grow is infallible
+    let _ = message.encode(&mut encoded);
+    encoded.to_vec()
+}
+
+impl<T: Message + Default> RecordCodec<T> for ProtobufAny {
+    fn encode(&self, value: &T) -> Result<Vec<u8>> {
+        Ok(encode_message(&prost_types::Any {
+            type_url: self.type_url.clone(),
+            value: encode_message(value),
+        }))
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<T> {
+        let any = prost_types::Any::decode(bytes).context(ProtobufDecodeError)?;
+        if any.type_url != self.type_url {
+            return Err(Error::UnexpectedRecordType {
+                expected: self.type_url.clone(),
+                found: any.type_url,
+            }
+            .into());
+        }
+        Ok(T::decode(&any.value[..]).context(ProtobufDecodeError)?)
+    }
+}
+
+impl Map {
+    pub fn encode<K, V>(
+        keys: &impl RecordCodec<K>,
+        values: &impl RecordCodec<V>,
+        key: &K,
+        value: &V,
+    ) -> Result<Self> {
+        Ok(Self {
+            key: keys.encode(key)?,
+            value: values.encode(value)?,
+            ..Default::default()
+        })
+    }
+
+    pub fn decode<K, V>(
+        &self,
+        keys: &impl RecordCodec<K>,
+        values: &impl RecordCodec<V>,
+    ) -> Result<(K, V)> {
+        Ok((keys.decode(&self.key)?, values.decode(&self.value)?))
+    }
+}
+
+impl Reduction {
+    pub fn encode<K, V>(
+        keys: &impl RecordCodec<K>,
+        values: &impl RecordCodec<V>,
+        key: &K,
+        reduced: &[V],
+    ) -> Result<Self> {
+        Ok(Self {
+            key: keys.encode(key)?,
+            values: reduced
+                .iter()
+                .map(|value| values.encode(value))
+                .collect::<Result<_>>()?,
+            ..Default::default()
+        })
+    }
+
+    pub fn decode<K, V>(
+        &self,
+        keys: &impl RecordCodec<K>,
+        values: &impl RecordCodec<V>,
+    ) -> Result<(K, Vec<V>)> {
+        let reduced = self
+            .values
+            .iter()
+            .map(|value| values.decode(value))
+            .collect::<Result<_>>()?;
+        Ok((keys.decode(&self.key)?, reduced))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::base::{Identifier, MembershipEpoch};
+
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    struct Visit {
+        page: String,
+        bytes: Vec<u8>,
+    }
+
+    fn visit() -> Visit {
+        Visit {
+            page: "/index".to_string(),
+            // not valid UTF-8
+            bytes: vec![0xff, 0xfe, 0],
+        }
+    }
+
+    #[test]
+    fn serde_codecs_round_trip_typed_records() {
+        let map = Map::encode(&Bincode, &Bincode, &7u64, &visit()).unwrap();
+        assert_eq!(
+            map.decode::<u64, Visit>(&Bincode, &Bincode).unwrap(),
+            (7, visit())
+        );
+
+        let key = "key".to_string();
+        let reduction = Reduction::encode(&Json, &Json, &key, &[visit(), visit()]).unwrap();
+        assert_eq!(
+            reduction.decode::<String, Visit>(&Json, &Json).unwrap(),
+            (key, vec![visit(), visit()])
+        );
+        assert!(reduction.decode::<String, u64>(&Json, &Json).is_err());
+    }
+
+    #[test]
+    fn any_refuses_other_message_types() {
+        let identifier = Identifier {
+            uuid: "foo".to_string(),
+        };
+        let codec = ProtobufAny::new("type.googleapis.com/base.Identifier");
+        let map = Map::encode(&codec, &codec, &identifier, &identifier).unwrap();
+        assert_eq!(
+            map.decode::<Identifier, Identifier>(&codec, &codec)
+                .unwrap(),
+            (identifier.clone(), identifier)
+        );
+
+        let other = ProtobufAny::new("type.googleapis.com/base.MembershipEpoch");
+        assert!(RecordCodec::<Identifier>::decode(&other, &map.key).is_err());
+    }
+
+    #[test]
+    fn keys_and_values_keep_their_own_types() {
+        let identifier = Identifier {
+            uuid: "foo".to_string(),
+        };
+        let epoch = MembershipEpoch { epoch: 3 };
+        let identifiers = ProtobufAny::new("type.googleapis.com/base.Identifier");
+        let epochs = ProtobufAny::new("type.googleapis.com/base.MembershipEpoch");
+
+        let map = Map::encode(&identifiers, &epochs, &identifier, &epoch).unwrap();
+        assert_eq!(
+            map.decode::<Identifier, MembershipEpoch>(&identifiers, &epochs)
+                .unwrap(),
+            (identifier.clone(), epoch.clone())
+        );
+        assert!(map
+            .decode::<Identifier, MembershipEpoch>(&identifiers, &identifiers)
+            .is_err());
+
+        // a serde key can sit next to a protobuf value
+        let reduction = Reduction::encode(&Json, &epochs, &7u64, &[epoch]).unwrap();
+        assert_eq!(
+            reduction
+                .decode::<u64, MembershipEpoch>(&Json, &epochs)
+                .unwrap(),
+            (7, vec![MembershipEpoch { epoch: 3 }])
+        );
+    }
+}
@@ -0,0 +1,274 @@
+use std::collections::BTreeMap;
+
+// every node in the cluster has to agree on where a key lands, so this can never be randomized
+const RING_SEED: u64 = 0x6e65_7572_6f6d_616e;
+
+/// something that can be placed on a `Ring`, the name has to be unique per node and identical
+/// across every process that builds the ring
+pub trait Node: Clone {
+    fn name(&self) -> String;
+}
+
+/// consistent hash ring that can be inspected, the token of every virtual node is derived
+/// from the node's name so two rings with the same members always have the same layout.
+/// conhash keeps its tokens to itself, the executors need to show them and the librarians need
+/// the successors of a key
+pub struct Ring<N: Node> {
+    /// every node on the ring by name, along with its number of virtual nodes
+    nodes: BTreeMap<String, (N, usize)>,
+    tokens: BTreeMap<u64, N>,
+    /// whether a virtual node had to probe past a token that was taken
+    probed: bool,
+}
+
+impl<N: Node> Ring<N> {
+    pub fn new() -> Self {
+        Self {
+            nodes: BTreeMap::new(),
+            tokens: BTreeMap::new(),
+            probed: false,
+        }
+    }
+
+    /// Places `replicas` virtual nodes for `node` on the ring, replacing any that were there.
+    /// The layout only has to be redone when one of them collides with a token that's taken
+    pub fn add(&mut self, node: &N, replicas: usize) {
+        let name = node.name();
+        self.remove(node);
+        self.nodes.insert(name.clone(), (node.clone(), replicas));
+        let tokens: Vec<u64> = (0..replicas)
+            .map(|replica| Self::token(&name, replica))
+            .collect();
+        let mut distinct = tokens.clone();
+        distinct.sort_unstable();
+        distinct.dedup();
+        if distinct.len() != tokens.len() || tokens.iter().any(|t| self.tokens.contains_key(t)) {
+            self.place();
+            return;
+        }
+        for token in tokens {
+            self.tokens.insert(token, node.clone());
+        }
+    }
+
+    /// Removes every virtual node belonging to `node`. The others only move when one of them
+    /// probed past a token, which may have been one of `node`'s
+    pub fn remove(&mut self, node: &N) {
+        let name = node.name();
+        if self.nodes.remove(&name).is_none() {
+            return;
+        }
+        if self.probed {
+            self.place();
+        } else {
+            self.tokens.retain(|_, placed| placed.name() != name);
+        }
+    }
+
+    /// Returns the node that owns `key`, which is the first node clockwise from the key's token.
+    pub fn get(&self, key: &[u8]) -> Option<&N> {
+        let token = Self::hash(key);
+        self.tokens
+            .range(token..)
+            .chain(self.tokens.iter())
+            .next()
+            .map(|(_, node)| node)
+    }
+
+    /// Returns up to `n` distinct nodes in the order they are met walking clockwise from the
+    /// key's token, the first one being the owner of `key`.
+    pub fn successors(&self, key: &[u8], n: usize) -> Vec<&N> {
+        let token = Self::hash(key);
+        let mut result: Vec<&N> = Vec::with_capacity(n);
+        let mut names = Vec::with_capacity(n);
+        let walk = self.tokens.range(token..).chain(self.tokens.range(..token));
+        for (_, node) in walk {
+            if result.len() == n {
+                break;
+            }
+            let name = node.name();
+            if !names.contains(&name) {
+                names.push(name);
+                result.push(node);
+            }
+        }
+        result
+    }
+
+    /// The number of virtual nodes on the ring.
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    /// The virtual nodes in token order.
+    pub fn layout(&self) -> impl Iterator<Item = (u64, &N)> {
+        self.tokens.iter().map(|(token, node)| (*token, node))
+    }
+
+    /// Lays out the virtual nodes of every node from scratch. Nodes are placed in name order so
+    /// that token collisions are settled the same way by every process, whatever order the
+    /// nodes were added in
+    fn place(&mut self) {
+        self.tokens.clear();
+        self.probed = false;
+        for (name, (node, replicas)) in &self.nodes {
+            for replica in 0..*replicas {
+                let token = Self::free_token(&self.tokens, name, replica);
+                self.probed |= token != Self::token(name, replica);
+                self.tokens.insert(token, node.clone());
+            }
+        }
+    }
+
+    /// The token of a virtual node, probing further along its own sequence of tokens for as
+    /// long as the one it lands on is taken
+    fn free_token(tokens: &BTreeMap<u64, N>, name: &str, replica: usize) -> u64 {
+        let mut token = Self::token(name, replica);
+        let mut probe = 0;
+        while tokens.contains_key(&token) {
+            probe += 1;
+            token = Self::hash(format!("{}:{}:{}", name, replica, probe).as_bytes());
+        }
+        token
+    }
+
+    /// the token a virtual node is placed at unless it's taken
+    fn token(name: &str, replica: usize) -> u64 {
+        Self::hash(format!("{}:{}", name, replica).as_bytes())
+    }
+
+    fn hash(bytes: &[u8]) -> u64 {
+        wyhash::wyhash(bytes, RING_SEED)
+    }
+}
+
+impl<N: Node> Default for Ring<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Node for String {
+    fn name(&self) -> String {
+        self.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layout_is_ordered_and_deterministic() {
+        let mut first = Ring::new();
+        let mut second = Ring::new();
+        for name in &["foo", "bar", "baz"] {
+            first.add(&name.to_string(), 2);
+        }
+        for name in &["baz", "foo", "bar"] {
+            second.add(&name.to_string(), 2);
+        }
+
+        let first: Vec<_> = first.layout().collect();
+        let second: Vec<_> = second.layout().collect();
+
+        assert_eq!(first.len(), 6);
+        assert_eq!(first, second);
+        assert!(first.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    }
+
+    #[test]
+    fn tokens_never_change() {
+        // every process of every version has to agree on these, changing them moves every key
+        let mut ring = Ring::new();
+        for name in &["foo", "bar"] {
+            ring.add(&name.to_string(), 2);
+        }
+        let layout: Vec<(u64, &str)> = ring
+            .layout()
+            .map(|(token, node)| (token, node.as_str()))
+            .collect();
+        assert_eq!(
+            layout,
+            vec![
+                (0x38512ebda2dabdc0, "foo"),
+                (0x44206458c5f207f7, "bar"),
+                (0x79d096e6f76c4873, "foo"),
+                (0xb3b6d77194713b56, "bar"),
+            ]
+        );
+    }
+
+    #[test]
+    fn incremental_changes_match_a_fresh_layout() {
+        let mut ring = Ring::new();
+        for name in &["foo", "bar", "baz", "qux"] {
+            ring.add(&name.to_string(), 3);
+        }
+        ring.remove(&"bar".to_string());
+        ring.add(&"baz".to_string(), 5);
+        ring.add(&"quux".to_string(), 1);
+
+        let incremental: Vec<(u64, String)> = ring
+            .layout()
+            .map(|(token, node)| (token, node.clone()))
+            .collect();
+        ring.place();
+        let fresh: Vec<(u64, String)> = ring
+            .layout()
+            .map(|(token, node)| (token, node.clone()))
+            .collect();
+        assert_eq!(incremental.len(), 3 + 5 + 3 + 1);
+        assert_eq!(incremental, fresh);
+    }
+
+    #[test]
+    fn successors_are_distinct_and_start_at_the_owner() {
+        let mut ring = Ring::new();
+        for name in &["foo", "bar", "baz"] {
+            ring.add(&name.to_string(), 4);
+        }
+
+        for key in 0..64u32 {
+            let key = key.to_ne_bytes();
+            let successors = ring.successors(&key, 2);
+            assert_eq!(successors.len(), 2);
+            assert_eq!(Some(successors[0]), ring.get(&key));
+            assert_ne!(successors[0], successors[1]);
+            assert_eq!(ring.successors(&key, 5).len(), 3);
+        }
+    }
+
+    #[test]
+    fn get_wraps_around_and_skips_removed_nodes() {
+        let mut ring = Ring::new();
+        ring.add(&"foo".to_string(), 1);
+        ring.add(&"bar".to_string(), 1);
+        ring.remove(&"foo".to_string());
+
+        for key in 0..64u32 {
+            assert_eq!(ring.get(&key.to_ne_bytes()), Some(&"bar".to_string()));
+        }
+        ring.remove(&"bar".to_string());
+        assert_eq!(ring.get(b"key"), None);
+        assert!(ring.successors(b"key", 3).is_empty());
+        assert!(ring.is_empty());
+    }
+
+    #[test]
+    fn colliding_tokens_are_reprobed() {
+        let taken = Ring::<String>::hash(b"bar:0");
+        let mut tokens = BTreeMap::new();
+        tokens.insert(taken, "foo".to_string());
+
+        let token = Ring::<String>::free_token(&tokens, "bar", 0);
+
+        assert_eq!(token, Ring::<String>::hash(b"bar:0:1"));
+        tokens.clear();
+        assert_eq!(Ring::<String>::free_token(&tokens, "bar", 0), taken);
+    }
+}
@@ -0,0 +1,116 @@
+use bytes::BytesMut;
+use prost::Message;
+use tonic::{Code, Request, Status};
+use uuid::Uuid;
+
+use crate::base::{ErrorInfo, Identifier, RunIdentifiers};
+use crate::librarian::{job_client::JobClient, Redirect};
+use crate::ring::Ring;
+use crate::{details_from_status, socket, status_with_details};
+
+/// how many times a request follows a librarian pointing it to another one before giving up
+const MAX_REDIRECTS: usize = 3;
+
+/// what the `ErrorInfo` wrapping a `Redirect` is tagged with
+const REDIRECT_REASON: &str = "MISROUTED_IDENTIFIER";
+const REDIRECT_DOMAIN: &str = "librarian";
+
+/// Routes reads to the librarians that hold a copy of an identifier. The ring is laid out the
+/// same way the executors and librarians lay theirs out, so the first replica is the owner.
+/// Only the graph is replicated, output locations and complete jobs have to be asked of the
+/// owner
+pub struct LibrarianRouter {
+    ring: Ring<String>,
+    replication: usize,
+}
+
+impl LibrarianRouter {
+    pub fn new(librarians: &[String], replication: usize) -> Self {
+        let mut ring = Ring::new();
+        for librarian in librarians {
+            ring.add(librarian, 1);
+        }
+        Self { ring, replication }
+    }
+
+    /// The addresses of the librarians that hold `uuid`, the owner first.
+    pub fn replicas(&self, uuid: Uuid) -> Vec<&String> {
+        self.ring.successors(uuid.as_bytes(), self.replication)
+    }
+
+    /// Asks the owner of `identifier` for its children, falling back to the other replicas in
+    /// ring order while the librarian asked is unreachable or unavailable. Any other error is
+    /// returned as is since a replica would give the same answer. A librarian that doesn't hold
+    /// the identifier redirects the request to its owner, which happens while the router's
+    /// membership list is out of date
+    pub async fn identifiers(&self, identifier: Identifier) -> Result<RunIdentifiers, Status> {
+        let uuid = match Uuid::parse_str(&identifier.uuid) {
+            Ok(uuid) => uuid,
+            Err(e) => return Err(Status::invalid_argument(e.to_string())),
+        };
+
+        let mut last = Status::unavailable("no librarians are known");
+        for replica in self.replicas(uuid) {
+            match identifiers_from(replica.clone(), &identifier).await {
+                Ok(identifiers) => return Ok(identifiers),
+                Err(status) if status.code() == Code::Unavailable => last = status,
+                Err(status) => return Err(status),
+            }
+        }
+        Err(last)
+    }
+}
+
+/// FAILED_PRECONDITION pointing the caller to `owner`, the librarian that holds the identifier
+/// the request was about as of membership `epoch`
+pub fn redirect(message: impl Into<String>, owner: String, epoch: u64) -> Status {
+    let redirect = Redirect { epoch, owner };
+    let mut details = BytesMut::with_capacity(redirect.encoded_len());
+    // encoding into a buffer that can grow is infallible
+    let _ = redirect.encode(&mut details);
+    let info = ErrorInfo {
+        reason: REDIRECT_REASON.to_string(),
+        domain: REDIRECT_DOMAIN.to_string(),
+        details: details.to_vec(),
+    };
+    status_with_details(Code::FailedPrecondition, message, &info)
+}
+
+/// The owner a librarian redirected a request to, `None` for any other status
+pub fn redirected_to(status: &Status) -> Option<String> {
+    if status.code() != Code::FailedPrecondition {
+        return None;
+    }
+    let info = details_from_status::<ErrorInfo>(status).ok()?;
+    if info.reason != REDIRECT_REASON || info.domain != REDIRECT_DOMAIN {
+        return None;
+    }
+    Redirect::decode(info.details.as_slice())
+        .ok()
+        .map(|redirect| redirect.owner)
+        .filter(|owner| !owner.is_empty())
+}
+
+/// Asks `librarian` for the children of `identifier`, following its redirects
+async fn identifiers_from(
+    mut librarian: String,
+    identifier: &Identifier,
+) -> Result<RunIdentifiers, Status> {
+    for _ in 0..=MAX_REDIRECTS {
+        let mut client = match socket::connect(librarian.clone()).await {
+            Ok(channel) => JobClient::new(channel),
+            Err(e) => return Err(Status::unavailable(e.to_string())),
+        };
+        match client.identifiers(Request::new(identifier.clone())).await {
+            Ok(response) => return Ok(response.into_inner()),
+            Err(status) => match redirected_to(&status) {
+                Some(owner) => librarian = owner,
+                None => return Err(status),
+            },
+        }
+    }
+    Err(Status::failed_precondition(format!(
+        "{} was redirected more than {} times",
+        identifier.uuid, MAX_REDIRECTS
+    )))
+}
@@ -1,5 +1,598 @@
-use tokio::net::TcpStream;
+//! The transport every binary serves its gRPC services over. TCP is tuned for long lived
+//! connections between nodes of the same cluster rather than for the internet at large, services
+//! on the same host can skip it altogether with a `unix://<path>` address. The tuning that only
+//! Linux offers is skipped elsewhere, the rest works on any Unix
+//!
+//! ```ignore
+//! tonic::transport::Server::builder()
+//!     .add_service(service)
+//!     .serve_with_incoming(socket::incoming(address.parse()?, Options::default())?)
+//! ```
 
+use std::fmt;
+use std::fs;
+use std::future::Future;
+use std::net::{self, SocketAddr};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use std::{io, mem};
+
+use futures::{ready, Stream};
+use socket2::{Domain, Protocol, SockAddr, Type};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::time::{delay_for, Delay};
+use tonic::transport::server::Connected;
+use tonic::transport::{Channel, Endpoint, Uri};
+
+use crate::errors::*;
+
+pub mod bulk;
+mod generic;
+
+/// how many connections the kernel queues up before they are accepted
+const BACKLOG: i32 = 1024;
+/// how long accepting pauses after it failed, doubling while it keeps failing. Running out of
+/// descriptors is the usual cause, and retrying right away only spins
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+/// leads the addresses of services reached over a Unix domain socket, the path follows it
+pub const UNIX_SCHEME: &str = "unix://";
+
+/// Where a server listens
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Address {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+/// `unix://<path>` or a socket address such as `[::1]:1337`
+impl FromStr for Address {
+    type Err = net::AddrParseError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.strip_prefix(UNIX_SCHEME) {
+            Some(path) => Ok(Address::Unix(PathBuf::from(path))),
+            None => Ok(Address::Tcp(s.parse()?)),
+        }
+    }
+}
+
+impl From<SocketAddr> for Address {
+    fn from(addr: SocketAddr) -> Self {
+        Address::Tcp(addr)
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Tcp(addr) => write!(f, "{}", addr),
+            Address::Unix(path) => write!(f, "{}{}", UNIX_SCHEME, path.display()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Options {
+    /// disables Nagle's algorithm, gRPC frames are small and latency sensitive
+    pub nodelay: bool,
+    /// idle time before keepalive probes are sent, `None` disables them. Only Linux takes the
+    /// idle time, elsewhere the system default is used
+    pub keepalive: Option<Duration>,
+    /// how long sent data may stay unacknowledged before the connection is dropped, which
+    /// notices a vanished peer long before retransmissions give up. Ignored outside of Linux
+    pub user_timeout: Option<Duration>,
+    /// lets several processes on the same host accept on the same port. Off by default, a
+    /// second instance started by mistake would silently take half of the connections
+    pub reuse_port: bool,
+    /// `SO_RCVBUF`, left to the kernel's autotuning when `None`
+    pub recv_buffer_size: Option<usize>,
+    /// `SO_SNDBUF`, left to the kernel's autotuning when `None`
+    pub send_buffer_size: Option<usize>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            keepalive: Some(Duration::from_secs(60)),
+            user_timeout: Some(Duration::from_secs(30)),
+            reuse_port: false,
+            recv_buffer_size: None,
+            send_buffer_size: None,
+        }
+    }
+}
+
+/// An accepted connection, with `Options` applied to it when it came over TCP
+#[derive(Debug)]
 pub struct Socket {
-    inner: TcpStream,
+    inner: Connection,
+}
+
+#[derive(Debug)]
+enum Connection {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Socket {
+    pub fn new(inner: TcpStream, options: &Options) -> io::Result<Self> {
+        tune(inner.as_raw_fd(), options)?;
+        Ok(Self {
+            inner: Connection::Tcp(inner),
+        })
+    }
+}
+
+impl Connected for Socket {
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        match &self.inner {
+            Connection::Tcp(stream) => stream.peer_addr().ok(),
+            Connection::Unix(_) => None,
+        }
+    }
+}
+
+/// Connects to a service at `address`, which is either a `unix://<path>` address or a URI
+pub async fn connect(address: String) -> Result<Channel, tonic::transport::Error> {
+    match address.strip_prefix(UNIX_SCHEME) {
+        Some(path) => {
+            let path = PathBuf::from(path);
+            // the connector ignores the uri, but the channel still needs a valid one
+            Endpoint::from_static("http://localhost")
+                .connect_with_connector(tower::service_fn(move |_: Uri| {
+                    UnixStream::connect(path.clone())
+                }))
+                .await
+        }
+        None => Endpoint::new(address)?.connect().await,
+    }
+}
+
+/// Applies the per connection options, accepted connections don't reliably inherit them
+fn tune(fd: RawFd, options: &Options) -> io::Result<()> {
+    set_option(
+        fd,
+        libc::IPPROTO_TCP,
+        libc::TCP_NODELAY,
+        options.nodelay as libc::c_int,
+    )?;
+    set_option(
+        fd,
+        libc::SOL_SOCKET,
+        libc::SO_KEEPALIVE,
+        options.keepalive.is_some() as libc::c_int,
+    )?;
+    #[cfg(target_os = "linux")]
+    tune_linux(fd, options)?;
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn tune_linux(fd: RawFd, options: &Options) -> io::Result<()> {
+    if let Some(idle) = options.keepalive {
+        let seconds = idle.as_secs().clamp(1, libc::c_int::MAX as u64);
+        set_option(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_KEEPIDLE,
+            seconds as libc::c_int,
+        )?;
+    }
+    if let Some(timeout) = options.user_timeout {
+        let milliseconds = timeout.as_millis().min(libc::c_int::MAX as u128);
+        set_option(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_USER_TIMEOUT,
+            milliseconds as libc::c_int,
+        )?;
+    }
+    Ok(())
+}
+
+fn set_option(
+    fd: RawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    // SAFETY: callers pass a descriptor they own for the duration of the call, and every option
+    // set here takes an int
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// The connections accepted on a listening socket, as a stream tonic can serve. The stream never
+/// fails, tonic stops serving at the first error it yields, so accept errors are waited out and
+/// connections that can't be tuned are dropped
+pub struct Incoming {
+    listener: Listener,
+    options: Options,
+    backoff: Backoff,
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    /// the socket file is removed along with the listener
+    Unix(UnixListener, PathBuf),
+}
+
+impl Incoming {
+    pub fn local_addr(&self) -> io::Result<Address> {
+        match &self.listener {
+            Listener::Tcp(listener) => listener.local_addr().map(Address::Tcp),
+            Listener::Unix(_, path) => Ok(Address::Unix(path.clone())),
+        }
+    }
+}
+
+impl Drop for Incoming {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = &self.listener {
+            // whoever binds the path next removes it if this didn't
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Listens on `addr`. TCP buffer sizes are set on the listener so that accepted connections
+/// inherit them before the handshake negotiates a window, the rest is applied to every
+/// connection. Unix domain sockets have nothing to tune
+pub fn incoming(addr: impl Into<Address>, options: Options) -> Result<Incoming> {
+    let addr = addr.into();
+    Ok(listen(&addr, options).context(SocketError {
+        addr: addr.to_string(),
+    })?)
+}
+
+fn listen(addr: &Address, options: Options) -> io::Result<Incoming> {
+    let listener = match addr {
+        Address::Tcp(addr) => Listener::Tcp(TcpListener::from_std(bind(*addr, &options, true)?)?),
+        Address::Unix(path) => {
+            Listener::Unix(UnixListener::from_std(bind_unix(path)?)?, path.clone())
+        }
+    };
+    Ok(Incoming {
+        listener,
+        options,
+        backoff: Backoff::default(),
+    })
+}
+
+/// A listening Unix domain socket at `path`
+fn bind_unix(path: &Path) -> io::Result<std::os::unix::net::UnixListener> {
+    // a socket left behind by a process that didn't get to clean up refuses connections. One
+    // that doesn't belongs to a server that is still running, binding fails with AddrInUse then
+    if matches!(fs::symlink_metadata(path), Ok(metadata) if metadata.file_type().is_socket()) {
+        match std::os::unix::net::UnixStream::connect(path) {
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path)?,
+            _ => {}
+        }
+    }
+    std::os::unix::net::UnixListener::bind(path)
+}
+
+/// A listening socket with the options that have to be set before `listen` applied
+fn bind(addr: SocketAddr, options: &Options, nonblocking: bool) -> io::Result<net::TcpListener> {
+    let domain = match addr {
+        SocketAddr::V4(_) => Domain::ipv4(),
+        SocketAddr::V6(_) => Domain::ipv6(),
+    };
+    let socket = socket2::Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(options.reuse_port)?;
+    if let Some(size) = options.recv_buffer_size {
+        socket.set_recv_buffer_size(size)?;
+    }
+    if let Some(size) = options.send_buffer_size {
+        socket.set_send_buffer_size(size)?;
+    }
+    socket.set_nonblocking(nonblocking)?;
+    socket.bind(&SockAddr::from(addr))?;
+    socket.listen(BACKLOG)?;
+    Ok(socket.into_tcp_listener())
+}
+
+impl Listener {
+    fn poll_accept(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<Connection>> {
+        match self {
+            Listener::Tcp(listener) => listener
+                .poll_accept(cx)
+                .map_ok(|(stream, _)| Connection::Tcp(stream)),
+            // the stream of a unix listener never ends
+            Listener::Unix(listener, _) => Pin::new(listener)
+                .poll_next(cx)
+                .map(|accepted| accepted.unwrap().map(Connection::Unix)),
+        }
+    }
+}
+
+/// Retries accepting until it succeeds, pausing after errors that won't go away by themselves
+struct Backoff {
+    delay: Option<Delay>,
+    next: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            delay: None,
+            next: ACCEPT_BACKOFF,
+        }
+    }
+}
+
+impl Backoff {
+    fn poll_accept<T>(
+        &mut self,
+        cx: &mut Context<'_>,
+        mut accept: impl FnMut(&mut Context<'_>) -> Poll<io::Result<T>>,
+    ) -> Poll<T> {
+        loop {
+            if let Some(delay) = &mut self.delay {
+                ready!(Pin::new(delay).poll(cx));
+                self.delay = None;
+            }
+            match ready!(accept(cx)) {
+                Ok(accepted) => {
+                    self.next = ACCEPT_BACKOFF;
+                    return Poll::Ready(accepted);
+                }
+                // a connection that failed before it was accepted is the client's problem
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::ConnectionRefused
+                            | io::ErrorKind::ConnectionAborted
+                            | io::ErrorKind::ConnectionReset
+                            | io::ErrorKind::Interrupted
+                    ) => {}
+                Err(e) => {
+                    eprintln!("could not accept a connection: {}", e);
+                    self.delay = Some(delay_for(self.next));
+                    self.next = (self.next * 2).min(MAX_ACCEPT_BACKOFF);
+                }
+            }
+        }
+    }
+}
+
+impl Stream for Incoming {
+    type Item = io::Result<Socket>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            let listener = &mut this.listener;
+            match ready!(this.backoff.poll_accept(cx, |cx| listener.poll_accept(cx))) {
+                Connection::Tcp(stream) => match Socket::new(stream, &this.options) {
+                    Ok(socket) => return Poll::Ready(Some(Ok(socket))),
+                    Err(e) => eprintln!("dropped a connection that could not be tuned: {}", e),
+                },
+                Connection::Unix(stream) => {
+                    return Poll::Ready(Some(Ok(Socket {
+                        inner: Connection::Unix(stream),
+                    })))
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::supervisor::membership_client::MembershipClient;
+    use crate::supervisor::membership_server::{Membership, MembershipServer};
+    use crate::supervisor::ExecutorDeparture;
+
+    async fn ping(client: &mut (impl AsyncReadExt + AsyncWriteExt + Unpin), socket: &mut Socket) {
+        client.write_all(b"ping").await.unwrap();
+        let mut buffer = [0; 4];
+        socket.read_exact(&mut buffer).await.unwrap();
+        socket.write_all(&buffer).await.unwrap();
+        client.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(&buffer, b"ping");
+    }
+
+    #[tokio::test]
+    async fn accepted_sockets_are_tuned_and_carry_data() {
+        let options = Options {
+            recv_buffer_size: Some(1 << 16),
+            ..Default::default()
+        };
+        let mut incoming = incoming("[::1]:0".parse::<SocketAddr>().unwrap(), options).unwrap();
+        let addr = match incoming.local_addr().unwrap() {
+            Address::Tcp(addr) => addr,
+            Address::Unix(path) => panic!("listening on {}", path.display()),
+        };
+        // the port is only shared when every listener asks for it
+        assert!(super::incoming(addr, options).is_err());
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let mut socket = incoming.next().await.unwrap().unwrap();
+        match &socket.inner {
+            Connection::Tcp(stream) => {
+                assert!(stream.nodelay().unwrap());
+                #[cfg(target_os = "linux")]
+                assert_eq!(stream.keepalive().unwrap(), Some(Duration::from_secs(60)));
+            }
+            Connection::Unix(_) => panic!("accepted a unix domain socket"),
+        }
+        assert_eq!(socket.remote_addr(), client.local_addr().ok());
+        ping(&mut client, &mut socket).await;
+    }
+
+    #[tokio::test]
+    async fn listeners_share_a_port_when_asked_to() {
+        let options = Options {
+            reuse_port: true,
+            ..Default::default()
+        };
+        let first = incoming("[::1]:0".parse::<SocketAddr>().unwrap(), options).unwrap();
+        let addr = first.local_addr().unwrap();
+        assert!(incoming(addr.clone(), options).is_ok());
+        assert!(incoming(addr, Options::default()).is_err());
+    }
+
+    #[tokio::test]
+    async fn unix_addresses_listen_on_a_socket_file() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("executor.sock");
+        let address = format!("unix://{}", path.display());
+        assert_eq!(
+            address.parse::<Address>().unwrap(),
+            Address::Unix(path.clone())
+        );
+        assert_eq!(address.parse::<Address>().unwrap().to_string(), address);
+        assert!("unix".parse::<Address>().is_err());
+
+        let mut incoming =
+            incoming(address.parse::<Address>().unwrap(), Options::default()).unwrap();
+        let mut client = UnixStream::connect(&path).await.unwrap();
+        let mut socket = incoming.next().await.unwrap().unwrap();
+        assert_eq!(socket.remote_addr(), None);
+        ping(&mut client, &mut socket).await;
+
+        // a live listener keeps its socket file
+        let err = super::incoming(Address::Unix(path.clone()), Options::default())
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("in use"), "{}", err);
+        ping(&mut client, &mut socket).await;
+
+        drop(client);
+        drop(socket);
+        drop(incoming);
+        assert!(!path.exists());
+
+        // the socket file of a listener that went away without cleaning up is replaced
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+        let incoming = super::incoming(Address::Unix(path.clone()), Options::default()).unwrap();
+        drop(incoming);
+        assert!(!path.exists());
+    }
+
+    struct Departures;
+
+    #[tonic::async_trait]
+    impl Membership for Departures {
+        async fn executor_leaving(
+            &self,
+            request: tonic::Request<ExecutorDeparture>,
+        ) -> std::result::Result<tonic::Response<()>, tonic::Status> {
+            match request.into_inner().address.as_str() {
+                "" => Err(tonic::Status::invalid_argument("no address")),
+                _ => Ok(tonic::Response::new(())),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_serving_through_accept_errors() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("supervisor.sock");
+        let mut incoming = incoming(Address::Unix(path.clone()), Options::default()).unwrap();
+        let mut failures = vec![
+            io::Error::from(io::ErrorKind::ConnectionAborted),
+            io::Error::from_raw_os_error(libc::EMFILE),
+            io::Error::from_raw_os_error(libc::EMFILE),
+        ];
+        // the same listener, failing before it accepts anything
+        let accepted = futures::stream::poll_fn(move |cx| {
+            let listener = &mut incoming.listener;
+            incoming
+                .backoff
+                .poll_accept(cx, |cx| match failures.pop() {
+                    Some(e) => Poll::Ready(Err(e)),
+                    None => listener.poll_accept(cx),
+                })
+                .map(|inner| Some(Ok::<_, io::Error>(Socket { inner })))
+        });
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(async move {
+            tonic::transport::Server::builder()
+                .add_service(MembershipServer::new(Departures))
+                .serve_with_incoming_shutdown(accepted, async {
+                    let _ = rx.await;
+                })
+                .await
+                .unwrap();
+        });
+
+        let address = format!("{}{}", UNIX_SCHEME, path.display());
+        let mut client = MembershipClient::new(connect(address).await.unwrap());
+        let departure = ExecutorDeparture {
+            address: "[::1]:9001".to_string(),
+            ..Default::default()
+        };
+        client
+            .executor_leaving(tonic::Request::new(departure))
+            .await
+            .unwrap();
+
+        tx.send(()).unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn serves_grpc_over_unix_sockets() {
+        let directory = tempfile::tempdir().unwrap();
+        let address = format!(
+            "unix://{}",
+            directory.path().join("supervisor.sock").display()
+        );
+        let incoming = incoming(address.parse::<Address>().unwrap(), Options::default()).unwrap();
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(async move {
+            tonic::transport::Server::builder()
+                .add_service(MembershipServer::new(Departures))
+                .serve_with_incoming_shutdown(incoming, async {
+                    let _ = rx.await;
+                })
+                .await
+                .unwrap();
+        });
+
+        let mut client = MembershipClient::new(connect(address).await.unwrap());
+        let departure = ExecutorDeparture {
+            address: "[::1]:9001".to_string(),
+            ..Default::default()
+        };
+        client
+            .executor_leaving(tonic::Request::new(departure))
+            .await
+            .unwrap();
+        let err = client
+            .executor_leaving(tonic::Request::new(ExecutorDeparture::default()))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+
+        tx.send(()).unwrap();
+        server.await.unwrap();
+    }
 }
@@ -0,0 +1,507 @@
+//! A channel for moving large files between nodes without copying them through userspace. The
+//! sender `sendfile`s from the page cache straight into the socket and the receiver `splice`s
+//! from the socket into its file through a pipe, so neither side ever holds the data in a buffer
+//! of its own. io_uring isn't used, with both ends already zero copy it would only save
+//! syscalls, and those are amortised over the size of the transfers. Outside of Linux the data
+//! is copied through a buffer instead
+//!
+//! Everything here blocks, callers give every connection a thread of its own. What is sent is up
+//! to the caller, `write_frame` and `read_frame` carry the small messages around the payload
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::net::{TcpListener, TcpStream};
+#[cfg(target_os = "linux")]
+use std::os::unix::io::FromRawFd;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use bytes::BytesMut;
+use prost::Message;
+use tonic::{Code, Status};
+
+use super::{bind, bind_unix, tune, Address, Options};
+use crate::checksum::{checksum_of, verify_checksum, verify_with, Algorithm, Digest};
+use crate::errors::*;
+use crate::executor::{BulkHeader, FetchRequest};
+
+/// how long either end of a connection waits on a peer that stalled, sending or taking a
+/// request or the payload, before it gives up on it
+pub const IO_TIMEOUT: Duration = Duration::from_secs(30);
+/// frames only carry headers, anything larger is a corrupt or hostile peer
+const MAX_FRAME_LEN: u32 = 1 << 20;
+/// the most `sendfile` and `splice` move in one call
+#[cfg(target_os = "linux")]
+const MAX_TRANSFER: usize = 0x7fff_f000;
+/// the default capacity of a pipe, splicing more than it holds at once only blocks. Also the
+/// size of the buffer data is copied through where there is no splicing
+const PIPE_CAPACITY: usize = 1 << 16;
+
+pub enum Listener {
+    Tcp(TcpListener, Options),
+    /// the socket file is removed along with the listener
+    Unix(UnixListener, PathBuf),
+}
+
+/// Listens on `addr` for bulk connections, tuned like `super::incoming`
+pub fn listen(addr: &Address, options: Options) -> Result<Listener> {
+    let listener = match addr {
+        Address::Tcp(tcp) => bind(*tcp, &options, false).map(|inner| Listener::Tcp(inner, options)),
+        Address::Unix(path) => bind_unix(path).map(|inner| Listener::Unix(inner, path.clone())),
+    };
+    Ok(listener.context(SocketError {
+        addr: addr.to_string(),
+    })?)
+}
+
+impl Listener {
+    pub fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Listener::Tcp(listener, options) => {
+                let (stream, _) = listener.accept()?;
+                tune(stream.as_raw_fd(), options)?;
+                Ok(Stream::Tcp(stream))
+            }
+            Listener::Unix(listener, _) => Ok(Stream::Unix(listener.accept()?.0)),
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<Address> {
+        match self {
+            Listener::Tcp(listener, _) => listener.local_addr().map(Address::Tcp),
+            Listener::Unix(_, path) => Ok(Address::Unix(path.clone())),
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            // whoever binds the path next removes it if this didn't
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// A bulk connection, over TCP or a Unix domain socket on the same host
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Stream {
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout),
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_write_timeout(timeout),
+            Stream::Unix(stream) => stream.set_write_timeout(timeout),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+impl AsRawFd for Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Stream::Tcp(stream) => stream.as_raw_fd(),
+            Stream::Unix(stream) => stream.as_raw_fd(),
+        }
+    }
+}
+
+pub fn connect(addr: &Address, options: &Options) -> io::Result<Stream> {
+    match addr {
+        Address::Tcp(addr) => {
+            let stream = TcpStream::connect(addr)?;
+            tune(stream.as_raw_fd(), options)?;
+            Ok(Stream::Tcp(stream))
+        }
+        Address::Unix(path) => Ok(Stream::Unix(UnixStream::connect(path)?)),
+    }
+}
+
+/// Writes `message` led by its length as a little endian u32
+pub fn write_frame(stream: &mut impl Write, message: &impl Message) -> io::Result<()> {
+    let mut encoded = BytesMut::with_capacity(4 + message.encoded_len());
+    encoded.extend_from_slice(&(message.encoded_len() as u32).to_le_bytes());
+    // encoding into a buffer that can grow is infallible
+    let _ = message.encode(&mut encoded);
+    stream.write_all(&encoded)
+}
+
+pub fn read_frame<M: Message + Default>(stream: &mut impl Read) -> io::Result<M> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len);
+    if len > MAX_FRAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {} bytes is over the limit", len),
+        ));
+    }
+    let mut encoded = vec![0; len as usize];
+    stream.read_exact(&mut encoded)?;
+    M::decode(&encoded[..]).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Sends `len` bytes of `file` starting at `offset`. The file's own position is left alone, so
+/// any number of transfers can read the same file at once
+#[cfg(target_os = "linux")]
+pub fn send_file(stream: &mut Stream, file: &File, offset: u64, len: u64) -> io::Result<()> {
+    let mut offset = offset as libc::off_t;
+    let mut remaining = len;
+    while remaining > 0 {
+        let count = remaining.min(MAX_TRANSFER as u64) as usize;
+        // SAFETY: both descriptors are borrowed for the duration of the call
+        let sent =
+            unsafe { libc::sendfile(stream.as_raw_fd(), file.as_raw_fd(), &mut offset, count) };
+        match sent {
+            -1 => match io::Error::last_os_error() {
+                e if e.kind() == io::ErrorKind::Interrupted => continue,
+                e => return Err(e),
+            },
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            sent => remaining -= sent as u64,
+        }
+    }
+    Ok(())
+}
+
+/// Sends `len` bytes of `file` starting at `offset`. The file's own position is left alone, so
+/// any number of transfers can read the same file at once
+#[cfg(not(target_os = "linux"))]
+pub fn send_file(stream: &mut Stream, file: &File, offset: u64, len: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    let mut buffer = vec![0; PIPE_CAPACITY];
+    let mut offset = offset;
+    let mut remaining = len;
+    while remaining > 0 {
+        let count = remaining.min(PIPE_CAPACITY as u64) as usize;
+        let read = match file.read_at(&mut buffer[..count], offset) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        stream.write_all(&buffer[..read])?;
+        offset += read as u64;
+        remaining -= read as u64;
+    }
+    Ok(())
+}
+
+/// Moves the next `len` bytes of `stream` into `file` at its current position
+#[cfg(not(target_os = "linux"))]
+pub fn receive_file(stream: &mut Stream, mut file: &File, len: u64) -> io::Result<()> {
+    let copied = io::copy(&mut stream.take(len), &mut file)?;
+    if copied < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+/// Moves the next `len` bytes of `stream` into `file` at its current position
+#[cfg(target_os = "linux")]
+pub fn receive_file(stream: &mut Stream, file: &File, len: u64) -> io::Result<()> {
+    let (reader, writer) = pipe()?;
+    let mut remaining = len;
+    while remaining > 0 {
+        let count = remaining.min(PIPE_CAPACITY as u64) as usize;
+        let mut buffered = splice(stream.as_raw_fd(), writer.as_raw_fd(), count)?;
+        if buffered == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        remaining -= buffered as u64;
+        while buffered > 0 {
+            buffered -= splice(reader.as_raw_fd(), file.as_raw_fd(), buffered)?;
+        }
+    }
+    Ok(())
+}
+
+/// The tagged checksum of `len` bytes of `file` starting at `offset`, read without moving the
+/// file's own position
+pub fn digest_file(
+    file: &File,
+    offset: u64,
+    len: u64,
+    algorithm: Algorithm,
+) -> io::Result<Vec<u8>> {
+    use std::os::unix::fs::FileExt;
+
+    let mut digest = Digest::new(algorithm);
+    let mut buffer = vec![0; PIPE_CAPACITY];
+    let mut offset = offset;
+    let mut remaining = len;
+    while remaining > 0 {
+        let count = remaining.min(PIPE_CAPACITY as u64) as usize;
+        let read = match file.read_at(&mut buffer[..count], offset) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        digest.update(&buffer[..read]);
+        offset += read as u64;
+        remaining -= read as u64;
+    }
+    Ok(digest.finish())
+}
+
+/// Fetches a partition from an executor's bulk channel, appending its records to `file` as
+/// length delimited `Map`s. The returned header says how many were received, they are only
+/// returned once they matched the digest the header carries. A failed fetch leaves `file` as it
+/// found it, so that it can be fetched again from the same offset
+pub fn fetch(
+    addr: &Address,
+    options: &Options,
+    mut request: FetchRequest,
+    file: &File,
+) -> Result<BulkHeader, Status> {
+    if request.checksum.is_empty() {
+        request.checksum = checksum_of(&request)?;
+    }
+    let unavailable = |e: io::Error| Status::unavailable(e.to_string());
+    let mut stream = connect(addr, options).map_err(unavailable)?;
+    stream
+        .set_read_timeout(Some(IO_TIMEOUT))
+        .map_err(unavailable)?;
+    stream
+        .set_write_timeout(Some(IO_TIMEOUT))
+        .map_err(unavailable)?;
+    write_frame(&mut stream, &request).map_err(unavailable)?;
+    let header: BulkHeader = read_frame(&mut stream).map_err(unavailable)?;
+    verify_checksum(&header, &header.checksum)?;
+    if header.code != Code::Ok as i32 {
+        return Err(Status::new(Code::from_i32(header.code), header.message));
+    }
+    let start = (&mut &*file).stream_position().map_err(unavailable)?;
+    if let Err(status) = receive_payload(&mut stream, file, start, &header) {
+        // whatever arrived of the payload is dropped again
+        file.set_len(start)
+            .and_then(|()| (&mut &*file).seek(SeekFrom::Start(start)))
+            .map_err(|e| Status::internal(e.to_string()))?;
+        return Err(status);
+    }
+    Ok(header)
+}
+
+/// Receives the payload `header` announces into `file` at `start` and checks it against the
+/// header's digest
+fn receive_payload(
+    stream: &mut Stream,
+    file: &File,
+    start: u64,
+    header: &BulkHeader,
+) -> Result<(), Status> {
+    receive_file(stream, file, header.length).map_err(|e| Status::unavailable(e.to_string()))?;
+    verify_with(&header.digest, |algorithm| {
+        Ok(digest_file(file, start, header.length, algorithm).context(ReadPayload)?)
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn pipe() -> io::Result<(File, File)> {
+    let mut fds = [0; 2];
+    // SAFETY: `fds` has room for the two descriptors pipe2 writes
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the descriptors were just created and nothing else owns them
+    Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+}
+
+#[cfg(target_os = "linux")]
+fn splice(from: RawFd, to: RawFd, count: usize) -> io::Result<usize> {
+    loop {
+        // SAFETY: both descriptors are borrowed for the duration of the call, null offsets use
+        // and advance the descriptors' own positions
+        let moved = unsafe {
+            libc::splice(
+                from,
+                std::ptr::null_mut(),
+                to,
+                std::ptr::null_mut(),
+                count,
+                libc::SPLICE_F_MOVE,
+            )
+        };
+        match moved {
+            -1 => match io::Error::last_os_error() {
+                e if e.kind() == io::ErrorKind::Interrupted => continue,
+                e => return Err(e),
+            },
+            moved => return Ok(moved as usize),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Seek, SeekFrom};
+    use std::thread;
+
+    use super::*;
+    use crate::base::Identifier;
+
+    #[test]
+    fn files_move_from_an_offset() {
+        let contents: Vec<u8> = (0..3 << 20).map(|i| (i % 251) as u8).collect();
+        let mut source = tempfile::tempfile().unwrap();
+        source.write_all(&contents).unwrap();
+
+        let listener = listen(&"[::1]:0".parse().unwrap(), Options::default()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let sender = thread::spawn(move || {
+            let mut stream = listener.accept().unwrap();
+            let request: Identifier = read_frame(&mut stream).unwrap();
+            let offset: u64 = request.uuid.parse().unwrap();
+            send_file(&mut stream, &source, offset, (3 << 20) - offset).unwrap();
+        });
+
+        let mut stream = connect(&addr, &Options::default()).unwrap();
+        let request = Identifier {
+            uuid: "1000".to_string(),
+        };
+        write_frame(&mut stream, &request).unwrap();
+        let mut destination = tempfile::tempfile().unwrap();
+        receive_file(&mut stream, &destination, (3 << 20) - 1000).unwrap();
+        sender.join().unwrap();
+
+        let mut received = Vec::new();
+        destination.seek(SeekFrom::Start(0)).unwrap();
+        destination.read_to_end(&mut received).unwrap();
+        assert_eq!(received, &contents[1000..]);
+
+        // the sender hung up, there is nothing left to receive
+        assert_eq!(
+            receive_file(&mut stream, &destination, 1)
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn fetched_payloads_are_checked_against_the_digest() {
+        let contents: Vec<u8> = (0..1 << 20).map(|i| (i % 251) as u8).collect();
+        let mut source = tempfile::tempfile().unwrap();
+        source.write_all(&contents).unwrap();
+        let len = contents.len() as u64;
+
+        let listener = listen(&"[::1]:0".parse().unwrap(), Options::default()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let sender = thread::spawn(move || {
+            // the second header claims the digest of other bytes
+            let digests = vec![
+                digest_file(&source, 0, len, Algorithm::Crc32c).unwrap(),
+                Algorithm::Crc32c.checksum(b"something else"),
+            ];
+            for digest in digests {
+                let mut stream = listener.accept().unwrap();
+                let _: FetchRequest = read_frame(&mut stream).unwrap();
+                let mut header = BulkHeader {
+                    length: len,
+                    digest,
+                    ..Default::default()
+                };
+                header.checksum = checksum_of(&header).unwrap();
+                write_frame(&mut stream, &header).unwrap();
+                send_file(&mut stream, &source, 0, len).unwrap();
+            }
+        });
+
+        // appended after what the file already holds
+        let mut destination = tempfile::tempfile().unwrap();
+        destination.write_all(b"earlier").unwrap();
+        fetch(
+            &addr,
+            &Options::default(),
+            FetchRequest::default(),
+            &destination,
+        )
+        .unwrap();
+        let err = fetch(
+            &addr,
+            &Options::default(),
+            FetchRequest::default(),
+            &destination,
+        )
+        .unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+        sender.join().unwrap();
+
+        // the corrupt payload was dropped, the next fetch appends where the good one ended
+        let mut received = Vec::new();
+        assert_eq!(destination.stream_position().unwrap(), 7 + len);
+        destination.seek(SeekFrom::Start(0)).unwrap();
+        destination.read_to_end(&mut received).unwrap();
+        assert_eq!(&received[..7], b"earlier");
+        assert_eq!(&received[7..], &contents[..]);
+    }
+
+    #[test]
+    fn payloads_cut_short_are_dropped() {
+        let listener = listen(&"[::1]:0".parse().unwrap(), Options::default()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let sender = thread::spawn(move || {
+            let mut stream = listener.accept().unwrap();
+            let _: FetchRequest = read_frame(&mut stream).unwrap();
+            let mut header = BulkHeader {
+                length: 1 << 20,
+                digest: Algorithm::Crc32c.checksum(b"never sent"),
+                ..Default::default()
+            };
+            header.checksum = checksum_of(&header).unwrap();
+            write_frame(&mut stream, &header).unwrap();
+            stream.write_all(&[7; 1000]).unwrap();
+        });
+
+        let mut destination = tempfile::tempfile().unwrap();
+        destination.write_all(b"earlier").unwrap();
+        let err = fetch(
+            &addr,
+            &Options::default(),
+            FetchRequest::default(),
+            &destination,
+        )
+        .unwrap_err();
+        assert_eq!(err.code(), Code::Unavailable);
+        sender.join().unwrap();
+        assert_eq!(destination.metadata().unwrap().len(), 7);
+        assert_eq!(destination.stream_position().unwrap(), 7);
+    }
+}
@@ -6,9 +6,15 @@ pub use snafu::{OptionExt, ResultExt};
 #[snafu(visibility = "pub(crate)")]
 pub(crate) enum Error {
     #[snafu(display("{}", source))]
-    NeuromancerError {
+    Neuromancer {
         source: neuromancer::NeuromancerError,
     },
+    #[snafu(display("invalid address specified for supervisor: {}", source))]
+    InvalidSupervisorAddressSpecified { source: std::net::AddrParseError },
+    #[snafu(display("grpc transport error: {}", source))]
+    GRPCTransport { source: tonic::transport::Error },
+    #[snafu(display("no executor address provided"))]
+    NoAddressProvided,
 }
 
 #[derive(Debug, Snafu)]
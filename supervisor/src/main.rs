@@ -1,7 +1,46 @@
 mod errors;
+mod membership;
 
 use errors::*;
+use membership::Supervisor;
+use neuromancer::socket;
+use neuromancer::supervisor::membership_server::MembershipServer;
 
-fn main() -> Result<()> {
-    Ok(())
+pub(crate) struct Server {
+    addr: String,
+}
+
+impl Server {
+    const SUPERVISOR_SERVER_ADDRESS: &'static str = "[::1]:9000";
+    /// overrides `SUPERVISOR_SERVER_ADDRESS`, a `unix://` address serves over a Unix domain socket
+    const ADDRESS_VARIABLE: &'static str = "SUPERVISOR_ADDRESS";
+
+    pub(crate) fn new() -> Self {
+        let addr = std::env::var(Self::ADDRESS_VARIABLE)
+            .unwrap_or_else(|_| Self::SUPERVISOR_SERVER_ADDRESS.to_string());
+        Self { addr }
+    }
+
+    pub(crate) async fn build(self) -> Result<()> {
+        neuromancer::checksum::configure_from_env().context(Neuromancer)?;
+        tonic::transport::Server::builder()
+            .add_service(MembershipServer::new(Supervisor::default()))
+            .serve_with_incoming(
+                socket::incoming(
+                    self.addr
+                        .parse::<socket::Address>()
+                        .context(InvalidSupervisorAddressSpecified)?,
+                    socket::Options::default(),
+                )
+                .context(Neuromancer)?,
+            )
+            .await
+            .context(GRPCTransport)?;
+        Ok(())
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    Server::new().build().await
 }
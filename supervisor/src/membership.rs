@@ -0,0 +1,68 @@
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use tonic::{Request, Response, Status};
+
+use crate::errors::*;
+use neuromancer::supervisor::{membership_server::Membership, ExecutorDeparture};
+
+/// The supervisor's view of the cluster, clones share the same state
+#[derive(Clone, Default)]
+pub(crate) struct Supervisor {
+    /// the advertised addresses of the executors that announced they are leaving the cluster
+    departed: Arc<Mutex<BTreeSet<String>>>,
+}
+
+#[tonic::async_trait]
+impl Membership for Supervisor {
+    async fn executor_leaving(
+        &self,
+        request: Request<ExecutorDeparture>,
+    ) -> Result<Response<()>, Status> {
+        let departure = request.into_inner();
+        if departure.address.is_empty() {
+            return Err(Status::invalid_argument(
+                Error::NoAddressProvided.to_string(),
+            ));
+        }
+        self.departed.lock().insert(departure.address);
+        Ok(Response::new(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn departure(address: &str) -> Request<ExecutorDeparture> {
+        Request::new(ExecutorDeparture {
+            address: address.to_string(),
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn records_departed_executors() {
+        let supervisor = Supervisor::default();
+        supervisor
+            .executor_leaving(departure("[::1]:9001"))
+            .await
+            .unwrap();
+        // an executor that retries its departure is only recorded once
+        supervisor
+            .executor_leaving(departure("[::1]:9001"))
+            .await
+            .unwrap();
+
+        let err = supervisor
+            .executor_leaving(departure(""))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+        assert_eq!(
+            *supervisor.departed.lock(),
+            vec!["[::1]:9001".to_string()].into_iter().collect()
+        );
+    }
+}